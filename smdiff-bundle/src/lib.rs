@@ -0,0 +1,501 @@
+//! Directory tree diffs on top of SMDIFF.
+//!
+//! [`create_bundle`] walks an old and a new directory tree and writes a single archive (a bundle) containing:
+//! * A [`Manifest`] listing every path of the new tree, plus the paths that were removed.
+//! * One SMDIFF patch per added, modified or renamed file.
+//!
+//! Renamed files are detected by content similarity, so a file that moved and changed is still patched against its old version.
+//!
+//! [`apply_bundle`] rebuilds the new tree from the old tree and the bundle.
+//! The new tree is first built next to the destination and only moved into place once every entry was applied.
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use smdiff_decoder::apply_patch;
+use smdiff_encoder::{encode, similarity::Sketch, EncoderConfig};
+use tree::{make_symlink, scan_tree, set_mode, Node, NodeKind};
+
+pub use manifest::{Change, Entry, Manifest};
+
+mod manifest;
+mod tree;
+
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How symbolic links in the trees are handled.
+/// Default: Preserve
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Store the link itself. It is recreated pointing to the same target.
+    #[default]
+    Preserve,
+    /// Treat the link as whatever it points to.
+    Follow,
+    /// Ignore links completely.
+    Skip,
+}
+
+/// Configuration for creating a bundle.
+///
+/// Default values are:
+/// - encoder: EncoderConfig::default()
+/// - symlinks: Preserve
+/// - rename_threshold: Some(0.5)
+#[derive(Clone, Debug)]
+pub struct BundleConfig {
+    /// The config used to encode each file.
+    pub encoder: EncoderConfig,
+    /// What to do with symbolic links.
+    pub symlinks: SymlinkPolicy,
+    /// The minimum similarity (0.0..=1.0) between a removed and an added file to treat it as a rename.
+    /// None disables rename detection.
+    pub rename_threshold: Option<f32>,
+}
+
+impl Default for BundleConfig {
+    fn default() -> Self {
+        Self { encoder: EncoderConfig::default(), symlinks: SymlinkPolicy::Preserve, rename_threshold: Some(0.5) }
+    }
+}
+
+impl BundleConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set_encoder(mut self, config: EncoderConfig) -> Self {
+        self.encoder = config;
+        self
+    }
+    pub fn set_symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+    pub fn set_rename_threshold(mut self, threshold: f32) -> Self {
+        self.rename_threshold = Some(threshold.clamp(0.0, 1.0));
+        self
+    }
+    pub fn no_rename_detection(mut self) -> Self {
+        self.rename_threshold = None;
+        self
+    }
+}
+
+/// Diffs two directory trees and writes a bundle to the writer.
+/// # Arguments
+/// * `old_root` - The directory the bundle will be applied to.
+/// * `new_root` - The directory the bundle will recreate.
+/// * `writer` - The writer to write the bundle to.
+/// * `config` - The configuration to use.
+/// # Returns
+/// The manifest that was written.
+/// # Errors
+/// Returns an error if the trees could not be read, if a path is not valid utf8, or if the temporary payload file could not be written.
+///
+/// The manifest comes first but needs every payload length, so the payloads are kept in a temporary file (in the system temp directory) until it is written.
+pub fn create_bundle<W: Write>(old_root: &Path, new_root: &Path, writer: &mut W, config: &BundleConfig) -> std::io::Result<Manifest> {
+    let old = scan_tree(old_root, config.symlinks)?;
+    let new = scan_tree(new_root, config.symlinks)?;
+    let mut entries = Vec::new();
+    let mut payloads = PayloadSpill::create()?;
+    let mut added = Vec::new();
+    for (path, node) in new.iter() {
+        let change = match &node.kind {
+            NodeKind::Dir => Change::Directory,
+            NodeKind::Symlink { target } => Change::Symlink { target: target.clone() },
+            NodeKind::File { .. } => match old.get(path) {
+                Some(old_node) if old_node.is_file() => {
+                    let old_bytes = fs::read(&old_node.abs_path)?;
+                    let new_bytes = fs::read(&node.abs_path)?;
+                    if old_bytes == new_bytes {
+                        Change::Unchanged
+                    } else {
+                        let payload = encode_file(Some(old_bytes), new_bytes, &config.encoder)?;
+                        Change::Modified { payload_len: payloads.push(path, &payload)? }
+                    }
+                },
+                _ => {
+                    added.push(path.clone());
+                    continue;
+                },
+            },
+        };
+        entries.push(Entry { path: path.clone(), mode: node.mode, change });
+    }
+    //a file or link replacing another file or link is just overwritten, only a directory swapped for something else needs the old one gone.
+    let removed: Vec<&String> = old.iter()
+        .filter(|(path, node)| new.get(*path).map(|n| (n.kind == NodeKind::Dir) != (node.kind == NodeKind::Dir)).unwrap_or(true))
+        .map(|(path, _)| path)
+        .collect();
+    //any old file whose path no longer holds a file may have been moved.
+    let rename_sources: Vec<&String> = old.iter()
+        .filter(|(path, node)| node.is_file() && !new.get(*path).is_some_and(|n| n.is_file()))
+        .map(|(path, _)| path)
+        .collect();
+    let renames = match config.rename_threshold {
+        Some(threshold) => detect_renames(&old, &new, &added, &rename_sources, threshold)?,
+        None => BTreeMap::new(),
+    };
+    for path in added {
+        let node = &new[&path];
+        let new_bytes = fs::read(&node.abs_path)?;
+        let change = match renames.get(&path) {
+            Some(from) => {
+                let old_bytes = fs::read(&old[from].abs_path)?;
+                let payload = encode_file(Some(old_bytes), new_bytes, &config.encoder)?;
+                Change::Renamed { from: from.clone(), payload_len: payloads.push(&path, &payload)? }
+            },
+            None => {
+                let payload = encode_file(None, new_bytes, &config.encoder)?;
+                Change::Added { payload_len: payloads.push(&path, &payload)? }
+            },
+        };
+        entries.push(Entry { path, mode: node.mode, change });
+    }
+    for path in removed {
+        entries.push(Entry { path: path.clone(), mode: 0, change: Change::Removed });
+    }
+    //Removed sorts after anything else at the same path, so a file replaced by a directory reads naturally.
+    entries.sort_by(|a, b| a.path.cmp(&b.path).then((a.change == Change::Removed).cmp(&(b.change == Change::Removed))));
+    let manifest = Manifest { entries };
+    manifest.write(writer)?;
+    //payloads are stored in manifest order
+    for entry in manifest.entries.iter() {
+        if entry.change.payload_len() > 0 {
+            payloads.copy_to(&entry.path, writer)?;
+        }
+    }
+    Ok(manifest)
+}
+
+/// Encoded payloads waiting for the manifest to be written, in a temporary file that is removed on drop.
+/// * index: path -> (offset, len) of its payload in the file.
+struct PayloadSpill {
+    path: PathBuf,
+    file: File,
+    index: BTreeMap<String, (u64, u64)>,
+    len: u64,
+}
+
+impl PayloadSpill {
+    fn create() -> std::io::Result<Self> {
+        let n = SPILL_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("smdiff-bundle-{}-{}.payloads", std::process::id(), n));
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Self { path, file, index: BTreeMap::new(), len: 0 })
+    }
+    /// Stores the payload of `path`, returning its length.
+    fn push(&mut self, path: &str, payload: &[u8]) -> std::io::Result<u64> {
+        self.file.write_all(payload)?;
+        let len = payload.len() as u64;
+        self.index.insert(path.to_string(), (self.len, len));
+        self.len += len;
+        Ok(len)
+    }
+    fn copy_to<W: Write>(&mut self, path: &str, writer: &mut W) -> std::io::Result<()> {
+        let (offset, len) = *self.index.get(path).expect("Missing payload");
+        self.file.seek(SeekFrom::Start(offset))?;
+        let copied = std::io::copy(&mut (&mut self.file).take(len), writer)?;
+        if copied != len {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Temporary payload file is truncated"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PayloadSpill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Applies a bundle to the old tree, creating the new tree at `new_root`.
+/// # Arguments
+/// * `bundle` - The bundle created by [`create_bundle`].
+/// * `old_root` - The directory the bundle was created against. It is only read from.
+/// * `new_root` - Where to create the new tree. This path must not exist yet.
+/// # Returns
+/// The manifest of the bundle.
+/// # Errors
+/// Returns an error if `new_root` already exists, if the bundle is invalid, or if a file could not be read or written.
+/// On error nothing is left at `new_root`.
+pub fn apply_bundle<R: Read>(bundle: &mut R, old_root: &Path, new_root: &Path) -> std::io::Result<Manifest> {
+    if new_root.exists() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} already exists", new_root.display())));
+    }
+    let staging = staging_path(new_root)?;
+    if staging.exists() {
+        //left over from an interrupted apply
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    match build_tree(bundle, old_root, &staging) {
+        Ok(manifest) => {
+            fs::rename(&staging, new_root)?;
+            Ok(manifest)
+        },
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            Err(e)
+        },
+    }
+}
+
+/// Reads just the manifest from the start of a bundle.
+pub fn read_manifest<R: Read>(bundle: &mut R) -> std::io::Result<Manifest> {
+    Manifest::read(bundle)
+}
+
+fn staging_path(new_root: &Path) -> std::io::Result<PathBuf> {
+    let name = new_root.file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Destination has no file name"))?;
+    let mut staging_name = std::ffi::OsString::from(".");
+    staging_name.push(name);
+    staging_name.push(".smdiff-partial");
+    Ok(new_root.with_file_name(staging_name))
+}
+
+fn build_tree<R: Read>(bundle: &mut R, old_root: &Path, staging: &Path) -> std::io::Result<Manifest> {
+    let manifest = Manifest::read(bundle)?;
+    //Nothing may be written through a link, so no entry may be inside one, and links are only made once everything else is written.
+    let links: HashSet<&str> = manifest.entries.iter()
+        .filter(|e| matches!(e.change, Change::Symlink { .. }))
+        .map(|e| e.path.as_str())
+        .collect();
+    let mut dirs = Vec::new();
+    let mut symlinks = Vec::new();
+    for entry in manifest.entries.iter() {
+        if let Some(link) = entry.path.match_indices('/').map(|(i, _)| &entry.path[..i]).find(|p| links.contains(p)) {
+            return Err(manifest::invalid(format!("Invalid path in manifest: {} is inside the symbolic link {}", entry.path, link)));
+        }
+        let dest = join_rel(staging, &entry.path)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &entry.change {
+            Change::Directory => {
+                fs::create_dir_all(&dest)?;
+                dirs.push((dest, entry.mode));
+            },
+            Change::Unchanged => {
+                fs::copy(join_rel(old_root, &entry.path)?, &dest)?;
+                set_mode(&dest, entry.mode)?;
+            },
+            Change::Added { payload_len } => {
+                apply_payload(bundle, *payload_len, None, &dest)?;
+                set_mode(&dest, entry.mode)?;
+            },
+            Change::Modified { payload_len } => {
+                apply_payload(bundle, *payload_len, Some(&join_rel(old_root, &entry.path)?), &dest)?;
+                set_mode(&dest, entry.mode)?;
+            },
+            Change::Renamed { from, payload_len } => {
+                apply_payload(bundle, *payload_len, Some(&join_rel(old_root, from)?), &dest)?;
+                set_mode(&dest, entry.mode)?;
+            },
+            Change::Symlink { target } => symlinks.push((target, dest)),
+            Change::Removed => (),
+        }
+    }
+    for (target, dest) in symlinks {
+        make_symlink(target, &dest)?;
+    }
+    //directory permissions go last, since a read only directory would stop us from filling it.
+    for (dir, mode) in dirs.into_iter().rev() {
+        set_mode(&dir, mode)?;
+    }
+    Ok(manifest)
+}
+
+fn apply_payload<R: Read>(bundle: &mut R, payload_len: u64, dict: Option<&Path>, dest: &Path) -> std::io::Result<()> {
+    let mut payload = Vec::new();
+    bundle.take(payload_len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != payload_len {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Bundle payload is truncated"));
+    }
+    let mut sink = File::options().read(true).write(true).create_new(true).open(dest)?;
+    let mut dict = dict.map(File::open).transpose()?;
+    apply_patch(&mut Cursor::new(payload), dict.as_mut(), &mut sink)
+}
+
+/// Joins a manifest path onto a root, refusing anything that would escape the root.
+fn join_rel(root: &Path, rel: &str) -> std::io::Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for part in rel.split('/') {
+        if part.is_empty() || part == "." || part == ".." || part.contains('\\') {
+            return Err(manifest::invalid(format!("Invalid path in manifest: {}", rel)));
+        }
+        path.push(part);
+    }
+    Ok(path)
+}
+
+fn encode_file(old: Option<Vec<u8>>, new: Vec<u8>, config: &EncoderConfig) -> std::io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let mut new = Cursor::new(new);
+    match old {
+        Some(old) => encode(Some(&mut Cursor::new(old)), &mut new, &mut payload, config)?,
+        None => encode(None, &mut new, &mut payload, config)?,
+    }
+    Ok(payload)
+}
+
+/// Pairs up added files with old files (`sources`) that have similar content.
+/// Files too small to have any sampled hashes are never paired, their similarity would only compare lengths.
+/// Returns added path -> old path.
+fn detect_renames(old: &BTreeMap<String, Node>, new: &BTreeMap<String, Node>, added: &[String], sources: &[&String], threshold: f32) -> std::io::Result<BTreeMap<String, String>> {
+    let candidates: Vec<(&String, Sketch)> = sources.iter()
+        .map(|path| Ok((*path, Sketch::new(&fs::read(&old[*path].abs_path)?))))
+        .filter(|res| res.as_ref().map_or(true, |(_, sketch)| sketch.has_samples()))
+        .collect::<std::io::Result<_>>()?;
+    let mut used = HashSet::new();
+    let mut renames = BTreeMap::new();
+    for path in added {
+        let sketch = Sketch::new(&fs::read(&new[path].abs_path)?);
+        if !sketch.has_samples() {
+            continue;
+        }
+        let best = candidates.iter()
+            .filter(|(from, _)| !used.contains(*from))
+            .map(|(from, other)| (*from, sketch.similarity(other)))
+            .filter(|(_, score)| *score >= threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((from, _)) = best {
+            used.insert(from.clone());
+            renames.insert(path.clone(), from.clone());
+        }
+    }
+    Ok(renames)
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use smdiff_common::test_util::random_bytes;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("smdiff-bundle-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn assert_same_tree(a: &Path, b: &Path) {
+        let a_nodes = scan_tree(a, SymlinkPolicy::Preserve).unwrap();
+        let b_nodes = scan_tree(b, SymlinkPolicy::Preserve).unwrap();
+        assert_eq!(a_nodes.keys().collect::<Vec<_>>(), b_nodes.keys().collect::<Vec<_>>());
+        for (path, node) in a_nodes.iter() {
+            let other = &b_nodes[path];
+            assert_eq!(node.kind, other.kind, "{}", path);
+            assert_eq!(node.mode, other.mode, "{}", path);
+            if node.is_file() {
+                assert_eq!(fs::read(&node.abs_path).unwrap(), fs::read(&other.abs_path).unwrap(), "{}", path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let root = temp_dir("round-trip");
+        let old = root.join("old");
+        let new = root.join("new");
+        fs::create_dir_all(old.join("lib")).unwrap();
+        fs::create_dir_all(new.join("lib")).unwrap();
+        fs::create_dir_all(new.join("empty")).unwrap();
+        let lib = random_bytes(1, 20_000);
+        let mut lib_v2 = lib.clone();
+        lib_v2[500..520].copy_from_slice(&[7u8; 20]);
+        fs::write(old.join("readme.txt"), b"same in both trees").unwrap();
+        fs::write(new.join("readme.txt"), b"same in both trees").unwrap();
+        fs::write(old.join("app"), random_bytes(2, 5000)).unwrap();
+        let mut app_v2 = random_bytes(2, 5000);
+        app_v2.extend_from_slice(b"new tail");
+        fs::write(new.join("app"), &app_v2).unwrap();
+        fs::write(old.join("lib/libfoo.so.1"), &lib).unwrap();
+        fs::write(new.join("lib/libfoo.so.2"), &lib_v2).unwrap();
+        fs::write(old.join("removed.dat"), random_bytes(3, 3000)).unwrap();
+        fs::write(new.join("added.dat"), random_bytes(4, 3000)).unwrap();
+        //two unrelated small files of the same length, too small to sample, must not be taken for a rename
+        fs::write(old.join("old.cfg"), b"a=1").unwrap();
+        fs::write(new.join("new.cfg"), b"b=2").unwrap();
+        set_mode(&new.join("app"), 0o755).unwrap();
+        #[cfg(unix)]
+        {
+            make_symlink("libfoo.so.2", &new.join("lib/libfoo.so")).unwrap();
+            //a link that changed target, and a file that became a link
+            make_symlink("libfoo.so.1", &old.join("lib/current")).unwrap();
+            make_symlink("libfoo.so.2", &new.join("lib/current")).unwrap();
+            fs::write(old.join("lib/default"), b"libfoo.so.1").unwrap();
+            make_symlink("libfoo.so.2", &new.join("lib/default")).unwrap();
+        }
+
+        let mut bundle = Vec::new();
+        let manifest = create_bundle(&old, &new, &mut bundle, &BundleConfig::default()).unwrap();
+        let change_of = |path: &str| manifest.entries.iter().find(|e| e.path == path && e.change != Change::Removed).map(|e| e.change.clone());
+        assert_eq!(change_of("readme.txt"), Some(Change::Unchanged));
+        assert!(matches!(change_of("app"), Some(Change::Modified { .. })));
+        assert!(matches!(change_of("lib/libfoo.so.2"), Some(Change::Renamed { from, .. }) if from == "lib/libfoo.so.1"));
+        assert!(matches!(change_of("added.dat"), Some(Change::Added { .. })));
+        assert!(matches!(change_of("new.cfg"), Some(Change::Added { .. })));
+        assert!(manifest.entries.iter().any(|e| e.path == "removed.dat" && e.change == Change::Removed));
+        #[cfg(unix)]
+        for path in ["lib/current", "lib/default"] {
+            assert_eq!(change_of(path), Some(Change::Symlink { target: "libfoo.so.2".to_string() }));
+            assert!(!manifest.entries.iter().any(|e| e.path == path && e.change == Change::Removed), "{}", path);
+        }
+        //the renamed lib should only cost a small patch
+        assert!(bundle.len() < 10_000, "bundle is {} bytes", bundle.len());
+
+        let out = root.join("out");
+        apply_bundle(&mut Cursor::new(&bundle), &old, &out).unwrap();
+        assert_same_tree(&new, &out);
+        //applying again must not clobber the existing tree
+        assert!(apply_bundle(&mut Cursor::new(&bundle), &old, &out).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_failed_apply_leaves_nothing() {
+        let root = temp_dir("failed-apply");
+        let old = root.join("old");
+        let new = root.join("new");
+        fs::create_dir_all(&old).unwrap();
+        fs::create_dir_all(new.join("dir")).unwrap();
+        fs::write(old.join("a"), random_bytes(5, 1000)).unwrap();
+        fs::write(new.join("a"), random_bytes(5, 1200)).unwrap();
+        let mut bundle = Vec::new();
+        create_bundle(&old, &new, &mut bundle, &BundleConfig::default()).unwrap();
+        bundle.truncate(bundle.len() - 3);
+        let out = root.join("out");
+        assert!(apply_bundle(&mut Cursor::new(&bundle), &old, &out).is_err());
+        assert!(!out.exists());
+        assert!(!staging_path(&out).unwrap().exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_no_writes_through_symlinks() {
+        let root = temp_dir("symlink-escape");
+        let old = root.join("old");
+        let outside = root.join("outside");
+        fs::create_dir_all(&old).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        //a crafted bundle: a link to a directory outside the tree, then a file "inside" the link.
+        let payload = encode_file(None, b"escaped".to_vec(), &EncoderConfig::default()).unwrap();
+        let manifest = Manifest { entries: vec![
+            Entry { path: "a".to_string(), mode: 0, change: Change::Symlink { target: outside.to_string_lossy().into_owned() } },
+            Entry { path: "a/x".to_string(), mode: 0o644, change: Change::Added { payload_len: payload.len() as u64 } },
+        ] };
+        let mut bundle = Vec::new();
+        manifest.write(&mut bundle).unwrap();
+        bundle.extend_from_slice(&payload);
+        let out = root.join("out");
+        let err = apply_bundle(&mut Cursor::new(&bundle), &old, &out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!outside.join("x").exists());
+        assert!(!out.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! The manifest describes every path in the new tree (plus the removed paths from the old tree).
+//!
+//! Layout of a bundle:
+//! ```text
+//! Magic "SMDB" (4 bytes)
+//! Version (byte)
+//! Number of entries (u-varint)
+//! Entries...
+//!     Kind (byte)
+//!     Path (u-varint len + utf8 bytes)
+//!     Mode (u-varint)
+//!     [From Path] (Renamed only)
+//!     [Payload Len] (u-varint, Added/Modified/Renamed only)
+//!     [Link Target] (Symlink only)
+//! Payloads... (SMDIFF patches, in entry order)
+//! ```
+use std::io::{Read, Write};
+
+use smdiff_common::{read_u8, read_u_varint, write_u8, write_u_varint};
+
+const BUNDLE_MAGIC: [u8; 4] = *b"SMDB";
+const BUNDLE_VERSION: u8 = 0;
+
+const KIND_DIRECTORY: u8 = 0;
+const KIND_UNCHANGED: u8 = 1;
+const KIND_ADDED: u8 = 2;
+const KIND_MODIFIED: u8 = 3;
+const KIND_RENAMED: u8 = 4;
+const KIND_SYMLINK: u8 = 5;
+const KIND_REMOVED: u8 = 6;
+
+/// What happened to a path between the old and the new tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A directory in the new tree.
+    Directory,
+    /// The file is identical to the file at the same path in the old tree.
+    Unchanged,
+    /// A new file. The payload is a patch without a dictionary.
+    Added { payload_len: u64 },
+    /// The payload is a patch against the file at the same path in the old tree.
+    Modified { payload_len: u64 },
+    /// The payload is a patch against the file at `from` in the old tree.
+    Renamed { from: String, payload_len: u64 },
+    /// A symbolic link pointing to `target`.
+    Symlink { target: String },
+    /// The path existed in the old tree, but not in the new tree.
+    /// Also listed when a directory was replaced by a file or link, or the other way around.
+    Removed,
+}
+
+impl Change {
+    /// The length of the patch stored for this entry (0 if there is none).
+    pub fn payload_len(&self) -> u64 {
+        match self {
+            Change::Added { payload_len } | Change::Modified { payload_len } | Change::Renamed { payload_len, .. } => *payload_len,
+            _ => 0,
+        }
+    }
+}
+
+/// A single path in the manifest.
+/// * path: The path relative to the tree root, using '/' as separator.
+/// * mode: The unix permission bits (0 for Removed and Symlink entries).
+/// * change: What happened to this path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    pub mode: u32,
+    pub change: Change,
+}
+
+/// All the entries of a bundle, sorted by path.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&BUNDLE_MAGIC)?;
+        write_u8(writer, BUNDLE_VERSION)?;
+        write_u_varint(writer, self.entries.len() as u64)?;
+        for entry in self.entries.iter() {
+            let kind = match &entry.change {
+                Change::Directory => KIND_DIRECTORY,
+                Change::Unchanged => KIND_UNCHANGED,
+                Change::Added { .. } => KIND_ADDED,
+                Change::Modified { .. } => KIND_MODIFIED,
+                Change::Renamed { .. } => KIND_RENAMED,
+                Change::Symlink { .. } => KIND_SYMLINK,
+                Change::Removed => KIND_REMOVED,
+            };
+            write_u8(writer, kind)?;
+            write_string(writer, &entry.path)?;
+            write_u_varint(writer, entry.mode as u64)?;
+            match &entry.change {
+                Change::Added { payload_len } | Change::Modified { payload_len } => write_u_varint(writer, *payload_len)?,
+                Change::Renamed { from, payload_len } => {
+                    write_string(writer, from)?;
+                    write_u_varint(writer, *payload_len)?;
+                },
+                Change::Symlink { target } => write_string(writer, target)?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+    pub fn read<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != BUNDLE_MAGIC {
+            return Err(invalid("Not a smdiff bundle"));
+        }
+        let version = read_u8(reader)?;
+        if version != BUNDLE_VERSION {
+            return Err(invalid(format!("Unsupported bundle version {}", version)));
+        }
+        let num_entries = read_u_varint(reader)? as usize;
+        let mut entries = Vec::with_capacity(num_entries.min(1 << 16));
+        for _ in 0..num_entries {
+            let kind = read_u8(reader)?;
+            let path = read_string(reader)?;
+            let mode = read_u_varint(reader)? as u32;
+            let change = match kind {
+                KIND_DIRECTORY => Change::Directory,
+                KIND_UNCHANGED => Change::Unchanged,
+                KIND_ADDED => Change::Added { payload_len: read_u_varint(reader)? },
+                KIND_MODIFIED => Change::Modified { payload_len: read_u_varint(reader)? },
+                KIND_RENAMED => {
+                    let from = read_string(reader)?;
+                    Change::Renamed { from, payload_len: read_u_varint(reader)? }
+                },
+                KIND_SYMLINK => Change::Symlink { target: read_string(reader)? },
+                KIND_REMOVED => Change::Removed,
+                _ => return Err(invalid(format!("Invalid entry kind {}", kind))),
+            };
+            entries.push(Entry { path, mode, change });
+        }
+        Ok(Self { entries })
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> std::io::Result<()> {
+    write_u_varint(writer, s.len() as u64)?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> std::io::Result<String> {
+    let len = read_u_varint(reader)? as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated path in manifest"));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("Path in manifest is not valid utf8"))
+}
+
+pub(crate) fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let manifest = Manifest {
+            entries: vec![
+                Entry { path: "bin".to_string(), mode: 0o755, change: Change::Directory },
+                Entry { path: "bin/app".to_string(), mode: 0o755, change: Change::Modified { payload_len: 42 } },
+                Entry { path: "lib/a.so".to_string(), mode: 0o644, change: Change::Renamed { from: "lib/b.so".to_string(), payload_len: 7 } },
+                Entry { path: "lib/b.so".to_string(), mode: 0, change: Change::Removed },
+                Entry { path: "lib/current".to_string(), mode: 0, change: Change::Symlink { target: "a.so".to_string() } },
+                Entry { path: "readme".to_string(), mode: 0o644, change: Change::Unchanged },
+                Entry { path: "new".to_string(), mode: 0o600, change: Change::Added { payload_len: 3 } },
+            ],
+        };
+        let mut bytes = Vec::new();
+        manifest.write(&mut bytes).unwrap();
+        let read = Manifest::read(&mut std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(manifest, read);
+    }
+}
@@ -0,0 +1,110 @@
+//! Walking a directory tree into a flat, sorted map of relative paths.
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{manifest::invalid, SymlinkPolicy};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum NodeKind {
+    File { len: u64 },
+    Dir,
+    Symlink { target: String },
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Node {
+    pub(crate) kind: NodeKind,
+    pub(crate) mode: u32,
+    /// Where the node can be read from (after following links, if the policy says so).
+    pub(crate) abs_path: PathBuf,
+}
+
+impl Node {
+    pub(crate) fn is_file(&self) -> bool {
+        matches!(self.kind, NodeKind::File { .. })
+    }
+}
+
+/// Returns every node below `root` keyed by its '/' separated path relative to `root`.
+pub(crate) fn scan_tree(root: &Path, policy: SymlinkPolicy) -> std::io::Result<BTreeMap<String, Node>> {
+    let mut nodes = BTreeMap::new();
+    let mut visited = HashSet::new();
+    visited.insert(fs::canonicalize(root)?);
+    scan_dir(root, "", policy, &mut nodes, &mut visited)?;
+    Ok(nodes)
+}
+
+fn scan_dir(dir: &Path, rel_dir: &str, policy: SymlinkPolicy, nodes: &mut BTreeMap<String, Node>, visited: &mut HashSet<PathBuf>) -> std::io::Result<()> {
+    let mut children = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    children.sort_by_key(|c| c.file_name());
+    for child in children {
+        let name = child.file_name().into_string().map_err(|n| invalid(format!("Path is not valid utf8: {:?}", n)))?;
+        let rel_path = if rel_dir.is_empty() { name } else { format!("{}/{}", rel_dir, name) };
+        let abs_path = child.path();
+        let mut meta = fs::symlink_metadata(&abs_path)?;
+        if meta.file_type().is_symlink() {
+            match policy {
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Preserve => {
+                    let target = fs::read_link(&abs_path)?;
+                    let target = target.into_os_string().into_string().map_err(|t| invalid(format!("Link target is not valid utf8: {:?}", t)))?;
+                    nodes.insert(rel_path, Node { kind: NodeKind::Symlink { target }, mode: 0, abs_path });
+                    continue;
+                },
+                SymlinkPolicy::Follow => meta = fs::metadata(&abs_path)?,
+            }
+        }
+        let mode = mode_of(&meta);
+        if meta.is_dir() {
+            //guard against link loops when following links.
+            if !visited.insert(fs::canonicalize(&abs_path)?) {
+                continue;
+            }
+            nodes.insert(rel_path.clone(), Node { kind: NodeKind::Dir, mode, abs_path: abs_path.clone() });
+            scan_dir(&abs_path, &rel_path, policy, nodes, visited)?;
+        } else if meta.is_file() {
+            nodes.insert(rel_path, Node { kind: NodeKind::File { len: meta.len() }, mode, abs_path });
+        }
+        //anything else (sockets, fifos, devices) is not something we can diff.
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode_of(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(meta: &fs::Metadata) -> u32 {
+    match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(unix)]
+pub(crate) fn make_symlink(target: &str, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn make_symlink(_target: &str, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Symlinks can only be restored on unix"))
+}
@@ -0,0 +1,127 @@
+//! A virtual dictionary made by concatenating several named sources.
+//!
+//! The SMDIFF format only knows a single dictionary (`CopySrc::Dict`).
+//! To copy from several old files in one patch, the files are laid out back to back and addressed as one dictionary.
+//! The [`DictionaryManifest`] records where each source starts, so both sides agree on the layout.
+//!
+//! Layout of a serialized manifest:
+//! ```text
+//! Magic "SMDM" (4 bytes)
+//! Number of sources (u-varint)
+//! Sources...
+//!     Name (u-varint len + utf8 bytes)
+//!     Len (u-varint)
+//! ```
+use std::io::{Read, Write};
+
+use crate::{read_u_varint, write_u_varint};
+
+const DICT_MANIFEST_MAGIC: [u8; 4] = *b"SMDM";
+
+/// A single source within a concatenated dictionary.
+/// * name: The name of the source (usually a file path).
+/// * start: The dictionary address of the first byte of this source.
+/// * len: The length of the source in bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DictionarySource {
+    pub name: String,
+    pub start: u64,
+    pub len: u64,
+}
+
+impl DictionarySource {
+    /// The dictionary address one past the last byte of this source.
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// Maps dictionary address ranges to named sources.
+/// Sources are stored in the order they are concatenated.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DictionaryManifest {
+    sources: Vec<DictionarySource>,
+}
+
+impl DictionaryManifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Appends a source to the end of the dictionary and returns its start address.
+    pub fn push(&mut self, name: impl Into<String>, len: u64) -> u64 {
+        let start = self.total_len();
+        self.sources.push(DictionarySource { name: name.into(), start, len });
+        start
+    }
+    pub fn sources(&self) -> &[DictionarySource] {
+        &self.sources
+    }
+    /// The length of the whole concatenated dictionary.
+    pub fn total_len(&self) -> u64 {
+        self.sources.last().map(|s| s.end()).unwrap_or(0)
+    }
+    /// Finds the source containing the given dictionary address.
+    /// # Returns
+    /// The index of the source and the offset of `addr` within that source.
+    /// None if the address is past the end of the dictionary.
+    pub fn locate(&self, addr: u64) -> Option<(usize, u64)> {
+        //empty sources share their start with the next source, so we want the last source starting at or before addr that is not empty.
+        let idx = self.sources.partition_point(|s| s.end() <= addr);
+        let source = self.sources.get(idx)?;
+        Some((idx, addr - source.start))
+    }
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&DICT_MANIFEST_MAGIC)?;
+        write_u_varint(writer, self.sources.len() as u64)?;
+        for source in self.sources.iter() {
+            write_u_varint(writer, source.name.len() as u64)?;
+            writer.write_all(source.name.as_bytes())?;
+            write_u_varint(writer, source.len)?;
+        }
+        Ok(())
+    }
+    pub fn read<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != DICT_MANIFEST_MAGIC {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a smdiff dictionary manifest"));
+        }
+        let num_sources = read_u_varint(reader)?;
+        let mut manifest = Self::new();
+        for _ in 0..num_sources {
+            let name_len = read_u_varint(reader)?;
+            let mut name = Vec::new();
+            reader.take(name_len).read_to_end(&mut name)?;
+            if name.len() as u64 != name_len {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated dictionary manifest"));
+            }
+            let name = String::from_utf8(name).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Source name is not valid utf8"))?;
+            let len = read_u_varint(reader)?;
+            manifest.push(name, len);
+        }
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate_and_round_trip() {
+        let mut manifest = DictionaryManifest::new();
+        assert_eq!(manifest.push("a.txt", 10), 0);
+        assert_eq!(manifest.push("empty", 0), 10);
+        assert_eq!(manifest.push("b.txt", 5), 10);
+        assert_eq!(manifest.total_len(), 15);
+        assert_eq!(manifest.locate(0), Some((0, 0)));
+        assert_eq!(manifest.locate(9), Some((0, 9)));
+        assert_eq!(manifest.locate(10), Some((2, 0)));
+        assert_eq!(manifest.locate(14), Some((2, 4)));
+        assert_eq!(manifest.locate(15), None);
+        let mut bytes = Vec::new();
+        manifest.write(&mut bytes).unwrap();
+        let read = DictionaryManifest::read(&mut std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(manifest, read);
+    }
+}
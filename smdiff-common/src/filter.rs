@@ -0,0 +1,143 @@
+//! Reversible filters for executables, applied to both the dictionary and the target before encoding.
+//!
+//! Relative branch targets change whenever code moves, even when the branch itself did not change.
+//! The filters turn relative targets into absolute ones, so unchanged branches stay byte-identical between builds.
+//! These are simplified versions of the xz BCJ filters.
+//!
+//! A filtered patch starts with a small header, so the decoder knows which filter to reverse:
+//! ```text
+//! Magic "SMFL" (4 bytes)
+//! Filter id (u8)
+//! ```
+//! The first byte of the magic has non-zero version bits, so it can never be mistaken for a section header.
+//! Only `smdiff_decoder::apply_patch` reverses the filter. Tools that work on the sections directly (merging, transcoding,
+//! inverting, rebasing, rendering) reject filtered patches with an InvalidInput error. See section 4.3 of the spec.
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{read_u8, write_u8};
+
+const FILTER_MAGIC: [u8; 4] = *b"SMFL";
+
+/// Branch converter for a given instruction set.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// x86 and x86-64 CALL (E8) and JMP (E9) with a 32 bit relative target.
+    X86,
+    /// ARM64 BL instructions (26 bit relative target).
+    Arm64,
+}
+
+impl Filter {
+    /// The id stored in the patch header.
+    pub fn id(&self) -> u8 {
+        match self {
+            Filter::X86 => 1,
+            Filter::Arm64 => 2,
+        }
+    }
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Filter::X86),
+            2 => Some(Filter::Arm64),
+            _ => None,
+        }
+    }
+    /// Converts relative branch targets to absolute ones, in place.
+    pub fn apply(&self, data: &mut [u8]) {
+        match self {
+            Filter::X86 => x86(data, true),
+            Filter::Arm64 => arm64(data, true),
+        }
+    }
+    /// Undoes [`Filter::apply`], in place.
+    pub fn reverse(&self, data: &mut [u8]) {
+        match self {
+            Filter::X86 => x86(data, false),
+            Filter::Arm64 => arm64(data, false),
+        }
+    }
+}
+
+/// Writes the header that marks a patch as filtered. This goes before the first section.
+pub fn write_filter_header<W: Write>(filter: Filter, writer: &mut W) -> std::io::Result<()> {
+    writer.write_all(&FILTER_MAGIC)?;
+    write_u8(writer, filter.id())
+}
+
+/// Reads the filter header if the patch has one.
+/// If it does not, the reader is put back where it was.
+/// # Errors
+/// Returns an error if the filter id is unknown, or there was an issue reading the patch.
+pub fn read_filter_header<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Filter>> {
+    let start = reader.stream_position()?;
+    let mut magic = [0u8; 4];
+    let mut read = 0;
+    while read < magic.len() {
+        match reader.read(&mut magic[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    if read < magic.len() || magic != FILTER_MAGIC {
+        reader.seek(SeekFrom::Start(start))?;
+        return Ok(None);
+    }
+    let id = read_u8(reader)?;
+    Filter::from_id(id)
+        .map(Some)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown filter id {}", id)))
+}
+
+/// Every E8/E9 byte is treated as an opcode, the decision only looks at bytes the filter never changes so it stays reversible.
+/// Targets are converted with wrapping arithmetic.
+fn x86(data: &mut [u8], encode: bool) {
+    let mut i = 0;
+    while i + 5 <= data.len() {
+        if data[i] == 0xE8 || data[i] == 0xE9 {
+            let value = u32::from_le_bytes(data[i + 1..i + 5].try_into().unwrap());
+            let pos = (i + 5) as u32;
+            let converted = if encode { value.wrapping_add(pos) } else { value.wrapping_sub(pos) };
+            data[i + 1..i + 5].copy_from_slice(&converted.to_le_bytes());
+            i += 5;
+        } else {
+            i += 1;
+        }
+    }
+}
+
+fn arm64(data: &mut [u8], encode: bool) {
+    for (n, insn) in data.chunks_exact_mut(4).enumerate() {
+        let value = u32::from_le_bytes((&*insn).try_into().unwrap());
+        if value >> 26 != 0b100101 {
+            continue;
+        }
+        let pc = n as u32;
+        let imm = value & 0x03FF_FFFF;
+        let converted = if encode { imm.wrapping_add(pc) } else { imm.wrapping_sub(pc) };
+        insn.copy_from_slice(&((value & 0xFC00_0000) | (converted & 0x03FF_FFFF)).to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::test_util::random_bytes;
+
+    #[test]
+    fn test_filter_round_trip() {
+        let mut data = random_bytes(11, 10_000);
+        //a call to the same absolute target from two places
+        data[100..105].copy_from_slice(&[0xE8, 0x00, 0x10, 0x00, 0x00]);
+        data[3000..3005].copy_from_slice(&[0xE8, 0xAC, 0x04, 0x00, 0x00]);
+        for filter in [Filter::X86, Filter::Arm64] {
+            let mut filtered = data.clone();
+            filter.apply(&mut filtered);
+            assert_ne!(filtered, data);
+            if filter == Filter::X86 {
+                assert_eq!(filtered[101..105], filtered[3001..3005]);
+            }
+            filter.reverse(&mut filtered);
+            assert_eq!(filtered, data);
+        }
+    }
+}
@@ -1,3 +1,7 @@
+pub mod dictionary;
+pub mod filter;
+pub mod progress;
+pub mod run;
 
 /// Bits for the operation type
 pub const OP_MASK: u8 = 0b11000000;
@@ -189,6 +193,19 @@ impl<A:AddOp> Op<A> {
             Op::Run(run) => run.len as u16,
         }
     }
+    /// Get the number of bytes this operation takes in an Interleaved section.
+    /// * `last_d_addr` - The address of the previous Copy-D in the section (0 if none).
+    /// * `last_o_addr` - The address of the previous Copy-O in the section (0 if none).
+    ///
+    /// In a Segregated section the total is the same, the Add bytes are just stored at the end.
+    pub fn encoded_size(&self, last_d_addr: u64, last_o_addr: u64) -> usize {
+        op_header_size(self.oal()) + match self {
+            Op::Add(add) => add.bytes().len(),
+            Op::Run(_) => 1,
+            Op::Copy(Copy { src: CopySrc::Dict, addr, .. }) => copy_addr_size(last_d_addr, *addr),
+            Op::Copy(Copy { src: CopySrc::Output, addr, .. }) => copy_addr_size(last_o_addr, *addr),
+        }
+    }
 }
 
 
@@ -223,6 +240,18 @@ pub fn size_routine(size: u16)->Size{
     }
 }
 
+/// Number of bytes used by the OpByte and the Size Indicator of an operation of `size` (oal).
+#[inline]
+pub fn op_header_size(size: u16) -> usize {
+    1 + size_routine(size).size_overhead()
+}
+
+/// Number of bytes used by the i-varint of a copy address, given the address of the previous copy of the same kind.
+#[inline]
+pub fn copy_addr_size(last_addr: u64, addr: u64) -> usize {
+    u_varint_encode_size(zigzag_encode(diff_addresses_to_i64(last_addr, addr)))
+}
+
 /// Convert an i64 to a u64 using ZigZag encoding.
 #[inline]
 pub fn zigzag_encode(n: i64) -> u64 {
@@ -334,6 +363,21 @@ pub fn diff_addresses_to_i64(cur: u64, target: u64) -> i64 {
     }
 }
 
+/// Seeded test data shared by the tests of the smdiff crates, enabled with the `test-util` feature.
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util {
+    /// Deterministic pseudo random numbers for tests (a 64 bit LCG), the same on every platform.
+    pub fn random_stream(seed: u64) -> impl Iterator<Item = u64> {
+        std::iter::successors(Some(seed), |state| Some(state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407)))
+            .skip(1)
+            .map(|state| state >> 33)
+    }
+    /// Deterministic pseudo random bytes for tests, the low byte of each number from `random_stream`.
+    pub fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        random_stream(seed).take(len).map(|n| n as u8).collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,70 @@
+//! Progress reporting and cooperative cancellation for long running operations.
+//!
+//! The `*_with_observer` variants of encoding, applying, merging and transcoding take an [`Observer`].
+//! It is told which [`Phase`] the work is in and how far along it is, and is asked every so often whether to stop.
+//! If it asks to stop, the operation returns an error that [`is_cancelled`] recognizes. Anything already written to the output is incomplete.
+use std::io;
+
+/// What a long running operation is currently doing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Building the hash table (or suffix array) over the dictionary.
+    HashingSource,
+    /// Finding matches for the target.
+    Matching,
+    /// Writing sections without secondary compression.
+    Writing,
+    /// Writing sections with secondary compression.
+    Compressing,
+    /// Reading (and decompressing) the sections of a patch.
+    Reading,
+    /// Producing the output of a patch.
+    Applying,
+    /// Merging the ops of two patches.
+    Merging,
+}
+
+/// Receives progress updates, and can cancel the operation.
+///
+/// All methods have empty defaults, so only the interesting ones need implementing.
+pub trait Observer {
+    /// A new phase has started.
+    fn phase(&mut self, _phase: Phase) {}
+    /// The number of bytes processed so far in the current phase.
+    /// For matching and applying these are target (output) bytes, for writing they are the output bytes the written sections cover.
+    fn bytes_processed(&mut self, _bytes: u64) {}
+    /// The number of sections written (or applied) so far.
+    fn sections_processed(&mut self, _sections: usize) {}
+    /// Polled regularly. Returning true stops the operation with a [`Cancelled`] error.
+    fn cancelled(&mut self) -> bool {
+        false
+    }
+}
+
+/// The no-op observer.
+impl Observer for () {}
+
+/// The error (inside an `std::io::Error`) returned when an [`Observer`] cancels an operation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Operation was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Returns true if the error came from a cancelled operation.
+pub fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|e| e.is::<Cancelled>())
+}
+
+/// Returns the [`Cancelled`] error if the observer asks to stop.
+pub fn check_cancelled(observer: &mut dyn Observer) -> io::Result<()> {
+    if observer.cancelled() {
+        return Err(io::Error::other(Cancelled));
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Encoding of long runs of a single byte, shared by the encoder and the transcoder.
+//!
+//! A Run op covers at most MAX_RUN_LEN bytes. Longer runs are cheaper as Copy-O ops of the part of the run already written,
+//! each of which can cover everything written so far (up to MAX_INST_SIZE). So a Run or two, then copies that double in size.
+//!
+//! Sparse data (like disk images) has many long runs of the same byte, mostly zeros. A [`RunCache`] remembers the
+//! longest run of each byte value in the output, so a later run can start copying from it right away instead of growing again.
+//!
+//! Each run is written with the cheapest (in patch bytes, then in ops) of: only Runs, doubling copies of itself,
+//! or copies of the earlier run. Copying itself always uses the fewest ops possible.
+use crate::{copy_addr_size, op_header_size, AddOp, Copy, CopySrc, Op, Run, MAX_INST_SIZE, MAX_RUN_LEN};
+
+/// Remembers the longest run of each byte value written so far.
+#[derive(Clone, Debug)]
+pub struct RunCache {
+    /// (start, len) in the output, len 0 if there is none yet.
+    longest: [(u64, u64); 256],
+    /// The address of the last Copy-O, which the next copy address is relative to.
+    /// Copy addresses start again from 0 in every section, but the ops are only split into sections after they are made,
+    /// so this is not reset. For the first copies of a section the costs are then estimates.
+    /// That only changes which plan is picked, every plan writes the same bytes.
+    last_o_addr: u64,
+    /// How many ops of the output have been looked at for Copy-O addresses.
+    seen_ops: usize,
+}
+
+impl Default for RunCache {
+    fn default() -> Self {
+        Self { longest: [(0, 0); 256], last_o_addr: 0, seen_ops: 0 }
+    }
+}
+
+/// One op of a planned run.
+#[derive(Copy, Clone, Debug)]
+enum Step {
+    Run(u8),
+    Copy { addr: u64, len: u16 },
+}
+
+impl RunCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Records that `len` bytes of `byte` are in the output at `start`, however they were written.
+    pub fn note_run(&mut self, byte: u8, start: u64, len: u64) {
+        let longest = &mut self.longest[byte as usize];
+        if len > longest.1 {
+            *longest = (start, len);
+        }
+    }
+    /// The longest run of `byte` recorded so far, as (start, len).
+    pub fn longest(&self, byte: u8) -> Option<(u64, u64)> {
+        let (start, len) = self.longest[byte as usize];
+        (len > 0).then_some((start, len))
+    }
+    /// Pushes the cheapest ops that write `len` bytes of `byte` at output position `run_start_pos`, then records the run.
+    ///
+    /// The same `output` should be used for every call, the ops pushed in between are looked at for the last Copy-O address.
+    /// * `byte` - The byte to repeat.
+    /// * `len` - The length of the run.
+    /// * `run_start_pos` - Where the run starts in the output (not the section).
+    /// * `output` - The ops are pushed here.
+    pub fn make_run_ops<A: AddOp>(&mut self, byte: u8, len: usize, run_start_pos: u64, output: &mut Vec<Op<A>>) {
+        for op in output[self.seen_ops.min(output.len())..].iter() {
+            if let Op::Copy(Copy { src: CopySrc::Output, addr, .. }) = op {
+                self.last_o_addr = *addr;
+            }
+        }
+        let earlier = self.longest(byte).filter(|(_, prev_len)| *prev_len > MAX_RUN_LEN as u64);
+        let mut best = plan(len, run_start_pos, None, false);
+        let mut best_cost = self.cost(&best);
+        for candidate in [Some(plan(len, run_start_pos, None, true)), earlier.map(|e| plan(len, run_start_pos, Some(e), true))].into_iter().flatten() {
+            let cost = self.cost(&candidate);
+            if cost < best_cost {
+                (best, best_cost) = (candidate, cost);
+            }
+        }
+        for step in best {
+            output.push(match step {
+                Step::Run(run_len) => Op::Run(Run { byte, len: run_len }),
+                Step::Copy { addr, len } => {
+                    self.last_o_addr = addr;
+                    Op::Copy(Copy { src: CopySrc::Output, addr, len })
+                },
+            });
+        }
+        self.seen_ops = output.len();
+        self.note_run(byte, run_start_pos, len as u64);
+    }
+    /// (patch bytes, ops) of a plan.
+    /// Address sizes assume the plan is in the same section as the last Copy-O, see `last_o_addr`.
+    fn cost(&self, steps: &[Step]) -> (usize, usize) {
+        let mut last_o_addr = self.last_o_addr;
+        let bytes = steps.iter().map(|step| match *step {
+            Step::Run(_) => 2,
+            Step::Copy { addr, len } => {
+                let size = op_header_size(len) + copy_addr_size(last_o_addr, addr);
+                last_o_addr = addr;
+                size
+            },
+        }).sum();
+        (bytes, steps.len())
+    }
+}
+
+/// Plans the fewest ops for a run, copying from the run itself or from `earlier` (whichever has more), or only Runs.
+/// A copy is only used when it covers more than a Run would.
+fn plan(len: usize, run_start_pos: u64, earlier: Option<(u64, u64)>, copies: bool) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let mut written = 0;
+    while written < len {
+        let remaining = len - written;
+        let run_len = remaining.min(MAX_RUN_LEN as usize);
+        let (addr, available) = match earlier {
+            Some((start, prev_len)) if prev_len as usize > written => (start, prev_len as usize),
+            _ => (run_start_pos, written),
+        };
+        let copy_len = remaining.min(available).min(MAX_INST_SIZE);
+        if copies && copy_len > run_len {
+            steps.push(Step::Copy { addr, len: copy_len as u16 });
+            written += copy_len;
+        } else {
+            steps.push(Step::Run(run_len as u8));
+            written += run_len;
+        }
+    }
+    steps
+}
+
+/// Pushes the cheapest ops that write `len` bytes of `byte` at output position `run_start_pos`.
+/// Only the run itself is copied from, use a [`RunCache`] to also copy from earlier runs.
+pub fn make_run_ops<A: AddOp>(byte: u8, len: usize, run_start_pos: u64, output: &mut Vec<Op<A>>) {
+    RunCache { seen_ops: output.len(), ..RunCache::new() }.make_run_ops(byte, len, run_start_pos, output)
+}
+
+/// The fewest ops a run of `len` bytes can be written with, without an earlier run to copy from.
+pub fn run_op_count(len: usize) -> usize {
+    let mut written = 0;
+    let mut count = 0;
+    while written < len {
+        let remaining = len - written;
+        written += remaining.min((MAX_RUN_LEN as usize).max(written.min(MAX_INST_SIZE)));
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct NoAdd;
+    impl AddOp for NoAdd {
+        fn bytes(&self) -> &[u8] {
+            &[]
+        }
+    }
+
+    /// Applies the ops, checking every copy only reads bytes already written.
+    fn apply(ops: &[Op<NoAdd>], mut output: Vec<u8>) -> Vec<u8> {
+        for op in ops {
+            match op {
+                Op::Run(run) => output.extend(std::iter::repeat_n(run.byte, run.len as usize)),
+                Op::Copy(copy) => {
+                    let start = copy.addr as usize;
+                    assert!(start + copy.len as usize <= output.len(), "Copy reads past the output {:?}", copy);
+                    output.extend_from_within(start..start + copy.len as usize);
+                },
+                Op::Add(_) => unreachable!(),
+            }
+        }
+        output
+    }
+
+    /// The fewest ops for every run length up to `max_len`, by a search over every op length.
+    fn min_op_counts(max_len: usize) -> Vec<usize> {
+        let mut best = vec![usize::MAX; max_len + 1];
+        best[0] = 0;
+        for w in 0..max_len {
+            let max_step = (MAX_RUN_LEN as usize).max(w.min(MAX_INST_SIZE));
+            for step in 1..=max_step.min(max_len - w) {
+                best[w + step] = best[w + step].min(best[w] + 1);
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn test_short_runs() {
+        let mut output: Vec<Op<NoAdd>> = Vec::new();
+        make_run_ops(0xAA, 120, 0, &mut output);
+        assert_eq!(output, vec![Op::Run(Run { byte: 0xAA, len: 62 }), Op::Run(Run { byte: 0xAA, len: 58 })]);
+        output.clear();
+        //three Runs are as few ops as a Run and two copies, and need no addresses.
+        make_run_ops(0xBB, 186, 0, &mut output);
+        assert_eq!(output, vec![Op::Run(Run { byte: 0xBB, len: 62 }); 3]);
+        output.clear();
+        make_run_ops(0xDD, 0, 0, &mut output);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_doubling_copies() {
+        let mut output: Vec<Op<NoAdd>> = Vec::new();
+        make_run_ops(0xBB, 414, 10, &mut output);
+        assert_eq!(output, vec![
+            Op::Run(Run { byte: 0xBB, len: 62 }),
+            Op::Run(Run { byte: 0xBB, len: 62 }),
+            Op::Copy(Copy { src: CopySrc::Output, addr: 10, len: 124 }),
+            Op::Copy(Copy { src: CopySrc::Output, addr: 10, len: 166 }),
+        ]);
+        assert_eq!(apply(&output, vec![0; 10]), [vec![0; 10], vec![0xBB; 414]].concat());
+    }
+
+    #[test]
+    fn test_optimal_op_counts() {
+        let best = min_op_counts(5_000);
+        for (len, best) in best.into_iter().enumerate() {
+            let mut output: Vec<Op<NoAdd>> = Vec::new();
+            make_run_ops(0, len, 0, &mut output);
+            assert_eq!(output.len(), best, "len {}", len);
+            assert_eq!(output.len(), run_op_count(len), "len {}", len);
+            assert_eq!(apply(&output, Vec::new()), vec![0; len]);
+        }
+        for len in [65_535, 65_536, 200_000, 1 << 20] {
+            let mut output: Vec<Op<NoAdd>> = Vec::new();
+            make_run_ops(0, len, 0, &mut output);
+            assert_eq!(output.len(), run_op_count(len), "len {}", len);
+            assert_eq!(apply(&output, Vec::new()), vec![0; len]);
+        }
+        //1 MiB of zeros: Runs and doubling copies up to 63488 bytes, then max size copies.
+        assert_eq!(run_op_count(1 << 20), 27);
+    }
+
+    #[test]
+    fn test_sparse_zero_regions() {
+        //Zero regions with data between them, like a disk image.
+        let mut cache = RunCache::new();
+        let mut output: Vec<Op<NoAdd>> = Vec::new();
+        let mut expected = Vec::new();
+        let mut counts = Vec::new();
+        for (i, len) in [100_000usize, 70_000, 300, 100_000].into_iter().enumerate() {
+            output.push(Op::Run(Run { byte: i as u8 + 1, len: 1 }));
+            expected.push(i as u8 + 1);
+            let before = output.len();
+            cache.make_run_ops(0, len, expected.len() as u64, &mut output);
+            counts.push(output.len() - before);
+            expected.extend(std::iter::repeat_n(0, len));
+        }
+        assert_eq!(apply(&output, Vec::new()), expected);
+        //Only the first region has to grow, the rest copy from it (at most MAX_INST_SIZE per op, so this is the fewest possible).
+        assert_eq!(counts, vec![run_op_count(100_000), 2, 1, 2]);
+        assert_eq!(cache.longest(0), Some((1, 100_000)));
+    }
+}
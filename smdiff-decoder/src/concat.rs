@@ -0,0 +1,101 @@
+//! Read+Seek over several readers laid end to end.
+use std::io::{Read, Seek, SeekFrom};
+
+use smdiff_common::dictionary::DictionaryManifest;
+
+/// Presents a list of readers as one continuous dictionary.
+///
+/// This is the decoder side of a concatenated dictionary (see [`DictionaryManifest`]).
+pub struct ConcatReader<R> {
+    readers: Vec<R>,
+    manifest: DictionaryManifest,
+    pos: u64,
+}
+
+impl<R: Read + Seek> ConcatReader<R> {
+    /// Creates a reader over the given readers, measuring the length of each one.
+    /// The sources are named by their index.
+    pub fn new(mut readers: Vec<R>) -> std::io::Result<Self> {
+        let mut manifest = DictionaryManifest::new();
+        for (i, reader) in readers.iter_mut().enumerate() {
+            let len = reader.seek(SeekFrom::End(0))?;
+            manifest.push(i.to_string(), len);
+        }
+        Ok(Self { readers, manifest, pos: 0 })
+    }
+    /// Creates a reader over the given readers, checking that they match the manifest the patch was encoded with.
+    /// # Errors
+    /// Returns an error if the number of readers or the length of any reader does not match the manifest.
+    pub fn with_manifest(readers: Vec<R>, manifest: &DictionaryManifest) -> std::io::Result<Self> {
+        let concat = Self::new(readers)?;
+        if concat.readers.len() != manifest.sources().len() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Expected {} dictionaries, got {}", manifest.sources().len(), concat.readers.len())));
+        }
+        for (expected, found) in manifest.sources().iter().zip(concat.manifest.sources()) {
+            if expected.len != found.len {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Dictionary '{}' should be {} bytes, got {}", expected.name, expected.len, found.len)));
+            }
+        }
+        Ok(Self { manifest: manifest.clone(), ..concat })
+    }
+    pub fn manifest(&self) -> &DictionaryManifest {
+        &self.manifest
+    }
+    pub fn into_inner(self) -> Vec<R> {
+        self.readers
+    }
+}
+
+impl<R: Read + Seek> Read for ConcatReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (idx, offset) = match self.manifest.locate(self.pos) {
+            Some(found) => found,
+            None => return Ok(0),
+        };
+        let source_len = self.manifest.sources()[idx].len;
+        let reader = &mut self.readers[idx];
+        reader.seek(SeekFrom::Start(offset))?;
+        //never read past this source, the next read moves on to the next one.
+        let max = buf.len().min((source_len - offset) as usize);
+        let read = reader.read(&mut buf[..max])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for ConcatReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.manifest.total_len().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_across_sources() {
+        let readers = vec![Cursor::new(b"hello".to_vec()), Cursor::new(Vec::new()), Cursor::new(b" world".to_vec())];
+        let mut concat = ConcatReader::new(readers).unwrap();
+        assert_eq!(concat.manifest().total_len(), 11);
+        concat.seek(SeekFrom::Start(3)).unwrap();
+        let mut buf = [0u8; 5];
+        concat.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"lo wo");
+        let mut rest = Vec::new();
+        concat.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"rld");
+    }
+}
@@ -1,6 +1,9 @@
-use std::io::{Read, Seek, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
-use smdiff_common::MAX_INST_SIZE;
+use smdiff_common::{dictionary::DictionaryManifest, filter::{read_filter_header, Filter}, progress::{check_cancelled, Observer, Phase}, MAX_INST_SIZE};
+pub use concat::ConcatReader;
+pub use sink::{PatchSink, SparseSink, DEFAULT_MIN_HOLE_SIZE};
+use sink::PlainSink;
 
 pub mod zstd{
     //! Re-exports the zstd streaming decoder used
@@ -11,6 +14,8 @@ pub mod brotli{
     pub use brotlic::DecompressorReader;
 }
 pub mod reader;
+mod concat;
+mod sink;
 ///Applies an SMDiff patch to a source buffer
 /// # Arguments
 /// * `patch` - A Read object that contains the SMDiff patch data
@@ -20,17 +25,94 @@ pub mod reader;
 /// Returns an error if there is an issue reading from the patch or source data, or writing to the sink
 ///
 /// Note: To enable patch application to large files, we require Read+Seek on the Sink to handle CopySrc::Output operations
-pub fn apply_patch<P:Read+Seek,R:Read+Seek,W:Write+Read+Seek>(patch:&mut P,mut src:Option<&mut R>,sink:&mut W) -> std::io::Result<()> {
+///
+/// If the patch was encoded with a filter (see `smdiff_common::filter`), the filter is reversed automatically.
+/// This needs the whole source and output in memory.
+pub fn apply_patch<P:Read+Seek,R:Read+Seek,W:Write+Read+Seek>(patch:&mut P,src:Option<&mut R>,sink:&mut W) -> std::io::Result<()> {
+    apply_patch_with_observer(patch, src, sink, &mut ())
+}
+
+///Same as [`apply_patch`], but reports progress to the observer, which can also cancel applying the patch.
+/// # Arguments
+/// * `patch` - A Read object that contains the SMDiff patch data
+/// * `src` - An optional mutable reference to a Read+Seek object that contains the source (dictionary) data
+/// * `sink` - A Write object that will receive the patched data
+/// * `observer` - Told about every section applied, and the output bytes written so far
+/// # Errors
+/// Returns an error if there is an issue reading from the patch or source data, or writing to the sink
+/// Returns a [`smdiff_common::progress::Cancelled`] error if the observer cancelled, the sink then holds partial output.
+pub fn apply_patch_with_observer<P:Read+Seek,R:Read+Seek,W:Write+Read+Seek>(patch:&mut P,src:Option<&mut R>,sink:&mut W,observer:&mut dyn Observer) -> std::io::Result<()> {
+    apply_patch_to_sink(patch, src, &mut PlainSink(sink), observer)
+}
+
+///Same as [`apply_patch_with_observer`], but writes through a [`PatchSink`], which is told about zero Runs and copies of zeros separately.
+///
+/// Use a [`SparseSink`] over a new file to leave holes for long stretches of zeros (like the free space of a disk image) instead of writing them.
+/// # Arguments
+/// * `patch` - A Read object that contains the SMDiff patch data
+/// * `src` - An optional mutable reference to a Read+Seek object that contains the source (dictionary) data
+/// * `sink` - The sink that will receive the patched data. [`PatchSink::finish`] is called once all of it is written
+/// * `observer` - Told about every section applied, and the output bytes written so far
+/// # Errors
+/// Returns an error if there is an issue reading from the patch or source data, or writing to the sink
+/// Returns a [`smdiff_common::progress::Cancelled`] error if the observer cancelled, the sink then holds partial output.
+pub fn apply_patch_to_sink<P:Read+Seek,R:Read+Seek,S:PatchSink>(patch:&mut P,src:Option<&mut R>,sink:&mut S,observer:&mut dyn Observer) -> std::io::Result<()> {
+    match read_filter_header(patch)? {
+        Some(filter) => apply_filtered(patch, filter, src, sink, observer)?,
+        None => apply_sections(patch, src, sink, observer)?,
+    }
+    sink.finish()
+}
+
+fn apply_filtered<P:Read+Seek,R:Read+Seek,S:PatchSink>(patch:&mut P,filter:Filter,src:Option<&mut R>,sink:&mut S,observer:&mut dyn Observer) -> std::io::Result<()> {
+    let mut dict = None;
+    if let Some(src) = src {
+        let mut bytes = Vec::new();
+        src.seek(SeekFrom::Start(0))?;
+        src.read_to_end(&mut bytes)?;
+        filter.apply(&mut bytes);
+        dict = Some(Cursor::new(bytes));
+    }
+    let mut output = Cursor::new(Vec::new());
+    apply_sections(patch, dict.as_mut(), &mut PlainSink(&mut output), observer)?;
+    let mut output = output.into_inner();
+    filter.reverse(&mut output);
+    sink.seek(SeekFrom::Start(0))?;
+    for chunk in output.chunks(MAX_INST_SIZE) {
+        write_chunk(sink, chunk)?;
+    }
+    Ok(())
+}
+
+fn apply_sections<P:Read+Seek,R:Read+Seek,S:PatchSink>(patch:&mut P,mut src:Option<&mut R>,sink:&mut S,observer:&mut dyn Observer) -> std::io::Result<()> {
     let mut cur_o_pos: usize = 0;
     //let mut stats = Stats::default();
     let mut reader = crate::reader::SectionIterator::new(patch);
+    let mut sections = 0;
+    observer.phase(Phase::Applying);
     while let Some(res) = reader.next_borrowed(){
+        check_cancelled(observer)?;
         let (ops,_header) = res?;
         apply_ops(ops, &mut src, sink, &mut cur_o_pos)?;
+        sections += 1;
+        observer.sections_processed(sections);
+        observer.bytes_processed(cur_o_pos as u64);
     }
     Ok(())
 }
 
+///Applies an SMDiff patch that was encoded against several dictionaries (see `smdiff_encoder::encode_multi`)
+/// # Arguments
+/// * `patch` - A Read object that contains the SMDiff patch data
+/// * `manifest` - The dictionary manifest returned by the encoder
+/// * `dicts` - The dictionaries, in the same order as the manifest
+/// * `sink` - A Write object that will receive the patched data
+/// # Errors
+/// Returns an error if the dictionaries do not match the manifest, or if there is an issue reading from the patch or source data, or writing to the sink
+pub fn apply_patch_multi<P:Read+Seek,R:Read+Seek,W:Write+Read+Seek>(patch:&mut P,manifest:&DictionaryManifest,dicts:Vec<R>,sink:&mut W) -> std::io::Result<()> {
+    let mut src = ConcatReader::with_manifest(dicts, manifest)?;
+    apply_patch(patch, Some(&mut src), sink)
+}
 
 fn apply_no_sec_comp<P:Read,R:Read+Seek,W:Write+Read+Seek>(patch:&mut P,mut src:Option<&mut R>,sink:&mut W) -> std::io::Result<()> {
     //To avoid Seek on write, we must write all the output data to a Vec<u8> first
@@ -38,7 +120,7 @@ fn apply_no_sec_comp<P:Read,R:Read+Seek,W:Write+Read+Seek>(patch:&mut P,mut src:
     let mut reader = smdiff_reader::SectionIterator::new(patch);
     while let Some(res) = reader.next_borrowed(){
         let (ops,_header) = res?;
-        apply_ops(ops, &mut src, sink, &mut cur_o_pos)?;
+        apply_ops(ops, &mut src, &mut PlainSink(sink), &mut cur_o_pos)?;
 
     }
     Ok(())
@@ -48,7 +130,8 @@ fn apply_no_sec_comp<P:Read,R:Read+Seek,W:Write+Read+Seek>(patch:&mut P,mut src:
 /// Here `cur_o` represents the output buffer.
 /// We could replace it with W:Write+Read+Seek if we didn't want to allocate the entire output buffer in memory
 /// So... maybe TODO?
-fn apply_ops<R:Read+Seek,W:Write+Read+Seek>(ops:&[smdiff_reader::Op],src:&mut Option<&mut R>,cur_o:&mut W, cur_o_pos: &mut usize) -> std::io::Result<()> {
+/// Zero Runs and copies of zeros go to `PatchSink::write_zeros`.
+fn apply_ops<R:Read+Seek,S:PatchSink>(ops:&[smdiff_reader::Op],src:&mut Option<&mut R>,cur_o:&mut S, cur_o_pos: &mut usize) -> std::io::Result<()> {
     //let mut stats = Stats::default();
     //let out_size = header.output_size as usize;
     cur_o.seek(std::io::SeekFrom::Start(*cur_o_pos as u64))?;
@@ -72,7 +155,7 @@ fn apply_ops<R:Read+Seek,W:Write+Read+Seek>(ops:&[smdiff_reader::Op],src:&mut Op
                         src.seek(std::io::SeekFrom::Start(copy.addr))?;
                         let len = copy.len as usize;
                         src.read_exact(&mut copy_buffer[..len])?;
-                        cur_o.write_all(&copy_buffer[..len])?;
+                        write_chunk(cur_o, &copy_buffer[..len])?;
                         *cur_o_pos += len;
                     },
                     smdiff_common::CopySrc::Output => {
@@ -81,7 +164,7 @@ fn apply_ops<R:Read+Seek,W:Write+Read+Seek>(ops:&[smdiff_reader::Op],src:&mut Op
                         let len = copy.len as usize;
                         cur_o.read_exact(&mut copy_buffer[..len])?;
                         cur_o.seek(std::io::SeekFrom::Start(start_pos as u64))?;
-                        cur_o.write_all(&copy_buffer[..len])?;
+                        write_chunk(cur_o, &copy_buffer[..len])?;
                         *cur_o_pos += len;
                     },
                 }
@@ -90,8 +173,12 @@ fn apply_ops<R:Read+Seek,W:Write+Read+Seek>(ops:&[smdiff_reader::Op],src:&mut Op
             smdiff_common::Op::Run(run) => {
                 //stats.run();
                 let len = run.len as usize;
-                copy_buffer[..len].fill(run.byte);
-                cur_o.write_all(&copy_buffer[..len])?;
+                if run.byte == 0 {
+                    cur_o.write_zeros(len)?;
+                } else {
+                    copy_buffer[..len].fill(run.byte);
+                    cur_o.write_all(&copy_buffer[..len])?;
+                }
                 *cur_o_pos += len;
             },
         }
@@ -100,6 +187,14 @@ fn apply_ops<R:Read+Seek,W:Write+Read+Seek>(ops:&[smdiff_reader::Op],src:&mut Op
     Ok(())
 }
 
+fn write_chunk<S:PatchSink>(sink:&mut S,bytes:&[u8]) -> std::io::Result<()> {
+    if bytes.iter().all(|b| *b == 0) {
+        sink.write_zeros(bytes.len())
+    } else {
+        sink.write_all(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,316 @@
+//! Where the patched output is written.
+//!
+//! Patches of disk images are mostly long zero Runs and copies of zeros. Writing them out byte by byte is slow,
+//! and turns the holes of a sparse file into allocated blocks. A [`PatchSink`] is told about zeros separately,
+//! so a [`SparseSink`] can seek past them instead, leaving holes on filesystems that support them.
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Zeros written at a time by the default [`PatchSink::write_zeros`].
+const ZEROS: [u8; 4096] = [0; 4096];
+
+/// Default for [`SparseSink::set_min_hole_size`], a common filesystem block size.
+pub const DEFAULT_MIN_HOLE_SIZE: u64 = 4096;
+
+/// The output of a patch (see `apply_patch_to_sink`).
+///
+/// Like the sink of `apply_patch`, it needs Read+Seek for Copy-O ops.
+pub trait PatchSink: Read + Write + Seek {
+    /// Writes `len` zero bytes at the current position.
+    /// The default writes them, a sparse sink can skip over them instead.
+    fn write_zeros(&mut self, len: usize) -> io::Result<()> {
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(ZEROS.len());
+            self.write_all(&ZEROS[..n])?;
+            remaining -= n;
+        }
+        Ok(())
+    }
+    /// Called once all of the output has been written.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes to any Read+Write+Seek as it is, for `apply_patch`.
+pub(crate) struct PlainSink<'a, W>(pub(crate) &'a mut W);
+
+impl<W: Read> Read for PlainSink<'_, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<W: Write> Write for PlainSink<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Seek> Seek for PlainSink<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<W: Read + Write + Seek> PatchSink for PlainSink<'_, W> {}
+
+/// A sink that seeks past long stretches of zeros instead of writing them.
+///
+/// Zeros past everything written so far are only counted. When more data is written after them (or the output is finished),
+/// they are skipped if there are at least `min_hole_size` of them, and written otherwise.
+/// Reads of skipped zeros are answered without touching the inner writer, so Copy-O ops of zeros stay cheap.
+/// [`PatchSink::finish`] writes the last byte of the output if it was skipped, so the output always has its full length.
+///
+/// On a file, skipped stretches become holes on filesystems that support sparse files, and read as zeros on all others.
+/// Call [`PatchSink::finish`] before using the inner writer (`apply_patch_to_sink` does).
+pub struct SparseSink<W> {
+    inner: W,
+    min_hole_size: u64,
+    /// The position in the output.
+    pos: u64,
+    /// The length of the output, including skipped zeros.
+    len: u64,
+    /// The length of the inner writer, everything past this is zeros.
+    written_end: u64,
+    /// Where the inner writer is, if known.
+    inner_pos: Option<u64>,
+    skipped: u64,
+}
+
+impl<W: Read + Write + Seek> SparseSink<W> {
+    /// Creates a sparse sink over `inner`, like a newly created (or truncated) file.
+    /// # Errors
+    /// Returns an error if `inner` is not empty, as skipped zeros would not overwrite what is there.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        if len != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("A SparseSink needs an empty writer, this one has {} bytes", len)));
+        }
+        Ok(Self { inner, min_hole_size: DEFAULT_MIN_HOLE_SIZE, pos: 0, len: 0, written_end: 0, inner_pos: Some(0), skipped: 0 })
+    }
+    /// Sets the fewest zeros in a row that are skipped, shorter stretches are written.
+    pub fn set_min_hole_size(mut self, size: u64) -> Self {
+        self.min_hole_size = size.max(1);
+        self
+    }
+    /// The number of zeros skipped so far, rather than written.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+    /// Writes to the inner writer at `pos`.
+    fn write_inner(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.inner_pos != Some(self.pos) {
+            self.inner_pos = None;
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            self.inner_pos = Some(self.pos);
+        }
+        let n = self.inner.write(buf).inspect_err(|_| self.inner_pos = None)?;
+        self.pos += n as u64;
+        self.inner_pos = Some(self.pos);
+        self.written_end = self.written_end.max(self.pos);
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+    fn write_inner_zeros(&mut self, len: u64) -> io::Result<()> {
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(ZEROS.len() as u64) as usize;
+            match self.write_inner(&ZEROS[..n])? {
+                0 => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write zeros")),
+                written => remaining -= written as u64,
+            }
+        }
+        Ok(())
+    }
+    /// Before writing at `pos`, writes the zeros between the end of the inner writer and `pos` if there are too few to skip.
+    fn fill_gap(&mut self) -> io::Result<()> {
+        if self.pos > self.written_end {
+            let gap = self.pos - self.written_end;
+            if gap < self.min_hole_size {
+                self.pos = self.written_end;
+                self.write_inner_zeros(gap)?;
+            } else {
+                self.skipped += gap;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Read + Write + Seek> Read for SparseSink<W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let n = if self.pos < self.written_end {
+            let max = buf.len().min((self.written_end - self.pos) as usize);
+            if self.inner_pos != Some(self.pos) {
+                self.inner_pos = None;
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+            }
+            let n = self.inner.read(&mut buf[..max]).inspect_err(|_| self.inner_pos = None)?;
+            self.inner_pos = Some(self.pos + n as u64);
+            n
+        } else {
+            let n = buf.len().min((self.len - self.pos) as usize);
+            buf[..n].fill(0);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Read + Write + Seek> Write for SparseSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.fill_gap()?;
+        self.write_inner(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Read + Write + Seek> Seek for SparseSink<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+        Ok(self.pos)
+    }
+}
+
+impl<W: Read + Write + Seek> PatchSink for SparseSink<W> {
+    fn write_zeros(&mut self, len: usize) -> io::Result<()> {
+        //Zeros over written data have to be written, the rest are only counted.
+        let overwrite = self.written_end.saturating_sub(self.pos).min(len as u64);
+        self.write_inner_zeros(overwrite)?;
+        self.pos += len as u64 - overwrite;
+        self.len = self.len.max(self.pos);
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        if self.written_end < self.len {
+            //Seeking past the end does not make a file longer, writing does.
+            let end = self.pos;
+            self.pos = self.len - 1;
+            self.fill_gap()?;
+            self.write_inner(&[0])?;
+            self.pos = end;
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use smdiff_common::{Copy, CopySrc, Op, Run};
+    use smdiff_reader::Add;
+
+    use super::*;
+    use crate::apply_ops;
+
+    /// Counts the bytes written to a Cursor.
+    struct Counting(Cursor<Vec<u8>>, usize);
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 += buf.len();
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Seek for Counting {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    /// A small disk image: data, a long zero region (Runs, then Copy-O of them), more data, a copy of zeros from the dictionary, and a zero tail.
+    fn disk_image_ops() -> (Vec<smdiff_reader::Op>, Vec<u8>, Vec<u8>) {
+        let dict = vec![0u8; 20_000];
+        let mut ops = vec![Op::Add(Add::new(b"boot sector".to_vec()))];
+        ops.extend(std::iter::repeat_n(Op::Run(Run { byte: 0, len: 62 }), 2));
+        let mut written = 124;
+        while written < 16_000 {
+            let len = written.min(16_000 - written);
+            ops.push(Op::Copy(Copy { src: CopySrc::Output, addr: 11, len: len as u16 }));
+            written += len;
+        }
+        ops.push(Op::Add(Add::new(b"inode table".to_vec())));
+        ops.push(Op::Copy(Copy { src: CopySrc::Dict, addr: 0, len: 20_000 }));
+        ops.push(Op::Run(Run { byte: 7, len: 3 }));
+        ops.push(Op::Copy(Copy { src: CopySrc::Output, addr: 11, len: 10_000 }));
+        let expected = [&b"boot sector"[..], &[0; 16_000], b"inode table", &[0; 20_000], &[7; 3], &[0; 10_000]].concat();
+        (ops, dict, expected)
+    }
+
+    fn apply_to<S: PatchSink>(sink: &mut S) {
+        let (ops, dict, _) = disk_image_ops();
+        let mut dict = Cursor::new(dict);
+        let mut pos = 0;
+        apply_ops(&ops, &mut Some(&mut dict), sink, &mut pos).unwrap();
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn test_sparse_sink() {
+        let (_, _, expected) = disk_image_ops();
+        let mut sink = SparseSink::new(Counting(Cursor::new(Vec::new()), 0)).unwrap();
+        apply_to(&mut sink);
+        assert_eq!(sink.skipped(), 46_000 - 1);
+        let inner = sink.into_inner();
+        assert_eq!(inner.1, expected.len() - 46_000 + 1);
+        assert_eq!(inner.0.into_inner(), expected);
+
+        //Everything is written when the holes would be too small.
+        let mut sink = SparseSink::new(Counting(Cursor::new(Vec::new()), 0)).unwrap().set_min_hole_size(100_000);
+        apply_to(&mut sink);
+        assert_eq!(sink.skipped(), 0);
+        assert_eq!(sink.into_inner().0.into_inner(), expected);
+
+        //Zeros over existing data are written.
+        let mut sink = SparseSink::new(Cursor::new(Vec::new())).unwrap().set_min_hole_size(1);
+        sink.write_all(&[1; 10]).unwrap();
+        sink.seek(SeekFrom::Start(2)).unwrap();
+        sink.write_zeros(5).unwrap();
+        sink.finish().unwrap();
+        assert_eq!(sink.into_inner().into_inner(), [1, 1, 0, 0, 0, 0, 0, 1, 1, 1]);
+
+        assert!(SparseSink::new(Cursor::new(vec![1])).is_err());
+    }
+
+    #[test]
+    fn test_sparse_file() {
+        let (_, _, expected) = disk_image_ops();
+        let path = std::env::temp_dir().join(format!("smdiff-sparse-{}.img", std::process::id()));
+        let file = std::fs::File::options().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut sink = SparseSink::new(file).unwrap();
+        apply_to(&mut sink);
+        drop(sink);
+        let output = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output, expected);
+    }
+}
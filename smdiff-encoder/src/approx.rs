@@ -0,0 +1,79 @@
+//! Approximate matches: long aligned regions of the source and target with sparse byte differences.
+//!
+//! Executables change by relocated addresses scattered through otherwise identical code.
+//! The exact matcher needs 9 equal bytes to find a match, so the short equal stretches between the changes end up as Add bytes.
+//! After each Copy-D the source stays aligned with the target, so the Add bytes that follow are compared against the source
+//! at that alignment. Equal stretches become Copy-D ops when the copy is cheaper than the bytes it replaces, which it
+//! usually is since the address is only a small step from the previous copy.
+use smdiff_common::{copy_addr_size, op_header_size, Copy, CopySrc};
+
+use crate::{op_maker::make_add_ops, src_matcher::forward_match_len, Op};
+
+/// Stop following an alignment after this many different bytes in a row.
+const MAX_MISMATCH_RUN: usize = 16;
+
+/// Replaces parts of the Add ops that follow a Copy-D with copies from the same alignment.
+pub(crate) fn repair_adds<'a>(src: &[u8], trgt: &'a [u8], ops: Vec<Op<'a>>) -> Vec<Op<'a>> {
+    let mut out_ops = Vec::with_capacity(ops.len());
+    //the source position aligned with the current output position
+    let mut aligned: Option<usize> = None;
+    let mut last_d_addr = 0u64;
+    let mut o_pos = 0;
+    for op in ops {
+        let len = op.oal() as usize;
+        match (&op, aligned) {
+            (Op::Copy(Copy { src: CopySrc::Dict, addr, .. }), _) => {
+                last_d_addr = *addr;
+                aligned = Some(*addr as usize + len);
+                out_ops.push(op);
+            },
+            (Op::Add(_), Some(src_pos)) => {
+                aligned = split_add(src, src_pos, &trgt[o_pos..o_pos + len], &mut last_d_addr, &mut out_ops)
+                    .then_some(src_pos + len);
+            },
+            //Runs and Copy-Os are usually the changed bytes themselves, the alignment carries on past them.
+            _ => {
+                aligned = aligned.map(|src_pos| src_pos + len);
+                out_ops.push(op);
+            },
+        }
+        o_pos += len;
+    }
+    out_ops
+}
+
+/// Emits `bytes` (aligned with `src[src_pos..]`) as Adds and Copy-Ds, choosing by cost.
+/// Returns false if the alignment was given up on.
+fn split_add<'a>(src: &[u8], src_pos: usize, bytes: &'a [u8], last_d_addr: &mut u64, out_ops: &mut Vec<Op<'a>>) -> bool {
+    let mut add_start = 0;
+    let mut mismatch_run = 0;
+    let mut i = 0;
+    while i < bytes.len() && src_pos + i < src.len() {
+        let m = forward_match_len(src, src_pos + i, bytes, i);
+        if m == 0 {
+            mismatch_run += 1;
+            if mismatch_run > MAX_MISMATCH_RUN {
+                break;
+            }
+            i += 1;
+            continue;
+        }
+        mismatch_run = 0;
+        let addr = (src_pos + i) as u64;
+        //splitting the Add costs about one more OpByte
+        let copy_cost = op_header_size(m as u16) + copy_addr_size(*last_d_addr, addr) + 1;
+        if copy_cost < m {
+            if add_start < i {
+                make_add_ops(&bytes[add_start..i], out_ops);
+            }
+            out_ops.push(Op::Copy(Copy { src: CopySrc::Dict, addr, len: m as u16 }));
+            *last_d_addr = addr;
+            add_start = i + m;
+        }
+        i += m;
+    }
+    if add_start < bytes.len() {
+        make_add_ops(&bytes[add_start..], out_ops);
+    }
+    i == bytes.len()
+}
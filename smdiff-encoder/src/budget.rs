@@ -0,0 +1,203 @@
+//! Autotuned encoding against a patch size and time budget.
+//!
+//! `EncoderConfig::comp_level` picks the settings up front, without knowing what they give for a particular input.
+//! [`encode_with_budget`] instead tries configs from cheap to expensive: matching levels, then secondary compression and format for each.
+//! It stops at the first patch that is small enough, or when the deadline passes, and returns the smallest patch found with the config that made it.
+use std::time::{Duration, Instant};
+
+use smdiff_common::{filter::write_filter_header, progress::{is_cancelled, Observer}};
+
+use crate::{diff_inner, write_sections, EncoderConfig, SecondaryCompression, TrgtMatcherConfig};
+
+/// The matching levels tried, in order. Each is used for both the source and the target matcher.
+const MATCH_LEVELS: [usize; 4] = [0, 3, 6, 9];
+
+/// The limits for [`encode_with_budget`].
+/// * max_patch_size: Stop searching once a patch is at most this many bytes. None searches every config.
+/// * time_limit: Stop searching once this much time has passed. None has no limit.
+///
+/// Default values are: max_patch_size: None, time_limit: None
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PatchBudget {
+    pub max_patch_size: Option<usize>,
+    pub time_limit: Option<Duration>,
+}
+
+impl PatchBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set_max_patch_size(mut self, size: usize) -> Self {
+        self.max_patch_size = Some(size);
+        self
+    }
+    pub fn set_time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = Some(limit);
+        self
+    }
+}
+
+/// The result of [`encode_with_budget`].
+/// * patch: The smallest patch found.
+/// * config: The config that produced it. Encoding with it gives the same patch.
+/// * met_size: Whether the patch is within `max_patch_size` (always true without one).
+/// * timed_out: Whether the search was stopped by the time limit.
+/// * attempts: The number of patches written (including any cut short by the time limit).
+#[derive(Clone, Debug)]
+pub struct BudgetedPatch {
+    pub patch: Vec<u8>,
+    pub config: EncoderConfig,
+    pub met_size: bool,
+    pub timed_out: bool,
+    pub attempts: usize,
+}
+
+/// Cancels once the deadline has passed. Without one it never cancels.
+struct Deadline(Option<Instant>);
+
+impl Observer for Deadline {
+    fn cancelled(&mut self) -> bool {
+        self.0.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// The output options tried for each matching level, cheapest first.
+fn output_candidates() -> Vec<EncoderConfig> {
+    let base = EncoderConfig::default();
+    vec![
+        base.clone().no_sec_comp().format_interleaved(),
+        base.clone().set_sec_comp(SecondaryCompression::Zstd { level: 3 }).format_auto(),
+        base.clone().set_sec_comp(SecondaryCompression::Zstd { level: 19 }).format_auto(),
+        base.clone().set_sec_comp(SecondaryCompression::new_brotli_default()).format_auto(),
+        base.set_sec_comp(SecondaryCompression::Smdiff(TrgtMatcherConfig::comp_level(9))).format_auto(),
+    ]
+}
+
+/// Encodes the target, searching for a config that meets the budget.
+///
+/// Matching levels 0, 3, 6 and 9 are tried in order (each with the target matcher on), then level 9 with `optimal_parse` and `approximate_matches`.
+/// For each, the ops are written with no secondary compression, then Zstd (level 3 and 19), Brotli and Smdiff, the compressed ones with `format_auto`.
+/// The first attempt always runs to completion, so there is a patch even if the time limit is very short.
+/// Later attempts are cancelled when the time limit passes.
+/// # Arguments
+/// * `dict` - The source file to use as a dictionary. May be empty.
+/// * `target` - The target file to encode.
+/// * `budget` - When to stop searching.
+/// * `base` - Only `filter` and `output_segment_size` are used, everything else is searched over.
+/// # Errors
+/// Returns an error if there was an issue writing a patch.
+pub fn encode_with_budget(dict: &[u8], target: &[u8], budget: &PatchBudget, base: &EncoderConfig) -> std::io::Result<BudgetedPatch> {
+    let deadline = budget.time_limit.map(|limit| Instant::now() + limit);
+    let (dict, target) = match base.filter {
+        Some(filter) => {
+            let mut dict = dict.to_vec();
+            filter.apply(&mut dict);
+            let mut target = target.to_vec();
+            filter.apply(&mut target);
+            (std::borrow::Cow::Owned(dict), std::borrow::Cow::Owned(target))
+        },
+        None => (std::borrow::Cow::Borrowed(dict), std::borrow::Cow::Borrowed(target)),
+    };
+    let mut match_configs: Vec<EncoderConfig> = MATCH_LEVELS.iter().map(|&level| EncoderConfig::comp_level(level, true, None)).collect();
+    match_configs.push(EncoderConfig::comp_level(9, true, None).set_optimal_parse(true).set_approximate_matches(true));
+    let met_size = |len: usize| budget.max_patch_size.is_none_or(|max| len <= max);
+
+    let mut best: Option<BudgetedPatch> = None;
+    let mut attempts = 0;
+    let mut timed_out = false;
+    'search: for match_config in match_configs {
+        //The first attempt has to finish, so it is never cancelled.
+        let mut observer = Deadline(if attempts == 0 { None } else { deadline });
+        let ops = match diff_inner(&dict, &target, &match_config, None, &mut observer) {
+            Ok(ops) => ops,
+            Err(e) if is_cancelled(&e) => {
+                timed_out = true;
+                break 'search;
+            },
+            Err(e) => return Err(e),
+        };
+        for output_config in output_candidates() {
+            let config = EncoderConfig {
+                sec_comp: output_config.sec_comp,
+                format: output_config.format,
+                adaptive_format: output_config.adaptive_format,
+                output_segment_size: base.output_segment_size,
+                filter: base.filter,
+                ..match_config.clone()
+            };
+            let mut observer = Deadline(if attempts == 0 { None } else { deadline });
+            attempts += 1;
+            let mut patch = Vec::new();
+            if let Some(filter) = base.filter {
+                write_filter_header(filter, &mut patch)?;
+            }
+            match write_sections(&ops, &mut patch, &config, &mut observer) {
+                Ok(()) => (),
+                Err(e) if is_cancelled(&e) => {
+                    timed_out = true;
+                    break 'search;
+                },
+                Err(e) => return Err(e),
+            }
+            if best.as_ref().is_none_or(|b| patch.len() < b.patch.len()) {
+                best = Some(BudgetedPatch { met_size: met_size(patch.len()), patch, config, timed_out: false, attempts: 0 });
+            }
+            if best.as_ref().is_some_and(|b| b.met_size) {
+                break 'search;
+            }
+            if Deadline(deadline).cancelled() {
+                timed_out = true;
+                break 'search;
+            }
+        }
+    }
+    let mut best = best.expect("The first attempt is never cancelled");
+    best.timed_out = timed_out;
+    best.attempts = attempts;
+    Ok(best)
+}
+
+#[cfg(test)]
+mod test_super {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::random_stream;
+
+    fn apply(dict: &[u8], patch: &[u8]) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch(&mut Cursor::new(patch), Some(&mut Cursor::new(dict.to_vec())), &mut output).unwrap();
+        output.into_inner()
+    }
+
+    #[test]
+    fn test_encode_with_budget() {
+        let dict: Vec<u8> = random_stream(9).take(30_000).map(|n| b"abcdefgh"[(n >> 28) as usize]).collect();
+        let mut target = dict.clone();
+        target[5_000..9_000].reverse();
+        target.extend_from_slice(&dict[100..4_100]);
+        let base = EncoderConfig::default();
+
+        //Any patch will do: only the first attempt is made.
+        let easy = encode_with_budget(&dict, &target, &PatchBudget::new().set_max_patch_size(usize::MAX), &base).unwrap();
+        assert_eq!(easy.attempts, 1);
+        assert!(easy.met_size && !easy.timed_out);
+        assert!(easy.config.sec_comp.is_none());
+        assert_eq!(apply(&dict, &easy.patch), target);
+
+        //Impossible size: everything is tried and the smallest is kept.
+        let all = encode_with_budget(&dict, &target, &PatchBudget::new().set_max_patch_size(0), &base).unwrap();
+        assert_eq!(all.attempts, (MATCH_LEVELS.len() + 1) * output_candidates().len());
+        assert!(!all.met_size && !all.timed_out);
+        assert!(all.patch.len() < easy.patch.len());
+        assert_eq!(apply(&dict, &all.patch), target);
+        let mut again = Vec::new();
+        crate::encode(Some(&mut Cursor::new(&dict)), &mut Cursor::new(&target), &mut again, &all.config).unwrap();
+        assert_eq!(again, all.patch);
+
+        //No time: the first attempt still finishes.
+        let rushed = encode_with_budget(&dict, &target, &PatchBudget::new().set_max_patch_size(0).set_time_limit(Duration::ZERO), &base).unwrap();
+        assert!(rushed.timed_out);
+        assert_eq!(rushed.patch, easy.patch);
+    }
+}
@@ -0,0 +1,411 @@
+//! Content defined chunking of Add data, for deduplicated patch storage.
+//!
+//! [`encode_chunked`] diffs like [`crate::encode`], but instead of writing the new (Add) bytes in to the patch it cuts them
+//! in to chunks with a content defined chunker, puts the chunks in a [`ChunkStore`] keyed by their blake3 hash, and returns a
+//! [`ChunkedPatch`] that only references them. The same new data in many patches gets the same cuts, so it is stored once.
+//!
+//! A [`ChunkedPatch`] is not a SMDIFF patch. [`ChunkedPatch::rehydrate`] fetches the chunks again and writes a normal patch
+//! that any decoder can apply.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use smdiff_common::{read_u_varint, write_u_varint, Copy, CopySrc, Run, MAX_INST_SIZE};
+
+use crate::{diff, hasher::gear_cut_point, write_patch, Add, EncoderConfig, Op};
+
+/// Magic bytes at the start of a serialized chunked patch.
+const CHUNKED_PATCH_MAGIC: [u8; 4] = *b"SMCP";
+/// Smallest average chunk size a [`ChunkerConfig`] may use.
+pub const MIN_AVG_CHUNK_SIZE: usize = 64;
+
+const TAG_CHUNK: u8 = 0;
+const TAG_RUN: u8 = 1;
+const TAG_COPY_DICT: u8 = 2;
+const TAG_COPY_OUTPUT: u8 = 3;
+
+/// The blake3 hash of a chunk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkId(pub [u8; 32]);
+
+impl ChunkId {
+    /// Hashes the chunk bytes.
+    pub fn of(bytes: &[u8]) -> Self {
+        ChunkId(*blake3::hash(bytes).as_bytes())
+    }
+    /// The id as lower case hex.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Where the chunks of a [`ChunkedPatch`] are kept.
+pub trait ChunkStore {
+    /// Returns true if the chunk is already stored.
+    fn contains(&self, id: &ChunkId) -> std::io::Result<bool>;
+    /// Stores a chunk. Only called for chunks that are not already stored.
+    fn put(&mut self, id: &ChunkId, bytes: &[u8]) -> std::io::Result<()>;
+    /// Returns the chunk bytes, or None if the chunk is not stored.
+    fn get(&self, id: &ChunkId) -> std::io::Result<Option<Vec<u8>>>;
+}
+
+/// A [`ChunkStore`] that keeps the chunks in memory.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryChunkStore {
+    chunks: HashMap<ChunkId, Vec<u8>>,
+}
+
+impl MemoryChunkStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The number of distinct chunks stored.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+    /// The total size of all distinct chunks.
+    pub fn stored_bytes(&self) -> u64 {
+        self.chunks.values().map(|c| c.len() as u64).sum()
+    }
+}
+
+impl ChunkStore for MemoryChunkStore {
+    fn contains(&self, id: &ChunkId) -> std::io::Result<bool> {
+        Ok(self.chunks.contains_key(id))
+    }
+    fn put(&mut self, id: &ChunkId, bytes: &[u8]) -> std::io::Result<()> {
+        self.chunks.insert(*id, bytes.to_vec());
+        Ok(())
+    }
+    fn get(&self, id: &ChunkId) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.chunks.get(id).cloned())
+    }
+}
+
+/// A [`ChunkStore`] that keeps every chunk in its own file, named by the hex id, in one directory.
+#[derive(Clone, Debug)]
+pub struct DirChunkStore {
+    dir: PathBuf,
+}
+
+impl DirChunkStore {
+    /// Uses the given directory, creating it if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+    fn path(&self, id: &ChunkId) -> PathBuf {
+        self.dir.join(id.to_hex())
+    }
+}
+
+impl ChunkStore for DirChunkStore {
+    fn contains(&self, id: &ChunkId) -> std::io::Result<bool> {
+        self.path(id).try_exists()
+    }
+    fn put(&mut self, id: &ChunkId, bytes: &[u8]) -> std::io::Result<()> {
+        //Write then rename, so a chunk file is never seen half written.
+        let tmp = self.dir.join(format!("{}.tmp", id.to_hex()));
+        File::create(&tmp)?.write_all(bytes)?;
+        std::fs::rename(tmp, self.path(id))
+    }
+    fn get(&self, id: &ChunkId) -> std::io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(id)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Configuration for the content defined chunker.
+/// * min_size: No chunk (except the last of an Add region) is smaller than this.
+/// * avg_size: The chunk size aimed for. Rounded up to a power of two, and at least MIN_AVG_CHUNK_SIZE.
+/// * max_size: No chunk is larger than this.
+///
+/// Default values are: min_size: 2048, avg_size: 8192, max_size: 65536
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkerConfig {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self::new(8192)
+    }
+}
+
+impl ChunkerConfig {
+    /// Uses a quarter of `avg_size` as the minimum and eight times it as the maximum.
+    pub fn new(avg_size: usize) -> Self {
+        let avg_size = avg_size.max(MIN_AVG_CHUNK_SIZE).next_power_of_two();
+        Self { min_size: avg_size / 4, avg_size, max_size: avg_size * 8 }
+    }
+    pub fn set_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+    pub fn set_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+    /// Splits `data` in to chunks.
+    pub fn chunks<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        let avg_size = self.avg_size.max(MIN_AVG_CHUNK_SIZE).next_power_of_two();
+        let max_size = self.max_size.max(1);
+        let min_size = self.min_size.min(max_size);
+        let mut rest = data;
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let (chunk, tail) = rest.split_at(gear_cut_point(rest, min_size, avg_size, max_size));
+            rest = tail;
+            Some(chunk)
+        })
+    }
+}
+
+/// An operation of a [`ChunkedPatch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkedOp {
+    /// Add the bytes of a stored chunk.
+    Chunk { id: ChunkId, len: u64 },
+    Run(Run),
+    Copy(Copy),
+}
+
+impl ChunkedOp {
+    /// The number of output bytes this op produces.
+    pub fn oal(&self) -> u64 {
+        match self {
+            ChunkedOp::Chunk { len, .. } => *len,
+            ChunkedOp::Run(run) => run.len as u64,
+            ChunkedOp::Copy(copy) => copy.len as u64,
+        }
+    }
+}
+
+/// A patch whose Add data lives in a [`ChunkStore`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkedPatch {
+    ops: Vec<ChunkedOp>,
+}
+
+impl ChunkedPatch {
+    pub fn ops(&self) -> &[ChunkedOp] {
+        &self.ops
+    }
+    /// The ids of all referenced chunks, in output order (with repeats).
+    pub fn chunk_ids(&self) -> impl Iterator<Item = &ChunkId> {
+        self.ops.iter().filter_map(|op| match op {
+            ChunkedOp::Chunk { id, .. } => Some(id),
+            _ => None,
+        })
+    }
+    /// The size of the output this patch produces.
+    pub fn output_len(&self) -> u64 {
+        self.ops.iter().map(|op| op.oal()).sum()
+    }
+    /// Writes a normal SMDIFF patch, fetching the chunks from the store.
+    /// # Arguments
+    /// * `store` - The store the chunks were put in.
+    /// * `writer` - The writer to write the patch to.
+    /// * `config` - Only the output options are used (see [`write_patch`]).
+    /// # Errors
+    /// Returns an error if a chunk is missing or does not match its id, or there was an issue writing the patch.
+    pub fn rehydrate<S: ChunkStore + ?Sized, W: Write>(&self, store: &S, writer: &mut W, config: &EncoderConfig) -> std::io::Result<()> {
+        let mut chunks: HashMap<ChunkId, Vec<u8>> = HashMap::new();
+        for (id, len) in self.ops.iter().filter_map(|op| match op {
+            ChunkedOp::Chunk { id, len } => Some((id, *len)),
+            _ => None,
+        }) {
+            if chunks.contains_key(id) {
+                continue;
+            }
+            let bytes = store.get(id)?.ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Chunk {} is not in the store", id.to_hex()),
+            ))?;
+            if bytes.len() as u64 != len || ChunkId::of(&bytes) != *id {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Chunk {} is corrupt", id.to_hex())));
+            }
+            chunks.insert(*id, bytes);
+        }
+        let mut ops: Vec<Op> = Vec::with_capacity(self.ops.len());
+        for op in self.ops.iter() {
+            match op {
+                ChunkedOp::Chunk { id, .. } => ops.extend(chunks[id].chunks(MAX_INST_SIZE).map(|b| Op::Add(Add::new(b)))),
+                ChunkedOp::Run(run) => ops.push(Op::Run(run.clone())),
+                ChunkedOp::Copy(copy) => ops.push(Op::Copy(*copy)),
+            }
+        }
+        write_patch(&ops, writer, config)
+    }
+    /// Writes the chunked patch to the writer.
+    ///
+    /// Layout: Magic(4 bytes) | Num Ops (u-varint) | Ops...
+    /// Each op is a tag byte followed by: Chunk: id (32 bytes) + len (u-varint), Run: byte + len (u-varint),
+    /// Copy: addr (u-varint) + len (u-varint). Copy addresses are absolute.
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&CHUNKED_PATCH_MAGIC)?;
+        write_u_varint(writer, self.ops.len() as u64)?;
+        for op in self.ops.iter() {
+            match op {
+                ChunkedOp::Chunk { id, len } => {
+                    writer.write_all(&[TAG_CHUNK])?;
+                    writer.write_all(&id.0)?;
+                    write_u_varint(writer, *len)?;
+                },
+                ChunkedOp::Run(Run { byte, len }) => {
+                    writer.write_all(&[TAG_RUN, *byte])?;
+                    write_u_varint(writer, *len as u64)?;
+                },
+                ChunkedOp::Copy(Copy { src, addr, len }) => {
+                    writer.write_all(&[if *src == CopySrc::Dict { TAG_COPY_DICT } else { TAG_COPY_OUTPUT }])?;
+                    write_u_varint(writer, *addr)?;
+                    write_u_varint(writer, *len as u64)?;
+                },
+            }
+        }
+        Ok(())
+    }
+    /// Reads a chunked patch that was written with [`ChunkedPatch::write`].
+    pub fn read<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != CHUNKED_PATCH_MAGIC {
+            return Err(invalid("Not a smdiff chunked patch".to_string()));
+        }
+        let num_ops = read_u_varint(reader)?;
+        let mut ops = Vec::new();
+        for _ in 0..num_ops {
+            let mut tag = [0u8; 1];
+            reader.read_exact(&mut tag)?;
+            let op = match tag[0] {
+                TAG_CHUNK => {
+                    let mut id = [0u8; 32];
+                    reader.read_exact(&mut id)?;
+                    ChunkedOp::Chunk { id: ChunkId(id), len: read_u_varint(reader)? }
+                },
+                TAG_RUN => {
+                    let mut byte = [0u8; 1];
+                    reader.read_exact(&mut byte)?;
+                    let len = read_u_varint(reader)?;
+                    let len = u8::try_from(len).map_err(|_| invalid(format!("Run length {} is too large", len)))?;
+                    ChunkedOp::Run(Run { byte: byte[0], len })
+                },
+                TAG_COPY_DICT | TAG_COPY_OUTPUT => {
+                    let src = if tag[0] == TAG_COPY_DICT { CopySrc::Dict } else { CopySrc::Output };
+                    let addr = read_u_varint(reader)?;
+                    let len = read_u_varint(reader)?;
+                    let len = u16::try_from(len).map_err(|_| invalid(format!("Copy length {} is too large", len)))?;
+                    ChunkedOp::Copy(Copy { src, addr, len })
+                },
+                t => return Err(invalid(format!("Unknown chunked op tag {}", t))),
+            };
+            ops.push(op);
+        }
+        Ok(Self { ops })
+    }
+}
+
+/// Diffs the target against the dictionary and puts the new data in the store as content defined chunks.
+///
+/// Consecutive Add ops are joined in to one region before chunking, so the cuts do not depend on how the encoder split them.
+/// Chunks already in the store are not put again.
+/// # Arguments
+/// * `dict` - The source file to use as a dictionary. May be empty.
+/// * `target` - The target file to encode.
+/// * `store` - Where to put the chunks.
+/// * `config` - The matching options of the encoder config are used. A `filter` is not supported.
+/// * `chunker` - How to cut the Add regions.
+/// # Errors
+/// Returns an error if a filter is set, or the store fails.
+pub fn encode_chunked<S: ChunkStore + ?Sized>(dict: &[u8], target: &[u8], store: &mut S, config: &EncoderConfig, chunker: &ChunkerConfig) -> std::io::Result<ChunkedPatch> {
+    if config.filter.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "A filter can not be used with chunked encoding"));
+    }
+    let mut ops = Vec::new();
+    let mut add_start: Option<usize> = None;
+    let mut o_pos = 0;
+    let mut flush_adds = |ops: &mut Vec<ChunkedOp>, start: Option<usize>, end: usize| -> std::io::Result<()> {
+        let Some(start) = start else { return Ok(()) };
+        for chunk in chunker.chunks(&target[start..end]) {
+            let id = ChunkId::of(chunk);
+            if !store.contains(&id)? {
+                store.put(&id, chunk)?;
+            }
+            ops.push(ChunkedOp::Chunk { id, len: chunk.len() as u64 });
+        }
+        Ok(())
+    };
+    for op in diff(dict, target, config) {
+        let oal = op.oal() as usize;
+        match op {
+            Op::Add(_) => {
+                add_start.get_or_insert(o_pos);
+            },
+            Op::Run(run) => {
+                flush_adds(&mut ops, add_start.take(), o_pos)?;
+                ops.push(ChunkedOp::Run(run));
+            },
+            Op::Copy(copy) => {
+                flush_adds(&mut ops, add_start.take(), o_pos)?;
+                ops.push(ChunkedOp::Copy(copy));
+            },
+        }
+        o_pos += oal;
+    }
+    flush_adds(&mut ops, add_start.take(), o_pos)?;
+    Ok(ChunkedPatch { ops })
+}
+
+#[cfg(test)]
+mod test_super {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::random_bytes;
+
+    #[test]
+    fn test_encode_chunked() {
+        let src = random_bytes(1, 40_000);
+        let new_data = random_bytes(2, 100_000);
+        //Two targets with the same new data, in different places.
+        let targets: Vec<Vec<u8>> = (0..2).map(|i| {
+            let mut trgt = src.clone();
+            trgt.splice(10_000 + i * 20_000..10_000 + i * 20_000, new_data.iter().copied());
+            trgt.extend_from_slice(&[7; 500]);
+            trgt
+        }).collect();
+        let config = EncoderConfig::default();
+        let chunker = ChunkerConfig::new(4096);
+        let mut store = MemoryChunkStore::new();
+        let patches: Vec<ChunkedPatch> = targets.iter().map(|t| encode_chunked(&src, t, &mut store, &config, &chunker).unwrap()).collect();
+        //The second patch adds (almost) nothing to the store.
+        assert!(store.stored_bytes() < new_data.len() as u64 + 4096, "stored {} bytes", store.stored_bytes());
+        for (trgt, patch) in targets.iter().zip(patches) {
+            assert_eq!(patch.output_len(), trgt.len() as u64);
+            let mut serialized = Vec::new();
+            patch.write(&mut serialized).unwrap();
+            let patch = ChunkedPatch::read(&mut Cursor::new(serialized)).unwrap();
+            let mut smdiff = Vec::new();
+            patch.rehydrate(&store, &mut smdiff, &config).unwrap();
+            let mut output = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(smdiff), Some(&mut Cursor::new(src.clone())), &mut output).unwrap();
+            assert_eq!(output.get_ref(), trgt);
+        }
+        let err = ChunkedPatch { ops: vec![ChunkedOp::Chunk { id: ChunkId([0; 32]), len: 1 }] }
+            .rehydrate(&store, &mut Vec::new(), &config).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+}
@@ -15,7 +15,12 @@ There are several ways to configure this encoder, so it should allow for tuning
 
 
 */
-use crate::{hasher::*, src_matcher::{add_start_positions_to_matcher, SrcMatcherConfig}, trgt_matcher::TrgtMatcherConfig};
+use smdiff_common::progress::{check_cancelled, Observer, Phase};
+
+use crate::{hasher::*, index::DictionaryIndex, src_matcher::{add_start_positions_to_matcher, SrcMatcher, SrcMatcherConfig}, trgt_matcher::{TrgtMatcher, TrgtMatcherConfig}};
+
+/// Target bytes matched between progress reports (and cancellation checks).
+const PROGRESS_INTERVAL: usize = 1 << 20;
 
 #[allow(unused)]
 #[derive(Copy,Clone,Debug)]
@@ -111,14 +116,16 @@ impl InnerOp {
 /// Returns all possible operations to encode src into trgt.
 /// This means ops may overlap or not.
 /// It is up to the caller to decide how to handle the gaps and overlaps
-pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8])->Vec<InnerOp>{
+/// The observer is told about progress every PROGRESS_INTERVAL target bytes, and can cancel the encode.
+pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8],observer:&mut dyn Observer)->std::io::Result<Vec<InnerOp>>{
     let naive_tests = config.naive_tests;
+    let collect_candidates = config.collect_candidates;
 
     let trgt_len = trgt.len();
     if (config.match_src.is_none() && config.match_trgt.is_none())
         || (src.len() == 0 && config.match_trgt.is_none())
         || trgt_len == 0 {
-        return vec![];
+        return Ok(vec![]);
     }
     //first try the naive tests.
     //these are here to avoid the overhead of the matcher.
@@ -158,24 +165,42 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
 
     //now we decide our matcher configs, at least one of these will be Some.
     let _start = std::time::Instant::now();
+    observer.phase(Phase::HashingSource);
+    check_cancelled(observer)?;
     let mut trgt_matcher = config.match_trgt.as_mut().map(|c| c.build(trgt, cur_o_pos));
-    let mut src_matcher = config.match_src.as_mut().map(|c| c.build(src, cur_o_pos, trgt));
+    let mut src_matcher = match config.src_index.as_ref() {
+        Some(index) => Some(SrcMatcher::from_index(index, cur_o_pos, trgt)),
+        None => config.match_src.as_mut().map(|c| c.build(src, cur_o_pos, trgt)),
+    };
     // let _elapsed = _start.elapsed();
     // dbg!(_elapsed);
     let lazy_escape_len = config.lazy_escape_len.unwrap_or(90);
 
+    //the smallest match the trgt hash can find. With collect_candidates the parse prices each match,
+    //so this only bounds what the matchers report, not what is worth a copy.
     let min_match_value = 4;
     let mut min_match= min_match_value;
     let mut run_len = 0;
     let mut run_byte = 0;
     let mut state = EncoderState::StartNewMatch;
+    //(src start, o_pos) of the last src match, and the start of the last trgt match.
+    //Copy addresses are encoded relative to the previous copy, so the matchers use these to weigh address costs.
+    let mut last_d = (0, 0);
+    let mut last_o_addr = 0;
     //now we start the main loop.
     let _start = std::time::Instant::now();
+    observer.phase(Phase::Matching);
+    let mut next_report = cur_o_pos;
     loop {
         //first we see if we are out of data.
         if cur_o_pos + min_match_value >= max_trgt_match_len {
             break;
         }
+        if cur_o_pos >= next_report {
+            observer.bytes_processed(cur_o_pos as u64);
+            check_cancelled(observer)?;
+            next_report = cur_o_pos + PROGRESS_INTERVAL;
+        }
         match state{
             EncoderState::StartNewMatch => {
                 //we setup for trying to start a new match
@@ -185,7 +210,7 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
                     if matcher.next_hash_pos <= cur_o_pos{
                         add_start_positions_to_matcher(matcher, cur_o_pos, src)
                     }
-                    if matcher.fwd_pos < matcher.max_fwd_hash_pos{
+                    if matcher.fwd_pos < matcher.max_fwd_hash_pos && cur_o_pos <= matcher.max_fwd_hash_pos{
                         if matcher.fwd_pos + 9 > cur_o_pos{
                             for old_pos in matcher.fwd_pos..cur_o_pos{
                                 matcher.fwd_hash = update_large_checksum_fwd(matcher.fwd_hash, trgt[old_pos], trgt[old_pos+9]);
@@ -197,7 +222,7 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
                     }
                 };
                 if let Some(matcher) = trgt_matcher.as_mut(){
-                    if matcher.fwd_pos < matcher.max_fwd_hash_pos{
+                    if matcher.fwd_pos < matcher.max_fwd_hash_pos && cur_o_pos <= matcher.max_fwd_hash_pos{
                         if matcher.fwd_pos + 4 > cur_o_pos{
                             for old_pos in matcher.fwd_pos..cur_o_pos{
                                 matcher.fwd_hash = update_small_checksum_fwd(matcher.fwd_hash, trgt[old_pos], trgt[old_pos+4]);
@@ -237,11 +262,11 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
                 if let Some(matcher) = src_matcher.as_mut(){
                     if cur_o_pos + 9 <= max_trgt_match_len{
                         debug_assert!(matcher.fwd_pos == cur_o_pos, "lh.pos {} != cur o {}",matcher.fwd_pos,cur_o_pos);
-                        if let Some((src_start,pre_match,post_match)) = matcher.find_best_src_match(src, trgt) {
+                        if let Some((src_start,pre_match,post_match)) = matcher.find_best_src_match(src, trgt, last_d, min_match) {
                             let length = pre_match + post_match;
                             if post_match >= min_match{
                                 let trgt_match_start = cur_o_pos - pre_match;
-                                if pre_match > 0{
+                                if pre_match > 0 && !collect_candidates{
                                     //remove all ops that are fully before this start position
                                     clear_existing_ops(&mut ops, trgt_match_start)
                                 }
@@ -250,8 +275,11 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
                                 debug_assert!(src_match_start + length <= src_len);
                                 debug_assert!(src[src_match_start..src_match_start+length] == trgt[trgt_match_start..trgt_match_start+length]);
                                 ops.push(InnerOp::MatchSrc{start:src_match_start, length, o_pos:trgt_match_start});
+                                last_d = (src_match_start, trgt_match_start);
                                 state = EncoderState::FoundMatch { match_len: post_match };
-                                continue;
+                                if !collect_candidates{
+                                    continue;
+                                }
                             }
                         }
                     }
@@ -260,17 +288,27 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
                 if let Some(matcher) = trgt_matcher.as_mut(){
                     if cur_o_pos + 4 <= max_trgt_match_len{
                         debug_assert!(matcher.fwd_pos == cur_o_pos, "sh.pos {} != cur o {}",matcher.fwd_pos,cur_o_pos);
-                        if let Some((match_start,length)) = matcher.find_best_trgt_match(trgt,min_match) {
+                        if let Some((match_start,length)) = matcher.find_best_trgt_match(trgt,min_match,last_o_addr) {
                             if length >= min_match{
                                 debug_assert!(match_start + length <= cur_o_pos);
                                 debug_assert!(trgt[match_start..match_start+length] == trgt[cur_o_pos..cur_o_pos+length]);
                                 ops.push(InnerOp::MatchTrgt{start:match_start, length, o_pos:cur_o_pos});
-                                state = EncoderState::FoundMatch { match_len: length };
+                                last_o_addr = match_start;
+                                //when collecting, the src match found here may be the longer one.
+                                let match_len = match state {
+                                    EncoderState::FoundMatch { match_len } => match_len.max(length),
+                                    _ => length,
+                                };
+                                state = EncoderState::FoundMatch { match_len };
                                 continue;
                             }
                         }
                     }
                 }
+                if matches!(state, EncoderState::FoundMatch { .. }){
+                    //only a src match was collected
+                    continue;
+                }
 
                 //no matches >= min_match, so we move forward one byte.
                 if min_match > min_match_value{
@@ -288,6 +326,12 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
                     min_match = match_len;
                     state = EncoderState::MoveForwardOneByte;
                 }else{
+                    if collect_candidates{
+                        if let Some(matcher) = trgt_matcher.as_mut(){
+                            //the bytes being skipped can be copied by later matches
+                            store_trgt_positions(matcher, trgt, cur_o_pos + match_len);
+                        }
+                    }
                     cur_o_pos += match_len;
                     state = EncoderState::StartNewMatch;
                 }
@@ -302,14 +346,14 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
                     run_byte = next_char;
                 }
                 if let Some(matcher) = trgt_matcher.as_mut() {
-                    matcher.store(matcher.fwd_hash as usize, cur_o_pos);
-                    if matcher.fwd_pos < matcher.max_fwd_hash_pos{
+                    matcher.store(matcher.fwd_hash as u64, cur_o_pos);
+                    if matcher.fwd_pos < matcher.max_fwd_hash_pos && cur_o_pos < matcher.max_fwd_hash_pos{
                         matcher.fwd_hash = update_small_checksum_fwd(matcher.fwd_hash, trgt[cur_o_pos], trgt[cur_o_pos+4]);
                         matcher.fwd_pos = cur_o_pos+1;
                     }
                 }
                 if let Some(m) = src_matcher.as_mut(){
-                    if m.fwd_pos < m.max_fwd_hash_pos{
+                    if m.fwd_pos < m.max_fwd_hash_pos && cur_o_pos < m.max_fwd_hash_pos{
                         m.fwd_hash = update_large_checksum_fwd(m.fwd_hash, trgt[cur_o_pos], trgt[cur_o_pos+9]);
                         m.fwd_pos = cur_o_pos+1;
                     }
@@ -326,8 +370,13 @@ pub(crate) fn encode_inner(config:&mut GenericEncoderConfig,src:&[u8],trgt:&[u8]
         //if we had prepended naive test, we need to place all of the src at the end.
         ops.push(InnerOp::MatchSrc { start: 0, length: src_len, o_pos: max_trgt_match_len });
     }
+    if collect_candidates{
+        //src matches that reach back are pushed after the ops they overlap.
+        ops.sort_by_key(|op| *op.o_pos());
+    }
 
-    ops
+    observer.bytes_processed(trgt_len as u64);
+    Ok(ops)
 
 }
 enum EncoderState{
@@ -354,6 +403,16 @@ fn find_initial_run_len(seg: &[u8], match_len: usize, run_byte: &mut u8) -> usiz
     run_len
 }
 
+/// Stores the hashes of the trgt positions from `matcher.fwd_pos` up to `end`, without looking for matches at them.
+fn store_trgt_positions(matcher:&mut TrgtMatcher,trgt:&[u8],end:usize){
+    while matcher.fwd_pos < end && matcher.fwd_pos < matcher.max_fwd_hash_pos{
+        let pos = matcher.fwd_pos;
+        matcher.store(matcher.fwd_hash as u64, pos);
+        matcher.fwd_hash = update_small_checksum_fwd(matcher.fwd_hash, trgt[pos], trgt[pos+4]);
+        matcher.fwd_pos = pos+1;
+    }
+}
+
 #[inline(always)]
 fn clear_existing_ops(ops:&mut Vec<InnerOp>,gte_start:usize){
     while ops.last().map(|x|*x.o_pos()).unwrap_or(0) >= gte_start{
@@ -371,18 +430,41 @@ pub struct GenericEncoderConfig{
     /// If the current match is less than lazy_escape_len it steps byte by byte looking for more matches.
     pub lazy_escape_len: Option<usize>,
     pub naive_tests: Option<LargerTrgtNaiveTests>,
-
+    /// Used instead of building a SrcMatcher from match_src.
+    pub src_index: Option<DictionaryIndex>,
+    /// Try both matchers at every position and keep both matches, instead of stopping at the first one found.
+    /// The ops are then sorted by o_pos and left for a parse that prices the overlaps.
+    pub collect_candidates: bool,
 }
 
 #[allow(unused)]
 impl GenericEncoderConfig {
     pub fn new(match_trgt: Option<TrgtMatcherConfig>, match_src: Option<SrcMatcherConfig>, naive_tests:Option<LargerTrgtNaiveTests>,lazy_escape_len:Option<usize>) -> Self {
-        Self { match_trgt, match_src,naive_tests,lazy_escape_len }
+        Self { match_trgt, match_src,naive_tests,lazy_escape_len, src_index: None, collect_candidates: false }
     }
 }
 
 impl Default for GenericEncoderConfig {
     fn default() -> Self {
-        Self { match_trgt: None, match_src: Some(SrcMatcherConfig::comp_level(3)),naive_tests: Some(LargerTrgtNaiveTests::Append),lazy_escape_len: Some(45)}
+        Self { match_trgt: None, match_src: Some(SrcMatcherConfig::comp_level(3)),naive_tests: Some(LargerTrgtNaiveTests::Append),lazy_escape_len: Some(45), src_index: None, collect_candidates: false}
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test_super {
+    use std::io::Cursor;
+
+    use crate::{encode, EncoderConfig};
+
+    #[test]
+    fn test_match_ends_near_target_end() {
+        //the copy of the whole dictionary leaves fewer bytes than a hash window, the forward hashes must not roll past the end
+        let src: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(i).wrapping_mul(2654435761) >> 24) as u8).collect();
+        let mut trgt = src.clone();
+        trgt.extend_from_slice(b"new tail");
+        let mut patch = Vec::new();
+        encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &EncoderConfig::default()).unwrap();
+        let mut sink = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), trgt);
+    }
+}
@@ -3,24 +3,14 @@
 const HASH_MULTIPLIER_32_BIT: u32 = 1597334677;
 const HASH_MULTIPLIER_64_BIT: u64 = 1181783497276652981;
 
-#[cfg(target_pointer_width = "64")]
-const MOD_INV: usize = 13515856136758413469;
-#[cfg(target_pointer_width = "32")]
-const MOD_INV: usize = 851723965;
+// The large checksum is always 64 bits (never usize), so the same input hashes the same on every platform.
+const MOD_INV: u64 = 13515856136758413469;
 
-#[cfg(target_pointer_width = "64")]
-const HASH_MULT: usize = HASH_MULTIPLIER_64_BIT as usize;
-#[cfg(target_pointer_width = "32")]
-const HASH_MULT: usize = HASH_MULTIPLIER_32_BIT as usize;
+const HASH_MULT: u64 = HASH_MULTIPLIER_64_BIT;
 
-#[cfg(target_pointer_width = "64")]
-const FIRST_BYTE_WEIGHT: usize = 2694756932615366293;
+const FIRST_BYTE_WEIGHT: u64 = 2694756932615366293;
 
-#[cfg(target_pointer_width = "32")]
-const FIRST_BYTE_WEIGHT: usize = 1614047349;
-
-#[cfg(target_pointer_width = "64")]
-const POWERS: [usize; 9] =[
+const POWERS: [u64; 9] =[
     12309708155212419425,
     10962798122732597373,
     17101839937587700905,
@@ -32,39 +22,26 @@ const POWERS: [usize; 9] =[
     1,
 ];
 
-#[cfg(target_pointer_width = "32")]
-const POWERS: [usize; 9] = [
-    785718369,
-    2237430173,
-    2226829033,
-    2554380293,
-    438510001,
-    1152009645,
-    1664532153,
-    1597334677,
-    1,
-];
-
 #[inline(always)]
-pub(crate) fn calculate_large_checksum(data: &[u8]) -> usize {
+pub(crate) fn calculate_large_checksum(data: &[u8]) -> u64 {
     data.iter()
         .zip(POWERS.iter())
         .fold(0, |acc, (byte, power)|{
-            acc.wrapping_add(power.wrapping_mul(*byte as usize))
+            acc.wrapping_add(power.wrapping_mul(*byte as u64))
         })
 }
 
 #[inline(always)]
-pub(crate) fn update_large_checksum_fwd(checksum: usize, old:u8, new:u8) -> usize {
+pub(crate) fn update_large_checksum_fwd(checksum: u64, old:u8, new:u8) -> u64 {
     (HASH_MULT).wrapping_mul(checksum)//multiply to 'shift values' left
-    .wrapping_sub(FIRST_BYTE_WEIGHT.wrapping_mul(old as usize))
-    .wrapping_add(new as usize)
+    .wrapping_sub(FIRST_BYTE_WEIGHT.wrapping_mul(old as u64))
+    .wrapping_add(new as u64)
 }
 
 #[inline(always)]
-pub(crate) fn update_large_checksum_bwd(checksum: usize, old:u8, new:u8) -> usize {
-    checksum.wrapping_sub(old as usize)
-    .wrapping_add(FIRST_BYTE_WEIGHT.wrapping_mul(new as usize))
+pub(crate) fn update_large_checksum_bwd(checksum: u64, old:u8, new:u8) -> u64 {
+    checksum.wrapping_sub(old as u64)
+    .wrapping_add(FIRST_BYTE_WEIGHT.wrapping_mul(new as u64))
     .wrapping_mul(MOD_INV)
 }
 const SMALL_POWERS: [u32; 4] = [
@@ -91,6 +68,33 @@ pub(crate) fn update_small_checksum_fwd(checksum: u32, old:u8, new:u8) -> u32 {
     .wrapping_add(new as u32)
 }
 
+/// Offset added to every byte of the block checksum (same as rsync/librsync).
+/// Keeps runs of zeros from all hashing to the same value.
+const BLOCK_CHAR_OFFSET: u32 = 31;
+
+/// The 'weak' rolling checksum used for signature blocks (rsync style).
+/// Unlike the large/small checksums the window length is not fixed, so it is given by the caller.
+#[inline(always)]
+pub(crate) fn calculate_block_checksum(data: &[u8]) -> u32 {
+    let len = data.len() as u32;
+    let (a, b) = data.iter().enumerate().fold((0u32, 0u32), |(a, b), (i, byte)| {
+        let v = *byte as u32 + BLOCK_CHAR_OFFSET;
+        (a.wrapping_add(v), b.wrapping_add((len - i as u32).wrapping_mul(v)))
+    });
+    (a & 0xFFFF) | (b << 16)
+}
+
+#[inline(always)]
+pub(crate) fn update_block_checksum(checksum: u32, old:u8, new:u8, block_len:usize) -> u32 {
+    let old = old as u32 + BLOCK_CHAR_OFFSET;
+    let new = new as u32 + BLOCK_CHAR_OFFSET;
+    let a = (checksum & 0xFFFF).wrapping_sub(old).wrapping_add(new) & 0xFFFF;
+    let b = (checksum >> 16)
+        .wrapping_sub((block_len as u32).wrapping_mul(old))
+        .wrapping_add(a) & 0xFFFF;
+    a | (b << 16)
+}
+
 // This is about a wash compared to the rolling method.
 // #[inline(always)]
 // pub(crate) fn calculate_small_checksum_direct(data: &[u8]) -> u32 {
@@ -98,6 +102,58 @@ pub(crate) fn update_small_checksum_fwd(checksum: u32, old:u8, new:u8) -> u32 {
 //     state.wrapping_mul(HASH_MULTIPLIER_32_BIT)
 // }
 
+/// Gear table for content defined chunking, filled with splitmix64 so it is the same everywhere.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x5344_4946_4643_4443u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Finds the length of the first content defined chunk of `data` (FastCDC with normalized chunking).
+///
+/// The cut only depends on the bytes near it, so the same data gets the same cuts wherever it sits in a file.
+/// * `min_size` - No cut is made before this many bytes.
+/// * `avg_size` - Must be a power of two. Cuts are harder to hit before it and easier after.
+/// * `max_size` - A cut is forced at this many bytes.
+pub(crate) fn gear_cut_point(data: &[u8], min_size: usize, avg_size: usize, max_size: usize) -> usize {
+    debug_assert!(avg_size.is_power_of_two() && avg_size >= 4, "avg_size {} must be a power of two of at least 4", avg_size);
+    if data.len() <= min_size {
+        return data.len();
+    }
+    let bits = avg_size.trailing_zeros();
+    //the masks use the top bits, those have seen the most bytes.
+    let mask_s = u64::MAX << (64 - (bits + 1));
+    let mask_l = u64::MAX << (64 - (bits - 1));
+    let end = data.len().min(max_size);
+    let normal = avg_size.min(end);
+    let mut hash = 0u64;
+    let mut i = min_size;
+    while i < normal {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_s == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    while i < end {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_l == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    end
+}
+
 
 
 
@@ -105,6 +161,7 @@ pub(crate) fn update_small_checksum_fwd(checksum: u32, old:u8, new:u8) -> u32 {
 #[cfg(test)]
 mod test_super {
     use super::*;
+    use crate::test_util::random_bytes;
 
     #[test]
     fn test_rolling_hash_fwd() {
@@ -135,4 +192,40 @@ mod test_super {
             assert_eq!(hash,expected_hash, "config.update Failed at starting index {}", i);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rolling_block_checksum() {
+        let initial_data = b"hello world, this is a test of the rolling hash";
+        let block_len = 16;
+        let mut hash = calculate_block_checksum(&initial_data[..block_len]);
+        for i in 1..(initial_data.len() - block_len) {
+            let expected_hash = calculate_block_checksum(&initial_data[i..i + block_len]);
+            hash = update_block_checksum(hash, initial_data[i - 1], initial_data[i + block_len - 1], block_len);
+            assert_eq!(hash,expected_hash, "update Failed at starting index {}", i);
+        }
+    }
+
+    #[test]
+    fn test_gear_cut_point() {
+        let data = random_bytes(5, 200_000);
+        let cuts = |data: &[u8]| {
+            let mut cuts = Vec::new();
+            let mut pos = 0;
+            while pos < data.len() {
+                let len = gear_cut_point(&data[pos..], 256, 1024, 8192);
+                assert!(len <= 8192 && (len >= 256 || pos + len == data.len()));
+                pos += len;
+                cuts.push(pos);
+            }
+            cuts
+        };
+        let a = cuts(&data);
+        assert!(a.len() > 100 && a.len() < 400, "{} chunks", a.len());
+        //Inserting bytes at the front only moves the cuts that follow.
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&data);
+        let b: Vec<usize> = cuts(&shifted).into_iter().map(|c| c - 8).collect();
+        let common = a.iter().filter(|c| b.contains(c)).count();
+        assert!(common + 3 >= a.len(), "{} of {} cuts survived", common, a.len());
+    }
+}
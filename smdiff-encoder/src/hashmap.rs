@@ -3,7 +3,7 @@ const BUCKET_VALUE_OFFSET: usize = 1;
 
 #[derive(Clone, Debug, Default)]
 pub(crate) struct BucketValue{
-    hash:usize,
+    hash:u64,
     value:usize,
 }
 
@@ -12,7 +12,7 @@ impl BucketValue {
         self.value - BUCKET_VALUE_OFFSET
     }
     #[inline]
-    pub(crate) fn get(&self,hash:usize) -> Option<usize> {
+    pub(crate) fn get(&self,hash:u64) -> Option<usize> {
         if self.value == 0 {
             return None;
         }
@@ -23,7 +23,7 @@ impl BucketValue {
         }
     }
     #[inline]
-    pub(crate) fn set(&mut self, hash:usize, value: usize) -> Result<Option<usize>,(usize,usize)> {
+    pub(crate) fn set(&mut self, hash:u64, value: usize) -> Result<Option<usize>,(u64,usize)> {
         if self.value == 0 {
             self.hash = hash;
             self.value = value + BUCKET_VALUE_OFFSET;
@@ -44,7 +44,7 @@ impl BucketValue {
 
 /// A basic hash table implementation.
 /// This is effectively the innards of a HashMap<K, usize> with a fixed size table.
-/// The caller is responsible for hashing K to a usize value.
+/// The caller is responsible for hashing K to a u64 value.
 /// We expose the bucket index to allow bucket calculation to happen exactly once.
 /// The caller is responsible for dealing with hash collisions.
 /// This way the caller can avoid recalculating the index.
@@ -57,8 +57,9 @@ pub(crate) struct BasicHashTable {
 impl BasicHashTable{
     /// Creates a new BasicHashTable with the given number of slots.
     /// min_capacity will be rounded up to the next power of 2.
-    /// If interpret_hash_as_32_bit is true, the hash will be interpreted as a 32 bit value, regardless of the std::mem::size_of::<usize>().
-    /// This allows for using small hashes on a 64 bit system.
+    /// If interpret_hash_as_32_bit is true, the hash will be interpreted as a 32 bit value instead of a 64 bit one.
+    /// This allows for using small hashes in the same table type.
+    /// Hashes are always u64 (never usize), so bucket positions are the same on every platform.
     pub(crate) fn new(min_capacity: usize, interpret_hash_as_32_bit:bool) -> Self {
         // For the capacity given, what power of 2 should we use?
         let num_bits = determine_hash_table_size_bits(min_capacity);
@@ -67,9 +68,9 @@ impl BasicHashTable{
         let mod_mask = table_size - 1;
         // The shift amount is the number of bits to shift the hash to get the bucket index
         // To deal with types easily and allow usage of a single table between 8 byte and 4 byte hashes
-        // we must shift the high bits right, but not off the end of the usize.
+        // we must shift the high bits right, but not off the end of the u64.
         // The shifting is part of the bit mixing to spread out the values to the buckets
-        let base_shift = if interpret_hash_as_32_bit {4} else {std::mem::size_of::<usize>()};
+        let base_shift = if interpret_hash_as_32_bit {4} else {8};
         let shift_amount = (base_shift * 8) - num_bits;
         let buckets = vec![BucketValue::default(); table_size];
         Self {
@@ -81,15 +82,24 @@ impl BasicHashTable{
 }
 impl BasicHashTable {
     #[inline]
-    pub(crate) fn get(&self,hash:usize)->Option<usize>{
+    pub(crate) fn get(&self,hash:u64)->Option<usize>{
         let idx = get_bucket_idx(hash, self.shift_amount, self.mod_mask);
         self.buckets[idx].get(hash)
     }
     #[inline]
-    pub(crate) fn insert(&mut self, hash: usize, position: usize) -> Result<Option<usize>,(usize,usize)> {
+    pub(crate) fn insert(&mut self, hash: u64, position: usize) -> Result<Option<usize>,(u64,usize)> {
         let idx = get_bucket_idx(hash, self.shift_amount, self.mod_mask);
         self.buckets[idx].set(hash, position)
     }
+    /// Number of buckets, always a power of 2.
+    pub(crate) fn capacity(&self) -> usize {
+        self.buckets.len()
+    }
+    /// The (hash, value) of every occupied bucket.
+    /// Inserting them in to an empty table of the same capacity recreates this table.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.buckets.iter().filter(|b| b.value != 0).map(|b| (b.hash, b.get_value_unchecked()))
+    }
     // //Experimental, didn't seem to have much of an effect
     // /// Inserts a new position into the hash table conditional on if the old position is old enough.
     // /// * hash: The hash of the key
@@ -110,14 +120,14 @@ impl BasicHashTable {
 /// Determines the number of bits to use for the hash table size.
 fn determine_hash_table_size_bits(slots: usize) -> usize {
     ((slots+1).next_power_of_two().trailing_zeros() as usize)
-        .clamp(3, std::mem::size_of::<usize>() * 8) - 1
+        .clamp(3, 64) - 1
 }
 /// Gets the bucket index for a given hash.
 #[inline(always)]
-fn get_bucket_idx(checksum: usize, shift_amt:usize, mod_mask:usize) -> usize {
-   (checksum >> shift_amt) // move high bits down
+fn get_bucket_idx(checksum: u64, shift_amt:usize, mod_mask:usize) -> usize {
+   ((checksum >> shift_amt) // move high bits down
     ^ //mix the bits with xor
-    (checksum & mod_mask) //ensure the final value is within the table bounds (mod op)
+    (checksum & mod_mask as u64)) as usize //ensure the final value is within the table bounds (mod op)
 }
 
 
@@ -168,12 +178,12 @@ impl ChainList {
     /// key: The parent position in the chain (new head) (BUCKET_VALUE_OFFSET is already subtracted)
     /// new_pos: The new position to insert
     #[inline]
-    pub(crate) fn insert(&mut self, hash:usize, key_position: usize, position: usize) {
+    pub(crate) fn insert(&mut self, hash:u64, key_position: usize, position: usize) {
         if self.positions.is_empty() {return;}
         let _ = self.positions.get_mut(key_position & self.mod_mask).unwrap().set(hash, position);
     }
 
-    pub(crate) fn iter_prev_starts<'a>(&'a self, last_pos: usize, cur_out_pos: usize, hash_value:usize) -> PrevPositionIterator<'a>{
+    pub(crate) fn iter_prev_starts<'a>(&'a self, last_pos: usize, cur_out_pos: usize, hash_value:u64) -> PrevPositionIterator<'a>{
         PrevPositionIterator::new(&self, last_pos, cur_out_pos, hash_value)
     }
 }
@@ -181,11 +191,11 @@ pub(crate) struct PrevPositionIterator<'a> {
     list: &'a ChainList,
     last_pos: usize,     // Base position for comparison
     cur_out_pos: usize,  // Current output position
-    hash_value: usize,     // Mask for modulo operation
+    hash_value: u64,     // Mask for modulo operation
 }
 
 impl<'a> PrevPositionIterator<'a> {
-    fn new(list: &'a ChainList, last_pos: usize,cur_out_pos:usize, hash_value:usize) -> Self {
+    fn new(list: &'a ChainList, last_pos: usize,cur_out_pos:usize, hash_value:u64) -> Self {
         if list.positions.is_empty() {
             return Self {
                 list,
@@ -0,0 +1,208 @@
+//! A prebuilt source hash table, for encoding many targets against the same dictionary.
+//!
+//! Every `encode` call hashes the dictionary before it can look for matches. When one dictionary is used for many
+//! targets, a [`DictionaryIndex`] does that once. It covers the whole dictionary (the source window never slides),
+//! is cheap to clone and can be shared between threads, and can be written to disk and read back.
+//!
+//! The index does not keep the dictionary itself, [`encode_with_index`] still needs the bytes to verify and extend matches.
+//! A dictionary that differs from the one the index was built from gives worse patches, but never wrong ones.
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use smdiff_common::{read_u_varint, write_u_varint};
+
+use crate::{diff_inner, hashmap::BasicHashTable, src_matcher::{align, hash_src_range}, write_patch, EncoderConfig, SrcMatcherConfig};
+
+/// Magic bytes at the start of a serialized index.
+const INDEX_MAGIC: [u8; 4] = *b"SMDI";
+
+/// The hashes of every `l_step` position of a dictionary.
+#[derive(Clone, Debug)]
+pub struct DictionaryIndex {
+    l_step: usize,
+    src_len: u64,
+    table: Arc<BasicHashTable>,
+}
+
+impl DictionaryIndex {
+    /// Hashes the whole dictionary.
+    /// * `dict` - The dictionary (source) to index.
+    /// * `config` - Only `l_step` is used. The window size is ignored (the whole dictionary is indexed), as is the backend.
+    pub fn new(dict: &[u8], config: &SrcMatcherConfig) -> Self {
+        let l_step = config.l_step.max(1);
+        let mut table = BasicHashTable::new(Self::table_size(dict.len(), l_step), false);
+        if dict.len() >= 9 {
+            hash_src_range(&mut table, l_step, dict, 0..align(dict.len() - 9, l_step));
+        }
+        Self { l_step, src_len: dict.len() as u64, table: Arc::new(table) }
+    }
+    /// Same sizing as the SrcMatcher uses when its window covers the whole source.
+    fn table_size(src_len: usize, l_step: usize) -> usize {
+        (src_len.next_power_of_two() >> 1) / l_step
+    }
+    pub fn l_step(&self) -> usize {
+        self.l_step
+    }
+    /// The length of the dictionary this index was built from.
+    pub fn src_len(&self) -> u64 {
+        self.src_len
+    }
+    pub(crate) fn table(&self) -> Arc<BasicHashTable> {
+        Arc::clone(&self.table)
+    }
+    /// Writes the index to the writer.
+    ///
+    /// Layout: Magic(4 bytes) | L Step (u-varint) | Src Len (u-varint) | Num Entries (u-varint) | Entries (hash u64 le + position u-varint)...
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&INDEX_MAGIC)?;
+        write_u_varint(writer, self.l_step as u64)?;
+        write_u_varint(writer, self.src_len)?;
+        write_u_varint(writer, self.table.entries().count() as u64)?;
+        for (hash, table_pos) in self.table.entries() {
+            writer.write_all(&hash.to_le_bytes())?;
+            write_u_varint(writer, table_pos as u64)?;
+        }
+        Ok(())
+    }
+    /// Reads an index that was written with [`DictionaryIndex::write`].
+    /// * `reader` - The serialized index.
+    /// * `dict` - The dictionary the index was built from. Its length is checked before the table is allocated.
+    /// # Errors
+    /// Returns an error if the index is malformed, or `dict` is not the length the index was built for.
+    pub fn read<R: Read>(reader: &mut R, dict: &[u8]) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != INDEX_MAGIC {
+            return Err(invalid("Not a smdiff dictionary index".to_string()));
+        }
+        let l_step = read_u_varint(reader)? as usize;
+        if l_step == 0 {
+            return Err(invalid("Invalid index l_step 0".to_string()));
+        }
+        let src_len = read_u_varint(reader)?;
+        if src_len != dict.len() as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Dictionary is {} bytes, the index was built for {} bytes", dict.len(), src_len),
+            ));
+        }
+        let mut table = BasicHashTable::new(Self::table_size(dict.len(), l_step), false);
+        let num_entries = read_u_varint(reader)?;
+        if num_entries > table.capacity() as u64 {
+            return Err(invalid(format!("Index has {} entries for {} buckets", num_entries, table.capacity())));
+        }
+        //every position has a full hash window in the dictionary
+        let max_table_pos = dict.len().checked_sub(9).map(|max_pos| max_pos / l_step);
+        for _ in 0..num_entries {
+            let mut hash = [0u8; 8];
+            reader.read_exact(&mut hash)?;
+            let hash = u64::from_le_bytes(hash);
+            let table_pos = read_u_varint(reader)? as usize;
+            if max_table_pos.is_none_or(|max| table_pos > max) {
+                return Err(invalid(format!("Index position {} (times l_step {}) is past the end of the dictionary", table_pos, l_step)));
+            }
+            if !matches!(table.insert(hash, table_pos), Ok(None)) {
+                return Err(invalid("Index has two entries for one bucket".to_string()));
+            }
+        }
+        Ok(Self { l_step, src_len, table: Arc::new(table) })
+    }
+}
+
+/// Encodes a delta file using a prebuilt index of the dictionary.
+///
+/// This gives the same patch as [`crate::encode`] with `SrcMatcherConfig::new(index.l_step(), None)` when the dictionary is smaller than the default source window,
+/// without hashing the dictionary again. `config.match_src` is ignored, the index is always used.
+/// # Arguments
+/// * `index` - The index built from `dict`.
+/// * `dict` - The source file to use as a dictionary.
+/// * `output` - The target file to encode.
+/// * `writer` - The writer to write the encoded data to.
+/// * `config` - The configuration to use for the encoder. A `filter` is not supported.
+/// # Errors
+/// Returns an error if `dict` is not the length the index was built for, a filter is set, or there was an issue reading the target or writing the encoded data.
+pub fn encode_with_index<R: Read, W: Write>(index: &DictionaryIndex, dict: &[u8], output: &mut R, writer: &mut W, config: &EncoderConfig) -> std::io::Result<()> {
+    if dict.len() as u64 != index.src_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Dictionary is {} bytes, the index was built for {} bytes", dict.len(), index.src_len),
+        ));
+    }
+    if config.filter.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "A filter can not be used with a dictionary index"));
+    }
+    let mut trgt = Vec::new();
+    output.read_to_end(&mut trgt)?;
+    let ops = diff_inner(dict, &trgt, config, Some(index), &mut ())?;
+    write_patch(&ops, writer, config)
+}
+
+#[cfg(test)]
+mod test_super {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::random_bytes;
+    use crate::{encode, TrgtMatcherConfig};
+
+    #[test]
+    fn test_encode_with_index() {
+        let src = random_bytes(23, 50_000);
+        let targets: Vec<Vec<u8>> = (0..4).map(|i| {
+            let mut trgt = src.clone();
+            trgt[i * 10_000..i * 10_000 + 50].fill(i as u8);
+            trgt.drain(30_000..30_000 + i * 100);
+            trgt
+        }).collect();
+        let match_src = SrcMatcherConfig::comp_level(5);
+        let config = EncoderConfig::default().set_match_src(match_src.clone()).set_match_target(TrgtMatcherConfig::comp_level(3));
+        let mut serialized = Vec::new();
+        DictionaryIndex::new(&src, &match_src).write(&mut serialized).unwrap();
+        let index = Arc::new(DictionaryIndex::read(&mut Cursor::new(&serialized), &src).unwrap());
+        let patches: Vec<Vec<u8>> = std::thread::scope(|s| {
+            let handles: Vec<_> = targets.iter().map(|trgt| {
+                let index = Arc::clone(&index);
+                let (src, config) = (&src, &config);
+                s.spawn(move || {
+                    let mut patch = Vec::new();
+                    encode_with_index(&index, src, &mut Cursor::new(trgt), &mut patch, config).unwrap();
+                    patch
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for (trgt, patch) in targets.iter().zip(patches) {
+            let mut expected = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(trgt), &mut expected, &config).unwrap();
+            assert_eq!(patch, expected);
+        }
+        let err = encode_with_index(&index, &src[1..], &mut Cursor::new(&targets[0]), &mut Vec::new(), &config).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_corrupt_index() {
+        let src = random_bytes(29, 5_000);
+        let l_step = 2;
+        let serialize = |src_len: u64, positions: &[u64]| {
+            let mut index = INDEX_MAGIC.to_vec();
+            write_u_varint(&mut index, l_step).unwrap();
+            write_u_varint(&mut index, src_len).unwrap();
+            write_u_varint(&mut index, positions.len() as u64).unwrap();
+            for (i, pos) in positions.iter().enumerate() {
+                index.extend_from_slice(&(i as u64).to_le_bytes());
+                write_u_varint(&mut index, *pos).unwrap();
+            }
+            index
+        };
+        let last = (5_000 - 9) / l_step;
+        assert!(DictionaryIndex::read(&mut Cursor::new(serialize(5_000, &[0, last])), &src).is_ok());
+        //a position whose hash window runs past the end of the dictionary
+        let err = DictionaryIndex::read(&mut Cursor::new(serialize(5_000, &[0, 5_000 / l_step])), &src).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        //a huge length is refused before the table is allocated
+        let err = DictionaryIndex::read(&mut Cursor::new(serialize(1 << 60, &[0])), &src).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -2,12 +2,14 @@
 use std::ops::{Range, RangeInclusive};
 
 use encoder::{GenericEncoderConfig, LargerTrgtNaiveTests};
+use index::DictionaryIndex;
 use op_maker::translate_inner_ops;
-use smdiff_common::{AddOp, Copy, CopySrc, Format, Run, MAX_INST_SIZE, MAX_WIN_SIZE};
+use smdiff_common::{dictionary::DictionaryManifest, filter::{write_filter_header, Filter}, progress::{check_cancelled, Observer, Phase}, AddOp, Copy, CopySrc, Format, Run, MAX_INST_SIZE, MAX_WIN_SIZE};
 use smdiff_writer::make_sections;
-pub use src_matcher::SrcMatcherConfig;
+pub use src_matcher::{SrcMatcherBackend, SrcMatcherConfig};
 pub use trgt_matcher::TrgtMatcherConfig;
-use writer::section_writer;
+pub use line_matcher::{split_lines, LineMatcherConfig};
+use writer::{adaptive_section_writer, section_writer};
 
 
 
@@ -15,9 +17,18 @@ mod hasher;
 mod hashmap;
 mod trgt_matcher;
 mod src_matcher;
+mod suffix_array;
+mod line_matcher;
 mod op_maker;
 mod encoder;
+mod optimal;
+mod approx;
 pub mod writer;
+pub mod signature;
+pub mod index;
+pub mod similarity;
+pub mod chunk;
+pub mod budget;
 
 pub mod zstd{
 //! This module is a re-export of the zstd encoder used in the secondary compression.
@@ -30,17 +41,24 @@ pub mod brotli {
 }
 
 /// The Add operation for the encoder.
+/// It borrows its bytes from the target.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-struct Add <'a> {
-    bytes: &'a [u8],
+pub struct Add <'a> {
+    pub bytes: &'a [u8],
+}
+impl<'a> Add<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Add { bytes }
+    }
 }
 impl AddOp for Add<'_> {
     fn bytes(&self) -> &[u8] {
-        &self.bytes
+        self.bytes
     }
 }
 
-type Op<'a> = smdiff_common::Op<Add<'a>>;
+/// Op Type alias for the Encoders Add type
+pub type Op<'a> = smdiff_common::Op<Add<'a>>;
 
 /// The secondary compression algorithm to use.
 /// Default Value: Zstd { level: 3 }
@@ -53,6 +71,11 @@ pub enum SecondaryCompression {
     Zstd{level:i32},
     /// Default Value: BrotliEncoderOptions::default()
     Brotli{options: ::brotlic::BrotliEncoderOptions},
+    /// Tries no compression, then Zstd, Smdiff and Brotli (at their defaults) on each section and writes the smallest.
+    /// Once `time_budget` has passed for a section, the remaining algorithms are not tried for it.
+    /// A try that is already running is not interrupted, so a section can go over the budget by one try.
+    /// Default Value: None (try them all)
+    Auto{time_budget: Option<std::time::Duration>},
 }
 
 impl SecondaryCompression {
@@ -67,12 +90,18 @@ impl SecondaryCompression {
     pub fn new_brotli_default() -> Self {
         SecondaryCompression::Brotli { options: ::brotlic::BrotliEncoderOptions::default() }
     }
+
+    pub fn new_auto_default() -> Self {
+        SecondaryCompression::Auto { time_budget: None }
+    }
     /// Returns the value to use in the header. Per the spec.
+    /// Auto is resolved per section, so it never ends up in a header. It returns 0 (no compression).
     pub fn algo_value(&self) -> u8 {
         match self {
             SecondaryCompression::Smdiff { .. } => 1,
             SecondaryCompression::Zstd { .. } => 2,
             SecondaryCompression::Brotli { .. } => 3,
+            SecondaryCompression::Auto { .. } => 0,
         }
     }
 }
@@ -89,9 +118,22 @@ impl Default for SecondaryCompression {
 /// - match_target: None
 /// - sec_comp: None
 /// - format: Interleaved
+/// - adaptive_format: false
 /// - output_segment_size: MAX_WIN_SIZE
 /// - naive_tests: None
 /// - lazy_escape_len: Some(45)
+/// - optimal_parse: false
+/// - approximate_matches: false
+/// - match_lines: None
+/// - filter: None
+///
+/// # Determinism
+/// The patch is a pure function of the dictionary, the target and this config.
+/// Nothing depends on the platform (hashes are 64 bit everywhere), on threads or on randomness, and a change to the output for the same inputs is treated as a breaking change (see the golden patch tests).
+///
+/// Every field takes part in choosing the patch bytes, so two encodes only give the same patch with equal configs. Two settings need more care:
+/// - `sec_comp` of `Zstd` or `Brotli`: the compressed bytes come from those libraries and can change between their versions. Pin them (e.g. with Cargo.lock) for reproducible patches. `Smdiff` and no secondary compression do not have this problem.
+/// - `sec_comp` of `Auto` with a `time_budget`: which algorithms are tried depends on how fast the machine is, so the output is not reproducible. Use `time_budget: None`.
 #[derive(Clone, Debug)]
 pub struct EncoderConfig {
     /// Do we consider the src file as a dictionary to find matches?
@@ -108,6 +150,9 @@ pub struct EncoderConfig {
     /// Whether to interleave or segregate the Add bytes.
     /// Default Value: Interleaved
     pub format: Format,
+    /// Write every section in both formats and keep the smaller one. `format` is ignored when this is set.
+    /// Default Value: false
+    pub adaptive_format: bool,
     /// The size of the output window.
     /// Default Value: MAX_WIN_SIZE
     /// The minimum value is MAX_INST_SIZE.
@@ -118,6 +163,25 @@ pub struct EncoderConfig {
     /// The length of a match that will end the lazy matching sequence.
     /// Default Value: Some(45)
     pub lazy_escape_len: Option<usize>,
+    /// Pick the ops with a shortest path search over all the candidate matches, using the exact size of each op in the patch.
+    /// Both matchers are asked at every position and all of their matches are kept, instead of the first one found.
+    /// Addresses are priced per section of `output_segment_size`, as the writer starts them over in each section.
+    /// This is slower and uses more memory than the default greedy choice, but gives smaller patches.
+    /// Default Value: false
+    pub optimal_parse: bool,
+    /// After each copy from the dictionary, keep comparing the following Add bytes against the dictionary at the same alignment.
+    /// Equal stretches become copies when that is cheaper, so regions with sparse changes (like relocated addresses in executables)
+    /// are encoded as long copies with small Adds for the changed bytes.
+    /// Default Value: false
+    pub approximate_matches: bool,
+    /// Diff the target against the dictionary line by line instead, for text. Unchanged lines are copied and changed lines are added.
+    /// When this is set, `match_src`, `match_trgt`, `naive_tests`, `lazy_escape_len`, `optimal_parse` and `approximate_matches` are ignored.
+    /// Default Value: None
+    pub match_lines: Option<LineMatcherConfig>,
+    /// A branch converter run over the dictionary and the target before encoding, for executables.
+    /// The filter is recorded at the start of the patch and `smdiff_decoder::apply_patch` reverses it.
+    /// Default Value: None
+    pub filter: Option<Filter>,
 
 }
 
@@ -147,10 +211,17 @@ impl EncoderConfig {
     }
     pub fn format_interleaved(mut self) -> Self {
         self.format = Format::Interleaved;
+        self.adaptive_format = false;
         self
     }
     pub fn format_segregated(mut self) -> Self {
         self.format = Format::Segregated;
+        self.adaptive_format = false;
+        self
+    }
+    /// Pick the smaller format for each section.
+    pub fn format_auto(mut self) -> Self {
+        self.adaptive_format = true;
         self
     }
     pub fn set_match_target(mut self, config: TrgtMatcherConfig) -> Self {
@@ -169,9 +240,26 @@ impl EncoderConfig {
         self.lazy_escape_len = Some(len);
         self
     }
+    pub fn set_optimal_parse(mut self, optimal_parse: bool) -> Self {
+        self.optimal_parse = optimal_parse;
+        self
+    }
+    pub fn set_approximate_matches(mut self, approximate_matches: bool) -> Self {
+        self.approximate_matches = approximate_matches;
+        self
+    }
+    pub fn set_match_lines(mut self, config: LineMatcherConfig) -> Self {
+        self.match_lines = Some(config);
+        self
+    }
+    pub fn set_filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
     /// Use the short hand compression level.
     /// If match_trgt is true, the same compression level will be used to set the TrgtMatcherConfig.
     /// If secondary compression is Some(_), the format will be Segregated, else Interleaved.
+    /// To search the levels for a patch size or time limit instead, see [`budget::encode_with_budget`].
     pub fn comp_level(level: usize,match_trgt:bool,sec_comp:Option<SecondaryCompression>) -> Self {
         let match_trgt = if match_trgt {
             Some(TrgtMatcherConfig::comp_level(level))
@@ -187,10 +275,15 @@ impl EncoderConfig {
             match_src: Some(SrcMatcherConfig::comp_level(level)),
             output_segment_size: MAX_WIN_SIZE,
             format,
+            adaptive_format: false,
             match_trgt,
             sec_comp,
             naive_tests: None,
             lazy_escape_len: None,
+            optimal_parse: false,
+            approximate_matches: false,
+            match_lines: None,
+            filter: None,
         }
     }
 }
@@ -200,10 +293,15 @@ impl Default for EncoderConfig {
             match_src: Some(SrcMatcherConfig::comp_level(3)),
             output_segment_size: MAX_WIN_SIZE,
             format: Format::Interleaved,
+            adaptive_format: false,
             match_trgt: None,
             sec_comp: None,
             naive_tests: None,
             lazy_escape_len: None,
+            optimal_parse: false,
+            approximate_matches: false,
+            match_lines: None,
+            filter: None,
         }
     }
 }
@@ -216,6 +314,20 @@ impl Default for EncoderConfig {
 /// # Errors
 /// Returns an error if there was an issue reading the source or target files, or writing the encoded data.
 pub fn encode<R: std::io::Read+std::io::Seek, W: std::io::Write>(dict: Option<&mut R>, output: &mut R, writer: &mut W,config:&EncoderConfig) -> std::io::Result<()> {
+    encode_with_observer(dict, output, writer, config, &mut ())
+}
+
+/// Same as [`encode`], but reports progress to the observer, which can also cancel the encode.
+/// # Arguments
+/// * `dict` - The source file to use as a dictionary. If None, the source file will not be used.
+/// * `output` - The target file to encode.
+/// * `writer` - The writer to write the encoded data to.
+/// * `config` - The configuration to use for the encoder.
+/// * `observer` - Receives the phases (hashing source, matching, writing or compressing) and progress.
+/// # Errors
+/// Returns an error if there was an issue reading the source or target files, or writing the encoded data.
+/// Returns a [`smdiff_common::progress::Cancelled`] error if the observer cancelled, the writer then holds an incomplete patch.
+pub fn encode_with_observer<R: std::io::Read+std::io::Seek, W: std::io::Write>(dict: Option<&mut R>, output: &mut R, writer: &mut W,config:&EncoderConfig, observer: &mut dyn Observer) -> std::io::Result<()> {
     //this simple encoder will just read all the bytes to memory.
     let mut src_bytes = Vec::new();
     if let Some(r) = dict {
@@ -223,41 +335,174 @@ pub fn encode<R: std::io::Read+std::io::Seek, W: std::io::Write>(dict: Option<&m
     }
     let mut trgt_bytes = Vec::new();
     output.read_to_end(&mut trgt_bytes)?;
-    let src = src_bytes.as_slice();
-    let trgt = trgt_bytes.as_slice();
-    let EncoderConfig { match_src, match_trgt, sec_comp, format,output_segment_size, naive_tests, lazy_escape_len } = config.clone();
-    let segment_size = output_segment_size.min(MAX_WIN_SIZE).max(MAX_INST_SIZE);
+    encode_bytes(&src_bytes, &trgt_bytes, writer, config, observer)
+}
+
+/// Encodes a delta file against several dictionaries at once.
+///
+/// The dictionaries are concatenated (in the given order) into one virtual dictionary, so a single patch can copy from all of them.
+/// The on-disk op encoding is unchanged; `CopySrc::Dict` addresses refer to the concatenation.
+/// # Arguments
+/// * `dicts` - The named source files to use as dictionaries.
+/// * `output` - The target file to encode.
+/// * `writer` - The writer to write the encoded data to.
+/// * `config` - The configuration to use for the encoder.
+/// # Returns
+/// The manifest describing the layout of the concatenated dictionary.
+/// The decoder needs the same sources, in the same order, to apply the patch.
+/// # Errors
+/// Returns an error if there was an issue reading the source or target files, or writing the encoded data.
+pub fn encode_multi<D: std::io::Read, R: std::io::Read, W: std::io::Write>(dicts: &mut [(&str, D)], output: &mut R, writer: &mut W, config:&EncoderConfig) -> std::io::Result<DictionaryManifest> {
+    let mut manifest = DictionaryManifest::new();
+    let mut src_bytes = Vec::new();
+    for (name, dict) in dicts.iter_mut() {
+        let len = dict.read_to_end(&mut src_bytes)?;
+        manifest.push(*name, len as u64);
+    }
+    let mut trgt_bytes = Vec::new();
+    output.read_to_end(&mut trgt_bytes)?;
+    encode_bytes(&src_bytes, &trgt_bytes, writer, config, &mut ())?;
+    Ok(manifest)
+}
+
+/// Encodes a delta file against whichever candidate dictionary is estimated to give the smallest patch.
+///
+/// Each candidate is only sketched (see [`similarity::Sketch`]), the target is then encoded against the best one.
+/// # Arguments
+/// * `dicts` - The candidate source files.
+/// * `output` - The target file to encode.
+/// * `writer` - The writer to write the encoded data to.
+/// * `config` - The configuration to use for the encoder.
+/// # Returns
+/// The index of the chosen dictionary, or None if no candidate shares any data with the target (the patch is then encoded without a dictionary).
+/// # Errors
+/// Returns an error if there was an issue reading the source or target files, or writing the encoded data.
+pub fn encode_best_of<D: std::io::Read+std::io::Seek, R: std::io::Read, W: std::io::Write>(dicts: &mut [D], output: &mut R, writer: &mut W, config:&EncoderConfig) -> std::io::Result<Option<usize>> {
+    let mut trgt_bytes = Vec::new();
+    output.read_to_end(&mut trgt_bytes)?;
+    let target = similarity::Sketch::new(&trgt_bytes);
+    let sketches = dicts.iter_mut().map(similarity::Sketch::from_reader).collect::<std::io::Result<Vec<_>>>()?;
+    let best = similarity::rank_candidates(&target, &sketches).into_iter().next().filter(|c| c.containment > 0.0);
+    let mut src_bytes = Vec::new();
+    if let Some(best) = best {
+        let dict = &mut dicts[best.index];
+        dict.seek(std::io::SeekFrom::Start(0))?;
+        dict.read_to_end(&mut src_bytes)?;
+    }
+    encode_bytes(&src_bytes, &trgt_bytes, writer, config, &mut ())?;
+    Ok(best.map(|c| c.index))
+}
+
+fn encode_bytes<W: std::io::Write>(src: &[u8], trgt: &[u8], writer: &mut W, config:&EncoderConfig, observer: &mut dyn Observer) -> std::io::Result<()> {
+    if let Some(filter) = config.filter {
+        let mut src = src.to_vec();
+        filter.apply(&mut src);
+        let mut trgt = trgt.to_vec();
+        filter.apply(&mut trgt);
+        write_filter_header(filter, writer)?;
+        return encode_bytes(&src, &trgt, writer, &EncoderConfig { filter: None, ..config.clone() }, observer);
+    }
+    let ops = diff_inner(src, trgt, config, None, observer)?;
+    write_sections(&ops, writer, config, observer)
+}
+
+/// Finds the ops that produce the target, without writing them.
+///
+/// This is the matching half of [`encode`]. The ops can be inspected or changed, then written with [`write_patch`].
+/// Only the matching options of the config are used (`match_src`, `match_trgt`, `naive_tests`, `lazy_escape_len`, `optimal_parse`, `approximate_matches` and `match_lines`).
+/// The `filter` is not applied here, filter the inputs first if one is wanted.
+/// # Arguments
+/// * `dict` - The source file to use as a dictionary. May be empty.
+/// * `target` - The target file to encode.
+/// * `config` - The configuration to use for the encoder.
+/// # Returns
+/// The ops in output order. Add ops borrow their bytes from `target`.
+pub fn diff<'a>(dict: &[u8], target: &'a [u8], config:&EncoderConfig) -> Vec<Op<'a>> {
+    diff_inner(dict, target, config, None, &mut ()).expect("The no-op observer never cancels")
+}
+
+/// [`diff`], optionally looking up source matches in a prebuilt index of `dict`.
+/// Only fails if the observer cancels.
+pub(crate) fn diff_inner<'a>(dict: &[u8], target: &'a [u8], config:&EncoderConfig, src_index: Option<&DictionaryIndex>, observer: &mut dyn Observer) -> std::io::Result<Vec<Op<'a>>> {
+    let EncoderConfig { match_src, match_trgt, naive_tests, lazy_escape_len, optimal_parse, approximate_matches, match_lines, output_segment_size, .. } = config.clone();
+    if let Some(match_lines) = match_lines {
+        observer.phase(Phase::Matching);
+        check_cancelled(observer)?;
+        return Ok(line_matcher::diff_lines(dict, target, &match_lines));
+    }
     let mut inner_config = GenericEncoderConfig{
         match_trgt,
-        match_src,
+        match_src: match src_index {
+            Some(index) => Some(SrcMatcherConfig::new(index.l_step(), None)),
+            None => match_src,
+        },
         lazy_escape_len,
         naive_tests,
+        src_index: src_index.cloned(),
+        collect_candidates: optimal_parse,
     };
-    let segments = encoder::encode_inner(&mut inner_config, src, trgt);
-    // dbg!(&inner_config);
-    let ops = translate_inner_ops(trgt, segments);
-    let mut cur_o_pos: usize = 0;
-    let mut win_data = Vec::new();
-    for (seg_ops,mut header) in make_sections(&ops, segment_size){
-        header.format = format;
-        debug_assert!({
-            let mut o = cur_o_pos;
-            seg_ops.iter().all(
-                |op| {
-                    let len = op.oal() as usize;
-                    let test = &trgt[o..o + len];
-                    o += len;
-                    match op{
-                        Op::Add(Add { bytes }) => test == &bytes[..],
-                        Op::Copy(Copy { src:CopySrc::Dict, addr, len }) => test == &src[*addr as usize..*addr as usize + *len as usize],
-                        Op::Copy(Copy { src:CopySrc::Output, addr, len }) => test == &trgt[*addr as usize..*addr as usize + *len as usize],
-                        Op::Run(Run{ byte, .. }) => test.iter().all(|b| b == byte),
-                    }
+    let segments = encoder::encode_inner(&mut inner_config, dict, target, observer)?;
+    let ops = if optimal_parse {
+        optimal::optimal_parse(target, segments, output_segment_size.clamp(MAX_INST_SIZE, MAX_WIN_SIZE))
+    }else{
+        translate_inner_ops(target, segments)
+    };
+    let ops = if approximate_matches && !dict.is_empty() {
+        approx::repair_adds(dict, target, ops)
+    }else{
+        ops
+    };
+    debug_assert!({
+        let mut o = 0;
+        ops.iter().all(
+            |op| {
+                let len = op.oal() as usize;
+                let test = &target[o..o + len];
+                o += len;
+                match op{
+                    Op::Add(Add { bytes }) => test == &bytes[..],
+                    Op::Copy(Copy { src:CopySrc::Dict, addr, len }) => test == &dict[*addr as usize..*addr as usize + *len as usize],
+                    Op::Copy(Copy { src:CopySrc::Output, addr, len }) => test == &target[*addr as usize..*addr as usize + *len as usize],
+                    Op::Run(Run{ byte, .. }) => test.iter().all(|b| b == byte),
                 }
-            )
-        });
-        cur_o_pos += header.output_size as usize;
-        section_writer(&sec_comp, header, writer, seg_ops, &mut win_data)?; //write the section
+            }
+        )
+    });
+    Ok(ops)
+}
+
+/// Writes ops as a patch, split in to sections.
+///
+/// This is the writing half of [`encode`]. The ops can come from [`diff`], or from anywhere else (like `smdiff_reader::Op`).
+/// Only the output options of the config are used (`sec_comp`, `format`, `adaptive_format` and `output_segment_size`).
+/// Copy-D addresses must be valid for the dictionary the patch will be applied to, this is not checked.
+/// # Arguments
+/// * `ops` - The ops to write, in output order.
+/// * `writer` - The writer to write the encoded data to.
+/// * `config` - The configuration to use for the encoder.
+/// # Errors
+/// Returns an error if there was an issue writing the encoded data.
+pub fn write_patch<W: std::io::Write, A: AddOp>(ops: &[smdiff_common::Op<A>], writer: &mut W, config:&EncoderConfig) -> std::io::Result<()> {
+    write_sections(ops, writer, config, &mut ())
+}
+
+fn write_sections<W: std::io::Write, A: AddOp>(ops: &[smdiff_common::Op<A>], writer: &mut W, config:&EncoderConfig, observer: &mut dyn Observer) -> std::io::Result<()> {
+    let EncoderConfig { sec_comp, format, adaptive_format, output_segment_size, .. } = config;
+    let segment_size = (*output_segment_size).clamp(MAX_INST_SIZE, MAX_WIN_SIZE);
+    let mut win_data = Vec::new();
+    observer.phase(if sec_comp.is_some() { Phase::Compressing } else { Phase::Writing });
+    let mut o_pos = 0u64;
+    for (i, (seg_ops,mut header)) in make_sections(ops, segment_size).into_iter().enumerate(){
+        check_cancelled(observer)?;
+        header.format = *format;
+        o_pos += header.output_size as u64;
+        if *adaptive_format {
+            adaptive_section_writer(sec_comp, header, writer, seg_ops, &mut win_data)?;
+        }else{
+            section_writer(sec_comp, header, writer, seg_ops, &mut win_data)?; //write the section
+        }
+        observer.sections_processed(i + 1);
+        observer.bytes_processed(o_pos);
     }
     Ok(())
 }
@@ -298,9 +543,13 @@ impl Ranger {
 
 
 
+#[cfg(test)]
+pub(crate) use smdiff_common::test_util;
+
 #[cfg(test)]
 mod test_super {
     use super::*;
+    use crate::test_util::{random_bytes, random_stream};
 
 
     #[test]
@@ -342,4 +591,470 @@ mod test_super {
         assert_eq!(interpolator.map(11), interpolator.map(10)); // Above range
     }
 
+    #[test]
+    fn test_encode_multi() {
+        use std::io::Cursor;
+        let a = random_bytes(1, 3000);
+        let b = random_bytes(2, 2000);
+        //a file made from the halves of two old files.
+        let mut trgt = b[1000..].to_vec();
+        trgt.extend_from_slice(b"glue");
+        trgt.extend_from_slice(&a[..1500]);
+        let mut patch = Vec::new();
+        let mut dicts = [("a.bin", Cursor::new(&a)), ("b.bin", Cursor::new(&b))];
+        let manifest = encode_multi(&mut dicts, &mut Cursor::new(&trgt), &mut patch, &EncoderConfig::default()).unwrap();
+        assert_eq!(manifest.locate(3000), Some((1, 0)));
+        assert!(patch.len() < 100, "patch too large: {}", patch.len());
+        let mut sink = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch_multi(&mut Cursor::new(&patch), &manifest, vec![Cursor::new(&a), Cursor::new(&b)], &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), trgt);
+        //the dictionaries must be given in the same order
+        let mut sink = Cursor::new(Vec::new());
+        assert!(smdiff_decoder::apply_patch_multi(&mut Cursor::new(&patch), &manifest, vec![Cursor::new(&b), Cursor::new(&a)], &mut sink).is_err());
+    }
+
+    #[test]
+    fn test_encode_best_of() {
+        use std::io::Cursor;
+        let v1 = random_bytes(1, 8000);
+        let mut v2 = v1.clone();
+        v2.extend_from_slice(&random_bytes(2, 1000));
+        let mut trgt = v2.clone();
+        trgt[100..110].copy_from_slice(b"0123456789");
+        let mut dicts = [Cursor::new(random_bytes(3, 8000)), Cursor::new(v1), Cursor::new(v2.clone())];
+        let mut patch = Vec::new();
+        let chosen = encode_best_of(&mut dicts, &mut Cursor::new(&trgt), &mut patch, &EncoderConfig::default()).unwrap();
+        assert_eq!(chosen, Some(2));
+        let mut sink = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&v2)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), trgt);
+    }
+
+    #[test]
+    fn test_format_auto() {
+        use std::io::Cursor;
+        let src = random_bytes(7, 20_000);
+        let mut trgt = Vec::new();
+        for chunk in src.chunks(500) {
+            trgt.extend_from_slice(&chunk[..450]);
+            trgt.extend_from_slice(b"some added text");
+        }
+        let base = EncoderConfig::default().set_sec_comp(SecondaryCompression::Zstd { level: 3 });
+        let mut sizes = Vec::new();
+        for config in [base.clone().format_interleaved(), base.clone().format_segregated(), base.format_auto()] {
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt);
+            sizes.push(patch.len());
+        }
+        assert!(sizes[2] <= sizes[0].min(sizes[1]), "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_optimal_parse() {
+        use std::io::Cursor;
+        let mut stream = random_stream(3);
+        let mut rand = |len: usize| -> Vec<u8> { stream.by_ref().take(len).map(|n| n as u8).collect() };
+        let src = rand(49_000);
+        let mut trgt = Vec::new();
+        for (i, chunk) in src.chunks(700).enumerate() {
+            trgt.extend_from_slice(&chunk[..600]);
+            trgt.extend(rand(i % 7 + 1));
+            trgt.extend_from_slice(&chunk[..20]);
+            trgt.extend(std::iter::repeat_n(b'z', i % 5 * 40));
+        }
+        trgt.extend(std::iter::repeat_n(0, 100_000));
+        let mut sizes = Vec::new();
+        let base = EncoderConfig::default().set_match_target(TrgtMatcherConfig::comp_level(3));
+        for config in [base.clone(), base.set_optimal_parse(true)] {
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt);
+            sizes.push(patch.len());
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_optimal_parse_candidates() {
+        use std::io::Cursor;
+        //a block from the start of the dictionary between pieces from far away, a Copy-O of its previous
+        //occurrence costs less than jumping the dictionary address back and forth, but it is only found if
+        //the trgt matcher is asked as well as the src matcher.
+        let src = random_bytes(12, 100_000);
+        let mut trgt = Vec::new();
+        for i in 0..200 {
+            let piece = 40_000 + i * 250;
+            trgt.extend_from_slice(&src[piece..piece + 240]);
+            trgt.extend_from_slice(&src[..200]);
+        }
+        let base = EncoderConfig::default().set_match_target(TrgtMatcherConfig::comp_level(3));
+        let copy_o_count = |config: &EncoderConfig| diff(&src, &trgt, config).iter().filter(|op| matches!(op, Op::Copy(Copy { src: CopySrc::Output, .. }))).count();
+        let counts = [copy_o_count(&base), copy_o_count(&base.clone().set_optimal_parse(true))];
+        assert!(counts[1] > counts[0] * 10, "{:?}", counts);
+        let mut sizes = Vec::new();
+        for config in [base.clone(), base.clone().set_optimal_parse(true)] {
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt);
+            sizes.push(patch.len());
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+        //copy addresses start over in each section, so no op may cross into the next one
+        let ops = diff(&src, &trgt, &base.set_optimal_parse(true).set_output_segment_size(MAX_INST_SIZE));
+        let mut o_pos = 0;
+        for op in ops.iter() {
+            let end = o_pos + op.oal() as usize;
+            assert!(o_pos / MAX_INST_SIZE == (end - 1) / MAX_INST_SIZE, "{:?} at {}", op, o_pos);
+            o_pos = end;
+        }
+    }
+
+    #[test]
+    fn test_suffix_array_backend() {
+        use std::io::Cursor;
+        let src = random_bytes(5, 60_000);
+        //short matches from all over the source, which a coarse l_step mostly misses
+        let mut trgt = Vec::new();
+        for i in 0..400 {
+            let start = (i * 7919) % 59_000;
+            trgt.extend_from_slice(&src[start..start + 40]);
+            trgt.push(i as u8);
+        }
+        let hash = SrcMatcherConfig::comp_level(0);
+        let mut sizes = Vec::new();
+        for match_src in [hash.clone(), hash.set_backend(SrcMatcherBackend::SuffixArray)] {
+            let config = EncoderConfig::default().set_match_src(match_src);
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt);
+            sizes.push(patch.len());
+        }
+        //every 40 byte block is found, roughly 3 bytes of copy plus 2 for the separator each
+        assert!(sizes[1] < 400 * 8, "{:?}", sizes);
+        assert!(sizes[1] * 2 < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_approximate_matches() {
+        use std::io::Cursor;
+        let src = random_bytes(9, 60_000);
+        //"relocate" a 4 byte address every 16 bytes after the first 2000
+        let mut trgt = src.clone();
+        for pos in (2000..trgt.len() - 4).step_by(16) {
+            trgt[pos..pos + 4].copy_from_slice(&(pos as u32 + 0x1000).to_le_bytes());
+        }
+        let mut sizes = Vec::new();
+        for approximate in [false, true] {
+            let config = EncoderConfig::default().set_approximate_matches(approximate);
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt);
+            sizes.push(patch.len());
+        }
+        assert!(sizes[1] * 3 < sizes[0] * 2, "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_suffix_array_short_match_at_end() {
+        use std::io::Cursor;
+        //the longest match for the start of the target is the 2 bytes at the very end of the dictionary
+        let mut src = random_bytes(41, 2000);
+        src.extend_from_slice(b"qz");
+        let mut trgt = b"qz".to_vec();
+        trgt.extend_from_slice(&src[..1000]);
+        let config = EncoderConfig::default().set_match_src(SrcMatcherConfig::default().set_backend(SrcMatcherBackend::SuffixArray));
+        let mut patch = Vec::new();
+        encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+        let mut sink = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), trgt);
+    }
+
+    #[test]
+    fn test_approximate_matches_address_cost() {
+        use std::io::Cursor;
+        //relocations dense enough that the stretches between them are shorter than a hash window,
+        //only approximate matches find them, each one a small step from the previous copy.
+        let src = random_bytes(9, 60_000);
+        let mut trgt = src.clone();
+        for pos in (2000..trgt.len() - 4).step_by(12) {
+            trgt[pos..pos + 4].copy_from_slice(&(pos as u32 + 0x1000).to_le_bytes());
+        }
+        let mut sizes = Vec::new();
+        for approximate in [false, true] {
+            let config = EncoderConfig::default().set_approximate_matches(approximate);
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            sizes.push(patch.len());
+        }
+        assert!(sizes[1] * 3 < sizes[0] * 2, "{:?}", sizes);
+        let ops = diff(&src, &trgt, &EncoderConfig::default().set_approximate_matches(true));
+        let addrs: Vec<u64> = ops.iter().filter_map(|op| match op {
+            Op::Copy(Copy { src: CopySrc::Dict, addr, .. }) => Some(*addr),
+            _ => None,
+        }).collect();
+        //after the first copy of the unchanged start, every address is one byte
+        for pair in addrs[1..].windows(2) {
+            assert_eq!(smdiff_common::copy_addr_size(pair[0], pair[1]), 1, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn test_prefer_near_matches() {
+        use std::io::Cursor;
+        let mut src = random_bytes(17, 40_000);
+        //a far away duplicate of the data following the edit, it is the one the hash table keeps
+        src.copy_within(21_000..21_200, 1000);
+        let mut trgt = src[20_000..21_200].to_vec();
+        trgt[1050..1052].copy_from_slice(b"XY");
+        let mut patch = Vec::new();
+        let config = EncoderConfig::default().set_match_src(SrcMatcherConfig::comp_level(9));
+        encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+        let (ops, _) = smdiff_decoder::reader::SectionIterator::new(Cursor::new(patch)).next().unwrap().unwrap();
+        let dict_addrs: Vec<u64> = ops.iter().filter_map(|op| match op {
+            smdiff_common::Op::Copy(Copy { src: CopySrc::Dict, addr, .. }) => Some(*addr),
+            _ => None,
+        }).collect();
+        //both copies after the edit are the same length, the near one has the cheaper address
+        assert_eq!(dict_addrs.last(), Some(&21_052), "{:?}", dict_addrs);
+    }
+
+    #[test]
+    fn test_sparse_zero_regions() {
+        use std::io::Cursor;
+        //A disk image like target: data blocks between large zero regions.
+        let mut stream = random_stream(29);
+        let mut trgt = Vec::new();
+        for _ in 0..5 {
+            trgt.extend(stream.by_ref().take(1_000).map(|n| n as u8 | 1));
+            trgt.resize(trgt.len() + 100_000, 0);
+        }
+        let config = EncoderConfig::default().no_match_src().set_match_target(TrgtMatcherConfig::comp_level(0));
+        let ops = diff(&[], &trgt, &config);
+        let fill_ops = ops.iter().filter(|op| !op.is_add()).count();
+        //the first region grows with doubling copies, the others copy 65535 bytes at a time from it.
+        assert_eq!(fill_ops, smdiff_common::run::run_op_count(100_000) + 4 * 2, "{:?}", ops.iter().filter(|op| !op.is_add()).collect::<Vec<_>>());
+        let mut patch = Vec::new();
+        write_patch(&ops, &mut patch, &config).unwrap();
+        let mut output = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch::<_, Cursor<Vec<u8>>, _>(&mut Cursor::new(&patch), None, &mut output).unwrap();
+        assert_eq!(output.into_inner(), trgt);
+        //Applied sparsely, only the data blocks (and the last byte) are written.
+        let mut sink = smdiff_decoder::SparseSink::new(Cursor::new(Vec::new())).unwrap();
+        smdiff_decoder::apply_patch_to_sink::<_, Cursor<Vec<u8>>, _>(&mut Cursor::new(&patch), None, &mut sink, &mut ()).unwrap();
+        assert_eq!(sink.skipped(), 5 * 100_000 - 1);
+        assert_eq!(sink.into_inner().into_inner(), trgt);
+    }
+
+    #[test]
+    fn test_diff_write_patch() {
+        use std::io::Cursor;
+        let src = random_bytes(19, 30_000);
+        let mut trgt = src[..20_000].to_vec();
+        trgt[5000..5100].fill(7);
+        trgt.extend_from_slice(&src[10_000..12_000]);
+        let config = EncoderConfig::default().set_match_target(TrgtMatcherConfig::comp_level(3)).set_sec_comp(SecondaryCompression::new_zstd_default());
+        let mut encoded = Vec::new();
+        encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut encoded, &config).unwrap();
+        let ops = diff(&src, &trgt, &config);
+        assert_eq!(ops.iter().map(|op| op.oal() as usize).sum::<usize>(), trgt.len());
+        let mut patch = Vec::new();
+        write_patch(&ops, &mut patch, &config).unwrap();
+        assert_eq!(patch, encoded);
+        //ops read back from a patch can be written again with other settings
+        let read_ops: Vec<_> = smdiff_decoder::reader::SectionIterator::new(Cursor::new(&patch))
+            .flat_map(|section| section.unwrap().0)
+            .collect();
+        let mut rewritten = Vec::new();
+        write_patch(&read_ops, &mut rewritten, &EncoderConfig::default().set_output_segment_size(MAX_INST_SIZE)).unwrap();
+        let mut sink = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch(&mut Cursor::new(&rewritten), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), trgt);
+    }
+
+    #[test]
+    fn test_observer() {
+        use std::io::Cursor;
+        use smdiff_common::progress::{is_cancelled, Observer, Phase};
+        #[derive(Default)]
+        struct Recorder {
+            phases: Vec<Phase>,
+            bytes: u64,
+            sections: usize,
+            cancel_after: Option<u64>,
+        }
+        impl Observer for Recorder {
+            fn phase(&mut self, phase: Phase) {
+                self.phases.push(phase);
+            }
+            fn bytes_processed(&mut self, bytes: u64) {
+                self.bytes = bytes;
+            }
+            fn sections_processed(&mut self, sections: usize) {
+                self.sections = sections;
+            }
+            fn cancelled(&mut self) -> bool {
+                self.cancel_after.is_some_and(|after| self.bytes >= after)
+            }
+        }
+        let src = random_bytes(29, 3_000_000);
+        let mut trgt = src.clone();
+        trgt[1_500_000..1_500_100].fill(0);
+        let config = EncoderConfig::default().set_output_segment_size(1 << 20);
+        let mut recorder = Recorder::default();
+        let mut patch = Vec::new();
+        encode_with_observer(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config, &mut recorder).unwrap();
+        assert_eq!(recorder.phases, vec![Phase::HashingSource, Phase::Matching, Phase::Writing]);
+        assert_eq!((recorder.bytes, recorder.sections), (trgt.len() as u64, 3));
+
+        let mut recorder = Recorder::default();
+        let mut sink = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch_with_observer(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink, &mut recorder).unwrap();
+        assert_eq!(sink.into_inner(), trgt);
+        assert_eq!((recorder.phases, recorder.bytes, recorder.sections), (vec![Phase::Applying], trgt.len() as u64, 3));
+
+        let mut recorder = Recorder { cancel_after: Some(1), ..Default::default() };
+        let err = encode_with_observer(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut Vec::new(), &config, &mut recorder).unwrap_err();
+        assert!(is_cancelled(&err));
+        assert_eq!(recorder.phases.last(), Some(&Phase::Matching));
+        let mut recorder = Recorder { cancel_after: Some(1), ..Default::default() };
+        let err = smdiff_decoder::apply_patch_with_observer(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut Cursor::new(Vec::new()), &mut recorder).unwrap_err();
+        assert!(is_cancelled(&err));
+        assert_eq!(recorder.sections, 1);
+    }
+
+    /// Inputs for the golden patches: text-like data (small alphabet, so there are plenty of short chance matches)
+    /// and a target with new data (repeated later), deletes, a run, a moved block and a region with sparse changes.
+    fn golden_inputs() -> (Vec<u8>, Vec<u8>) {
+        let mut stream = random_stream(31);
+        let mut next = move || stream.next().unwrap() as u32;
+        let src: Vec<u8> = (0..60_000).map(|_| b"etaoin shrdlu\n"[(next() % 14) as usize]).collect();
+        let mut trgt = Vec::new();
+        trgt.extend_from_slice(&src[..10_000]);
+        let fresh: Vec<u8> = (0..3_000).map(|_| b"0123456789abcdef"[(next() % 16) as usize]).collect();
+        trgt.extend_from_slice(&fresh);
+        trgt.extend_from_slice(&src[12_000..30_000]);
+        trgt.extend_from_slice(&[0x41; 200]);
+        trgt.extend_from_slice(&fresh[500..2_500]);
+        trgt.extend_from_slice(&src[45_000..50_000]);
+        //a region with sparse changes
+        let start = trgt.len();
+        trgt.extend_from_slice(&src[30_000..45_000]);
+        for i in (start..start + 6_000).step_by(13) {
+            trgt[i] = trgt[i].wrapping_add(1);
+        }
+        for i in (0..trgt.len()).step_by(997) {
+            trgt[i] ^= 0x20;
+        }
+        (src, trgt)
+    }
+
+    /// Patches must be byte identical for the same inputs and config, on every platform and across versions.
+    /// Run with `SMDIFF_BLESS=1` to rewrite the golden files after an intended change to the encoder output.
+    #[test]
+    fn test_golden_patches() {
+        use std::io::Cursor;
+        let (src, trgt) = golden_inputs();
+        let cases = [
+            ("default", EncoderConfig::default()),
+            ("level_9_trgt", EncoderConfig::comp_level(9, true, None)),
+            ("level_0_segregated", EncoderConfig::comp_level(0, true, None).format_segregated()),
+            ("sec_comp_smdiff", EncoderConfig::comp_level(3, true, Some(SecondaryCompression::new_smdiff_default()))),
+            ("small_sections_adaptive", EncoderConfig::default().set_match_target(TrgtMatcherConfig::comp_level(3)).set_output_segment_size(MAX_INST_SIZE).format_auto()),
+            ("optimal_parse", EncoderConfig::comp_level(6, true, None).set_optimal_parse(true)),
+            ("approximate_matches", EncoderConfig::default().set_approximate_matches(true)),
+            ("suffix_array", EncoderConfig::default().set_match_src(SrcMatcherConfig::default().set_backend(SrcMatcherBackend::SuffixArray))),
+            ("filter_x86", EncoderConfig::default().set_filter(Filter::X86)),
+        ];
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join("golden");
+        let bless = std::env::var_os("SMDIFF_BLESS").is_some();
+        for (name, config) in cases {
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt, "{}", name);
+            let path = dir.join(format!("{}.smdiff", name));
+            if bless {
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(&path, &patch).unwrap();
+                continue;
+            }
+            let golden = std::fs::read(&path).unwrap_or_else(|e| panic!("Missing golden patch {:?} ({}), run with SMDIFF_BLESS=1", path, e));
+            assert!(golden == patch, "Patch for '{}' changed ({} bytes, golden is {} bytes). If intended, run with SMDIFF_BLESS=1", name, patch.len(), golden.len());
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        use std::io::Cursor;
+        let mut stream = random_stream(13);
+        let mut rand = |len: usize| -> Vec<u8> { stream.by_ref().take(len).map(|n| n as u8).collect() };
+        //"code" with a call every 32 bytes to functions that do not move between builds
+        let header = rand(100);
+        let body: Vec<Vec<u8>> = (0..2000).map(|_| rand(32)).collect();
+        let build = |extra: &[u8]| -> Vec<u8> {
+            let mut code = header.clone();
+            code.extend_from_slice(extra);
+            for (i, chunk) in body.iter().enumerate() {
+                let call = code.len() + 4;
+                code.extend_from_slice(chunk);
+                let target = 200_000 + (i as u32 % 50) * 64;
+                code[call] = 0xE8;
+                code[call + 1..call + 5].copy_from_slice(&(target - (call as u32 + 5)).to_le_bytes());
+            }
+            code
+        };
+        let src = build(&[]);
+        //the new build inserts a few bytes near the start, which changes every relative target after it
+        let trgt = build(&rand(3));
+        let mut sizes = Vec::new();
+        for config in [EncoderConfig::default(), EncoderConfig::default().set_filter(Filter::X86)] {
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch(&mut Cursor::new(&patch), Some(&mut Cursor::new(&src)), &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt);
+            sizes.push(patch.len());
+        }
+        assert!(sizes[1] * 2 < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_sec_comp_auto() {
+        use std::io::Cursor;
+        let trgt: Vec<u8> = b"the quick brown fox jumps over the lazy dog. ".iter().cycle().take(50_000).copied().collect();
+        let mut sizes = Vec::new();
+        let fixed = [None, Some(SecondaryCompression::new_zstd_default()), Some(SecondaryCompression::new_smdiff_default()), Some(SecondaryCompression::new_brotli_default())];
+        for sec_comp in fixed {
+            let config = EncoderConfig { sec_comp, ..EncoderConfig::default().no_match_src() };
+            let mut patch = Vec::new();
+            encode(None::<&mut Cursor<&Vec<u8>>>, &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            sizes.push(patch.len());
+        }
+        let encode_auto = |time_budget| {
+            let config = EncoderConfig::default().no_match_src().set_sec_comp(SecondaryCompression::Auto { time_budget });
+            let mut patch = Vec::new();
+            encode(None::<&mut Cursor<&Vec<u8>>>, &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+            let mut sink = Cursor::new(Vec::new());
+            smdiff_decoder::apply_patch::<_, Cursor<Vec<u8>>, _>(&mut Cursor::new(&patch), None, &mut sink).unwrap();
+            assert_eq!(sink.into_inner(), trgt);
+            patch.len()
+        };
+        assert_eq!(encode_auto(None), *sizes.iter().min().unwrap());
+        //no time to try anything, so the section is left uncompressed
+        assert_eq!(encode_auto(Some(std::time::Duration::ZERO)), sizes[0]);
+    }
+
 }
\ No newline at end of file
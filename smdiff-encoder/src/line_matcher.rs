@@ -0,0 +1,205 @@
+//! Line based matching, used instead of the hash matchers when `EncoderConfig::match_lines` is set.
+//!
+//! Both inputs are split into lines (each keeping its '\n') and every distinct line is interned to a u32 id,
+//! so the diff compares integers instead of bytes. The id sequences are then diffed with Myers' algorithm in linear space:
+//! common prefixes and suffixes are stripped, the middle snake of what is left is found, and both halves are diffed recursively.
+//!
+//! The resulting runs of equal lines become Copy-D ops (split at MAX_INST_SIZE) and the lines between them become Adds.
+//! The encoder then writes these ops as they are, without the source, target or naive matchers.
+use std::collections::HashMap;
+use std::ops::Range;
+
+use smdiff_common::{Copy, CopySrc, MAX_INST_SIZE};
+
+use crate::{Add, Op};
+
+///Configuration for the LineMatcher.
+///
+/// The target is diffed against the dictionary line by line (Myers, in linear space).
+/// Runs of unchanged lines become Copy-D ops and changed lines become Adds, so the patch lines up with the line edits.
+/// This takes O((N+M)D) time for N and M lines with D changed lines, so it is meant for text (config files, source code),
+/// not for large binaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineMatcherConfig{
+    /// Runs of unchanged lines shorter than this many bytes are added instead of copied, as a copy costs a few bytes itself.
+    /// Default Value: 4
+    pub min_copy_len: usize,
+}
+
+impl Default for LineMatcherConfig {
+    fn default() -> Self {
+        Self { min_copy_len: 4 }
+    }
+}
+
+impl LineMatcherConfig {
+    pub fn new(min_copy_len: usize) -> Self {
+        Self { min_copy_len }
+    }
+}
+
+/// The byte ranges of the lines in data. Every line but the last ends with (and includes) a '\n'.
+pub fn split_lines(data: &[u8]) -> Vec<Range<usize>> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, b) in data.iter().enumerate() {
+        if *b == b'\n' {
+            lines.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        lines.push(start..data.len());
+    }
+    lines
+}
+
+/// Diffs the target against the dictionary by lines.
+pub(crate) fn diff_lines<'a>(dict: &[u8], target: &'a [u8], config: &LineMatcherConfig) -> Vec<Op<'a>> {
+    let dict_lines = split_lines(dict);
+    let trgt_lines = split_lines(target);
+    //Intern the lines, so the diff compares ids.
+    let mut ids: HashMap<&[u8], u32> = HashMap::new();
+    let a: Vec<u32> = dict_lines.iter().map(|l| intern(&mut ids, &dict[l.clone()])).collect();
+    let b: Vec<u32> = trgt_lines.iter().map(|l| intern(&mut ids, &target[l.clone()])).collect();
+    let mut blocks = Vec::new();
+    matching_blocks(&a, &b, 0, 0, &mut blocks);
+
+    let mut ops = Vec::new();
+    let mut add_start = 0;
+    for (a_start, b_start, len) in blocks {
+        let d_range = dict_lines[a_start].start..dict_lines[a_start + len - 1].end;
+        let o_range = trgt_lines[b_start].start..trgt_lines[b_start + len - 1].end;
+        if d_range.len() < config.min_copy_len.max(1) {
+            continue;
+        }
+        push_adds(&mut ops, &target[add_start..o_range.start]);
+        let mut addr = d_range.start as u64;
+        for chunk in o_range.clone().step_by(MAX_INST_SIZE) {
+            let len = (o_range.end - chunk).min(MAX_INST_SIZE) as u16;
+            ops.push(Op::Copy(Copy { src: CopySrc::Dict, addr, len }));
+            addr += len as u64;
+        }
+        add_start = o_range.end;
+    }
+    push_adds(&mut ops, &target[add_start..]);
+    ops
+}
+
+fn intern<'d>(ids: &mut HashMap<&'d [u8], u32>, line: &'d [u8]) -> u32 {
+    let next = ids.len() as u32;
+    *ids.entry(line).or_insert(next)
+}
+
+fn push_adds<'a>(ops: &mut Vec<Op<'a>>, bytes: &'a [u8]) {
+    ops.extend(bytes.chunks(MAX_INST_SIZE).map(|b| Op::Add(Add::new(b))));
+}
+
+/// Appends the runs of equal items as (a start, b start, len), in order.
+/// `a_off` and `b_off` are where `a` and `b` start in the full sequences.
+fn matching_blocks(a: &[u32], b: &[u32], a_off: usize, b_off: usize, blocks: &mut Vec<(usize, usize, usize)>) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    if prefix > 0 {
+        blocks.push((a_off, b_off, prefix));
+    }
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let (a_off, b_off) = (a_off + prefix, b_off + prefix);
+    let suffix = a.iter().rev().zip(b.iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+    if !a.is_empty() && !b.is_empty() {
+        let (x, y, u, v) = middle_snake(a, b);
+        matching_blocks(&a[..x], &b[..y], a_off, b_off, blocks);
+        if u > x {
+            blocks.push((a_off + x, b_off + y, u - x));
+        }
+        matching_blocks(&a[u..], &b[v..], a_off + u, b_off + v, blocks);
+    }
+    if suffix > 0 {
+        blocks.push((a_off + a.len(), b_off + b.len(), suffix));
+    }
+}
+
+/// Finds the middle snake of the shortest edit script, as (x, y) to (u, v).
+/// Both sequences must be non empty, and differ in their first and last items.
+fn middle_snake(a: &[u32], b: &[u32]) -> (usize, usize, usize, usize) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let odd = delta & 1 != 0;
+    let max = (n + m + 1) / 2;
+    let off = max + 1;
+    //fwd holds the furthest x on each diagonal, bwd the furthest distance from the end.
+    let mut fwd = vec![0isize; 2 * off as usize + 1];
+    let mut bwd = vec![0isize; 2 * off as usize + 1];
+    let at = |k: isize| (k + off) as usize;
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && fwd[at(k - 1)] < fwd[at(k + 1)]) { fwd[at(k + 1)] } else { fwd[at(k - 1)] + 1 };
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            fwd[at(k)] = x;
+            let kb = delta - k;
+            if odd && kb > -d && kb < d && fwd[at(k)] + bwd[at(kb)] >= n {
+                return (x0 as usize, y0 as usize, x as usize, y as usize);
+            }
+        }
+        for kb in (-d..=d).step_by(2) {
+            let mut x = if kb == -d || (kb != d && bwd[at(kb - 1)] < bwd[at(kb + 1)]) { bwd[at(kb + 1)] } else { bwd[at(kb - 1)] + 1 };
+            let mut y = x - kb;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && a[(n - 1 - x) as usize] == b[(m - 1 - y) as usize] {
+                x += 1;
+                y += 1;
+            }
+            bwd[at(kb)] = x;
+            let k = delta - kb;
+            if !odd && k >= -d && k <= d && fwd[at(k)] + bwd[at(kb)] >= n {
+                return ((n - x) as usize, (m - y) as usize, (n - x0) as usize, (m - y0) as usize);
+            }
+        }
+    }
+    unreachable!("The middle snake is always found by d = (n + m + 1) / 2")
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::test_util::random_stream;
+
+    /// Checks the blocks really match, and that they are the longest common subsequence by brute force.
+    fn check(a: &[u32], b: &[u32]) {
+        let mut blocks = Vec::new();
+        matching_blocks(a, b, 0, 0, &mut blocks);
+        let (mut pa, mut pb) = (0, 0);
+        for &(x, y, len) in blocks.iter() {
+            assert!(x >= pa && y >= pb && len > 0);
+            assert_eq!(&a[x..x + len], &b[y..y + len]);
+            (pa, pb) = (x + len, y + len);
+        }
+        let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+            }
+        }
+        assert_eq!(blocks.iter().map(|b| b.2).sum::<usize>(), lcs[0][0], "{:?} {:?}", a, b);
+    }
+
+    #[test]
+    fn test_matching_blocks() {
+        check(&[1, 2, 3, 4, 5], &[1, 2, 9, 4, 5]);
+        check(&[1], &[2]);
+        check(&[], &[1, 2]);
+        check(&[1, 2, 3], &[3, 2, 1]);
+        let mut stream = random_stream(3);
+        let mut next = |max: u64| stream.next().unwrap() % max;
+        for _ in 0..200 {
+            let a: Vec<u32> = (0..next(30)).map(|_| next(5) as u32).collect();
+            let b: Vec<u32> = (0..next(30)).map(|_| next(5) as u32).collect();
+            check(&a, &b);
+        }
+    }
+}
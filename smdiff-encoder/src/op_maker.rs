@@ -1,4 +1,4 @@
-use smdiff_common::{Copy, CopySrc, MAX_INST_SIZE, MAX_RUN_LEN};
+use smdiff_common::{run::RunCache, Copy, CopySrc, MAX_INST_SIZE};
 
 use crate::{encoder::InnerOp, Op};
 
@@ -64,6 +64,8 @@ pub fn translate_inner_ops<'a>(trgt:&'a [u8], mut ops: Vec<InnerOp>)->Vec<Op<'a>
     //now we convert the ops into Op structs
     let mut out_ops = Vec::with_capacity(ops_len);
     let mut out_pos = 0;
+    //long runs copy from earlier runs of the same byte.
+    let mut runs = RunCache::new();
     for op in ops.into_iter().filter(|a|*a.len() > 0) {
         let o_pos = *op.o_pos();
         if o_pos > out_pos {
@@ -81,7 +83,7 @@ pub fn translate_inner_ops<'a>(trgt:&'a [u8], mut ops: Vec<InnerOp>)->Vec<Op<'a>
                 make_copy_ops(CopySrc::Output, start, length, &mut out_ops);
             },
             InnerOp::Run { byte, length, .. } => {
-                make_run_ops(byte, length, out_pos, &mut out_ops);
+                runs.make_run_ops(byte, length, out_pos as u64, &mut out_ops);
             },
         }
         out_pos += len;
@@ -124,94 +126,3 @@ fn make_copy_ops(src: CopySrc, start:usize, len:usize, output: &mut Vec<Op>){
         processed += chunk_size;
     };
 }
-const RUN_LIMIT: usize = (MAX_RUN_LEN as usize) * 6;
-const COPY_LIMIT: usize = RUN_LIMIT/2;
-fn make_run_ops(byte:u8, len:usize, run_start_pos:usize, output: &mut Vec<Op>){
-    if len < RUN_LIMIT {
-        let mut processed = 0;
-        while processed < len {
-            let remaining = len - processed;
-            let chunk_size = (MAX_RUN_LEN as usize).min(remaining);
-            let op = Op::Run(smdiff_common::Run{byte, len: chunk_size as u8});
-            output.push(op);
-            processed += chunk_size;
-        };
-    }else{
-        //we can use one or more copies on 3 runs.
-        //we need to emit the three runs, then make the copies from the stack
-        output.extend(std::iter::repeat_with(|| Op::Run(smdiff_common::Run{byte, len: MAX_RUN_LEN})).take(3));
-
-        let copy_bytes = len - COPY_LIMIT;
-        let mut processed = 0;
-        let mut max_copy_size = COPY_LIMIT;
-        while processed < copy_bytes{
-            let copy_size = max_copy_size.min(copy_bytes - processed).min(MAX_INST_SIZE);
-            let op = Op::Copy(Copy{src: CopySrc::Output, addr: run_start_pos as u64, len: copy_size as u16});
-            output.push(op);
-            processed += copy_size;
-            max_copy_size += copy_size;
-        }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn test_small_run() {
-        let mut output = Vec::new();
-        make_run_ops(0xAA, 120, 0, &mut output);
-        assert_eq!(output.len(), 2);
-        assert!(matches!(output[0], Op::Run(smdiff_common::Run { byte: 0xAA, len: 62 })));
-        assert!(matches!(output[1], Op::Run(smdiff_common::Run { byte: 0xAA, len: 58 })));
-    }
-
-    #[test]
-    fn test_exact_run_limit() {
-        let mut output = Vec::new();
-        let run_limit = (MAX_RUN_LEN as usize) * 6;
-        make_run_ops(0xBB, run_limit, 0, &mut output);
-        assert_eq!(output.len(), 4); // Should fit exactly into 3 maximum-length runs + 1 Copy of all of them.
-        for op in &output[..3] {
-            assert!(matches!(op, Op::Run(smdiff_common::Run { byte: 0xBB, len: MAX_RUN_LEN })));
-        }
-        let copy = &output[3];
-        assert!(matches!(copy, Op::Copy(Copy { src: CopySrc::Output, addr: 0, len: 186 })),"{:?}",copy);
-    }
-
-    #[test]
-    fn test_large_run_needing_copies() {
-        let mut output = Vec::new();
-        let run_limit = (MAX_RUN_LEN as usize) * 6;
-        make_run_ops(0xBB, run_limit+100, 0, &mut output);
-        assert_eq!(output.len(), 5); // Should fit exactly into 3 maximum-length runs + 2 Copy of all of them.
-        for op in &output[..3] {
-            assert!(matches!(op, Op::Run(smdiff_common::Run { byte: 0xBB, len: MAX_RUN_LEN })));
-        }
-        let copy = &output[3];
-        assert!(matches!(copy, Op::Copy(Copy { src: CopySrc::Output, addr: 0, len: 186 })),"{:?}",copy);
-        let copy = &output[4];
-        assert!(matches!(copy, Op::Copy(Copy { src: CopySrc::Output, addr: 0, len: 100 })),"{:?}",copy);
-    }
-
-    #[test]
-    fn test_large_run_needing_copies2() {
-        let mut output = Vec::new();
-        make_run_ops(0xBB, 414, 0, &mut output);
-        assert_eq!(output.len(), 5); // Should fit exactly into 3 maximum-length runs + 2 Copy of all of them.
-        for op in &output[..3] {
-            assert!(matches!(op, Op::Run(smdiff_common::Run { byte: 0xBB, len: MAX_RUN_LEN })));
-        }
-        let copy = &output[3];
-        assert!(matches!(copy, Op::Copy(Copy { src: CopySrc::Output, addr: 0, len: 186 })),"{:?}",copy);
-        let copy = &output[4];
-        assert!(matches!(copy, Op::Copy(Copy { src: CopySrc::Output, addr: 0, len: 42 })),"{:?}",copy);
-    }
-
-    #[test]
-    fn test_zero_length() {
-        let mut output = Vec::new();
-        make_run_ops(0xDD, 0, 0, &mut output);
-        assert!(output.is_empty());
-    }
-}
\ No newline at end of file
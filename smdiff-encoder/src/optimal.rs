@@ -0,0 +1,164 @@
+//! A shortest path parse over the candidate ops found by the encoder.
+//!
+//! The greedy op maker resolves overlapping candidates with a few rules of thumb.
+//! Here every target position is a node, and every way of getting from one position to a later one is an edge priced
+//! with the real size of the op in the patch (OpByte, Size Indicator, address i-varint or Add bytes).
+//! Address costs depend on the previous copy of the same kind, so they are priced against the best path found so far.
+//! The writer starts both addresses over at 0 in each section, so the parse does too. No op crosses a section boundary,
+//! which makes the sections cut exactly where the parse assumed.
+//!
+//! To bound memory the target is parsed in blocks; the best path is fixed at each block boundary.
+use smdiff_common::{copy_addr_size, op_header_size, Copy, CopySrc, Run, MAX_INST_SIZE, MAX_RUN_LEN};
+
+use crate::{encoder::InnerOp, op_maker::make_add_ops, Op};
+
+/// Number of target positions parsed at once.
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// How the best path arrives at a position.
+#[derive(Copy, Clone, Debug)]
+enum Step {
+    Start,
+    /// Add (or a single byte Run) of `trgt[start..pos]`.
+    Literal { start: usize },
+    Copy { from: usize, copy: Copy },
+    Run { from: usize, byte: u8 },
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    cost: u64,
+    step: Step,
+    last_d_addr: u64,
+    last_o_addr: u64,
+}
+
+const UNREACHED: Node = Node { cost: u64::MAX, step: Step::Start, last_d_addr: 0, last_o_addr: 0 };
+
+/// Turns the (possibly overlapping) candidates from `encode_inner` into the cheapest sequence of ops.
+/// The candidates must be sorted by o_pos.
+/// * `trgt` - The target the candidates were found in.
+/// * `candidates` - The matches and runs found by `encode_inner`.
+/// * `section_size` - The output size of each section the ops will be written in.
+pub(crate) fn optimal_parse<'a>(trgt: &'a [u8], candidates: Vec<InnerOp>, section_size: usize) -> Vec<Op<'a>> {
+    let mut out_ops = Vec::new();
+    let mut active: Vec<InnerOp> = Vec::new();
+    let mut candidates = candidates.into_iter().peekable();
+    let mut nodes = Vec::new();
+    let mut steps = Vec::new();
+    let (mut last_d_addr, mut last_o_addr) = (0, 0);
+    let mut block_start = 0;
+    while block_start < trgt.len() {
+        let section_end = (block_start / section_size + 1) * section_size;
+        if block_start % section_size == 0 {
+            (last_d_addr, last_o_addr) = (0, 0);
+        }
+        let block_end = (block_start + BLOCK_SIZE).min(section_end).min(trgt.len());
+        nodes.clear();
+        nodes.resize(block_end - block_start + 1, UNREACHED);
+        nodes[0] = Node { cost: 0, step: Step::Start, last_d_addr, last_o_addr };
+        for pos in block_start..block_end {
+            while let Some(op) = candidates.next_if(|op| *op.o_pos() <= pos) {
+                active.push(op);
+            }
+            active.retain(|op| op.o_pos() + op.len() > pos);
+            let node = nodes[pos - block_start];
+            if node.cost == u64::MAX {
+                continue;
+            }
+            //a literal extends the one we arrived with, so its OpByte is only paid once.
+            let (literal_start, literal_step_cost) = match node.step {
+                Step::Literal { start } => (start, nodes[start - block_start].cost + literal_cost(pos + 1 - start) - node.cost),
+                _ => (pos, literal_cost(1)),
+            };
+            let mut relax = |to: usize, cost: u64, step: Step, last_d_addr: u64, last_o_addr: u64| {
+                let next = &mut nodes[to - block_start];
+                if node.cost + cost < next.cost {
+                    *next = Node { cost: node.cost + cost, step, last_d_addr, last_o_addr };
+                }
+            };
+            relax(pos + 1, literal_step_cost, Step::Literal { start: literal_start }, node.last_d_addr, node.last_o_addr);
+            for op in active.iter() {
+                let offset = pos - op.o_pos();
+                let remaining = (op.o_pos() + op.len() - pos).min(block_end - pos);
+                match *op {
+                    InnerOp::MatchSrc { start, .. } | InnerOp::MatchTrgt { start, .. } => {
+                        let src = if matches!(op, InnerOp::MatchSrc { .. }) { CopySrc::Dict } else { CopySrc::Output };
+                        let addr = (start + offset) as u64;
+                        let (last_d, last_o) = match src {
+                            CopySrc::Dict => (addr, node.last_o_addr),
+                            CopySrc::Output => (node.last_d_addr, addr),
+                        };
+                        let addr_cost = match src {
+                            CopySrc::Dict => copy_addr_size(node.last_d_addr, addr),
+                            CopySrc::Output => copy_addr_size(node.last_o_addr, addr),
+                        };
+                        for len in copy_lens(remaining) {
+                            let copy = Copy { src, addr, len: len as u16 };
+                            relax(pos + len, (op_header_size(len as u16) + addr_cost) as u64, Step::Copy { from: pos, copy }, last_d, last_o);
+                        }
+                    },
+                    InnerOp::Run { byte, o_pos, .. } => {
+                        let len = remaining.min(MAX_RUN_LEN as usize);
+                        relax(pos + len, 2, Step::Run { from: pos, byte }, node.last_d_addr, node.last_o_addr);
+                        //the part of the run already written can be copied, which doubles what one op covers.
+                        if offset > 0 {
+                            let addr = o_pos as u64;
+                            let len = remaining.min(offset).min(MAX_INST_SIZE);
+                            let cost = op_header_size(len as u16) + copy_addr_size(node.last_o_addr, addr);
+                            let copy = Copy { src: CopySrc::Output, addr, len: len as u16 };
+                            relax(pos + len, cost as u64, Step::Copy { from: pos, copy }, node.last_d_addr, addr);
+                        }
+                    },
+                }
+            }
+        }
+        //walk the best path back to the start of the block
+        steps.clear();
+        let mut pos = block_end;
+        while pos > block_start {
+            let step = nodes[pos - block_start].step;
+            steps.push((pos, step));
+            pos = match step {
+                Step::Literal { start } => start,
+                Step::Copy { from, .. } | Step::Run { from, .. } => from,
+                Step::Start => unreachable!("Every position is reachable with a literal"),
+            };
+        }
+        for &(end, step) in steps.iter().rev() {
+            match step {
+                Step::Literal { start } => make_add_ops(&trgt[start..end], &mut out_ops),
+                Step::Copy { copy, .. } => out_ops.push(Op::Copy(copy)),
+                Step::Run { from, byte } => out_ops.push(Op::Run(Run { byte, len: (end - from) as u8 })),
+                Step::Start => unreachable!(),
+            }
+        }
+        let last = nodes[block_end - block_start];
+        last_d_addr = last.last_d_addr;
+        last_o_addr = last.last_o_addr;
+        block_start = block_end;
+    }
+    out_ops
+}
+
+/// Size of the ops `make_add_ops` emits for `len` literal bytes.
+fn literal_cost(len: usize) -> u64 {
+    if len == 1 {
+        return 2;
+    }
+    let full = len / MAX_INST_SIZE;
+    let rest = len % MAX_INST_SIZE;
+    let mut cost = full * (op_header_size(MAX_INST_SIZE as u16) + MAX_INST_SIZE);
+    if rest > 0 {
+        cost += op_header_size(rest as u16) + rest;
+    }
+    cost as u64
+}
+
+/// The copy lengths worth trying with `remaining` bytes of match left.
+/// The longest possible, and the longest that still fits a single byte Size Indicator.
+fn copy_lens(remaining: usize) -> impl Iterator<Item = usize> {
+    let longest = remaining.min(MAX_INST_SIZE);
+    let short = (longest > MAX_RUN_LEN as usize).then_some(MAX_RUN_LEN as usize);
+    std::iter::once(longest).chain(short)
+}
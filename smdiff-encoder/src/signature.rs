@@ -0,0 +1,279 @@
+//! Signature based encoding (librsync style).
+//!
+//! When the dictionary lives on a different machine than the target, the dictionary owner computes a [`Signature`]:
+//! one weak rolling checksum and one strong hash per fixed size block of the dictionary.
+//! The signature is sent to the machine with the target, which calls [`encode_from_signature`] to produce a regular SMDIFF patch.
+//! That patch only contains block aligned `CopySrc::Dict` operations plus Add/Run operations for everything that did not match.
+//!
+//! Since only whole blocks can match, the patches are larger than what `encode` would produce with the full dictionary.
+//! Smaller block sizes find more matches, but make the signature larger.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use smdiff_common::{read_u_varint, write_u_varint, MAX_INST_SIZE, MAX_WIN_SIZE};
+use smdiff_writer::make_sections;
+
+use crate::{encoder::InnerOp, hasher::{calculate_block_checksum, update_block_checksum}, op_maker::translate_inner_ops, writer::{adaptive_section_writer, section_writer}, EncoderConfig};
+
+/// Magic bytes at the start of a serialized signature.
+const SIGNATURE_MAGIC: [u8; 4] = *b"SMSG";
+/// Number of bytes kept from the strong hash of each block.
+pub const STRONG_HASH_LEN: usize = 16;
+/// Default block size used for signatures.
+pub const DEFAULT_BLOCK_SIZE: usize = 2048;
+/// Smallest block size a signature may use.
+pub const MIN_BLOCK_SIZE: usize = 16;
+
+/// The checksums for a single block of the dictionary.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockSignature {
+    /// Rolling checksum of the block.
+    pub weak: u32,
+    /// Truncated blake3 hash of the block.
+    pub strong: [u8; STRONG_HASH_LEN],
+}
+
+/// Block signature of a dictionary (source) file.
+/// * block_size: The size of every block, except possibly the last one.
+/// * src_len: The total length of the dictionary.
+/// * blocks: The checksums of each block, in dictionary order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    block_size: usize,
+    src_len: u64,
+    blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    /// Computes the signature of the given dictionary.
+    /// * `dict` - The dictionary (source) to compute the signature for.
+    /// * `block_size` - The size of each block. Clamped to MIN_BLOCK_SIZE..=MAX_INST_SIZE.
+    pub fn new<R: Read>(dict: &mut R, block_size: usize) -> std::io::Result<Self> {
+        let block_size = block_size.clamp(MIN_BLOCK_SIZE, MAX_INST_SIZE);
+        let mut blocks = Vec::new();
+        let mut src_len = 0u64;
+        let mut buffer = vec![0u8; block_size];
+        loop {
+            let filled = read_block(dict, &mut buffer)?;
+            if filled == 0 {
+                break;
+            }
+            src_len += filled as u64;
+            blocks.push(block_signature(&buffer[..filled]));
+            if filled < block_size {
+                break;
+            }
+        }
+        Ok(Self { block_size, src_len, blocks })
+    }
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+    /// The length of the dictionary this signature was made from.
+    pub fn src_len(&self) -> u64 {
+        self.src_len
+    }
+    pub fn blocks(&self) -> &[BlockSignature] {
+        &self.blocks
+    }
+    /// Length of the block at the given index.
+    fn block_len(&self, idx: usize) -> usize {
+        let start = (idx * self.block_size) as u64;
+        (self.src_len - start).min(self.block_size as u64) as usize
+    }
+    /// Writes the signature to the writer.
+    ///
+    /// Layout: Magic(4 bytes) | Block Size (u-varint) | Src Len (u-varint) | Blocks (weak u32 le + strong hash)...
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&SIGNATURE_MAGIC)?;
+        write_u_varint(writer, self.block_size as u64)?;
+        write_u_varint(writer, self.src_len)?;
+        for block in self.blocks.iter() {
+            writer.write_all(&block.weak.to_le_bytes())?;
+            writer.write_all(&block.strong)?;
+        }
+        Ok(())
+    }
+    /// Reads a signature that was written with [`Signature::write`].
+    pub fn read<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != SIGNATURE_MAGIC {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a smdiff signature"));
+        }
+        let block_size = read_u_varint(reader)? as usize;
+        if !(MIN_BLOCK_SIZE..=MAX_INST_SIZE).contains(&block_size) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid signature block size {}", block_size)));
+        }
+        let src_len = read_u_varint(reader)?;
+        let num_blocks = src_len.div_ceil(block_size as u64);
+        //the length is not trusted until the blocks are actually read
+        let mut blocks = Vec::with_capacity(num_blocks.min(1 << 20) as usize);
+        for _ in 0..num_blocks {
+            let mut weak = [0u8; 4];
+            reader.read_exact(&mut weak)?;
+            let mut strong = [0u8; STRONG_HASH_LEN];
+            reader.read_exact(&mut strong)?;
+            blocks.push(BlockSignature { weak: u32::from_le_bytes(weak), strong });
+        }
+        Ok(Self { block_size, src_len, blocks })
+    }
+}
+
+fn read_block<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn strong_hash(data: &[u8]) -> [u8; STRONG_HASH_LEN] {
+    let mut strong = [0u8; STRONG_HASH_LEN];
+    strong.copy_from_slice(&blake3::hash(data).as_bytes()[..STRONG_HASH_LEN]);
+    strong
+}
+
+fn block_signature(data: &[u8]) -> BlockSignature {
+    BlockSignature { weak: calculate_block_checksum(data), strong: strong_hash(data) }
+}
+
+/// Encodes the target against a dictionary that is only known through its signature.
+/// # Arguments
+/// * `signature` - The signature of the dictionary the patch will be applied to.
+/// * `output` - The target file to encode.
+/// * `writer` - The writer to write the encoded data to.
+/// * `config` - Only the `format`, `adaptive_format`, `sec_comp` and `output_segment_size` settings are used.
+/// # Errors
+/// Returns an error if there was an issue reading the target, or writing the encoded data.
+pub fn encode_from_signature<R: Read, W: Write>(signature: &Signature, output: &mut R, writer: &mut W, config: &EncoderConfig) -> std::io::Result<()> {
+    let mut trgt = Vec::new();
+    output.read_to_end(&mut trgt)?;
+    let inner_ops = match_signature(signature, &trgt);
+    let ops = translate_inner_ops(&trgt, inner_ops);
+    let segment_size = config.output_segment_size.clamp(MAX_INST_SIZE, MAX_WIN_SIZE);
+    let mut win_data = Vec::new();
+    for (seg_ops, mut header) in make_sections(&ops, segment_size) {
+        header.format = config.format;
+        if config.adaptive_format {
+            adaptive_section_writer(&config.sec_comp, header, writer, seg_ops, &mut win_data)?;
+        } else {
+            section_writer(&config.sec_comp, header, writer, seg_ops, &mut win_data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds all the whole blocks of the signature in the target.
+fn match_signature(signature: &Signature, trgt: &[u8]) -> Vec<InnerOp> {
+    let block_size = signature.block_size;
+    let num_full = (signature.src_len / block_size as u64) as usize;
+    //weak hash -> block indices. Only full blocks can be found by the rolling checksum.
+    let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (idx, block) in signature.blocks[..num_full].iter().enumerate() {
+        table.entry(block.weak).or_default().push(idx);
+    }
+    let mut ops: Vec<InnerOp> = Vec::new();
+    let mut pos = 0;
+    let mut hash = None;
+    while pos + block_size <= trgt.len() {
+        let weak = *hash.get_or_insert_with(|| calculate_block_checksum(&trgt[pos..pos + block_size]));
+        if let Some(candidates) = table.get(&weak) {
+            let strong = strong_hash(&trgt[pos..pos + block_size]);
+            //prefer the block that continues the last copy, so the copies can be joined.
+            let next_idx = ops.last().and_then(|op| match op {
+                InnerOp::MatchSrc { start, length, o_pos } if o_pos + length == pos => Some((start + length) / block_size),
+                _ => None,
+            });
+            let found = candidates.iter()
+                .filter(|idx| signature.blocks[**idx].strong == strong)
+                .min_by_key(|idx| Some(**idx) != next_idx)
+                .copied();
+            if let Some(idx) = found {
+                push_block_match(&mut ops, idx * block_size, block_size, pos);
+                pos += block_size;
+                hash = None;
+                continue;
+            }
+        }
+        if pos + block_size < trgt.len() {
+            hash = Some(update_block_checksum(weak, trgt[pos], trgt[pos + block_size], block_size));
+        }
+        pos += 1;
+    }
+    //a short last block can only be found at the very end of the target.
+    if num_full < signature.blocks.len() {
+        let last_len = signature.block_len(num_full);
+        if trgt.len() >= last_len && trgt.len() - last_len >= pos {
+            let tail_start = trgt.len() - last_len;
+            let tail = &trgt[tail_start..];
+            if block_signature(tail) == signature.blocks[num_full] {
+                push_block_match(&mut ops, num_full * block_size, last_len, tail_start);
+            }
+        }
+    }
+    ops
+}
+
+fn push_block_match(ops: &mut Vec<InnerOp>, src_start: usize, len: usize, o_pos: usize) {
+    if let Some(InnerOp::MatchSrc { start, length, o_pos: last_o_pos }) = ops.last_mut() {
+        if *start + *length == src_start && *last_o_pos + *length == o_pos {
+            *length += len;
+            return;
+        }
+    }
+    ops.push(InnerOp::MatchSrc { start: src_start, length: len, o_pos });
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use std::io::Cursor;
+    use crate::test_util::random_bytes;
+
+    #[test]
+    fn test_signature_round_trip() {
+        let src = random_bytes(1, 5000);
+        let sig = Signature::new(&mut Cursor::new(&src), 64).unwrap();
+        assert_eq!(sig.blocks().len(), 79);
+        let mut bytes = Vec::new();
+        sig.write(&mut bytes).unwrap();
+        let read = Signature::read(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(sig, read);
+    }
+
+    #[test]
+    fn test_read_truncated_huge_signature() {
+        //a header claiming an enormous source must fail on the missing blocks, not allocate them all up front.
+        let mut bytes = SIGNATURE_MAGIC.to_vec();
+        write_u_varint(&mut bytes, 64).unwrap();
+        write_u_varint(&mut bytes, u64::MAX).unwrap();
+        let err = Signature::read(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_encode_from_signature() {
+        let src = random_bytes(2, 10_000);
+        let mut trgt = Vec::new();
+        trgt.extend_from_slice(b"prefix bytes!");
+        trgt.extend_from_slice(&src[..4000]);
+        trgt.extend_from_slice(&random_bytes(3, 300));
+        trgt.extend_from_slice(&src[4000..]);
+        let sig = Signature::new(&mut Cursor::new(&src), 128).unwrap();
+        let ops = match_signature(&sig, &trgt);
+        //two aligned runs of blocks, the short last block joins the second run.
+        assert_eq!(ops.len(), 2, "{:?}", ops);
+        let mut patch = Vec::new();
+        encode_from_signature(&sig, &mut Cursor::new(&trgt), &mut patch, &EncoderConfig::default()).unwrap();
+        assert!(patch.len() < 1000, "patch too large: {}", patch.len());
+        let mut sink = Cursor::new(Vec::new());
+        smdiff_decoder::apply_patch(&mut Cursor::new(patch), Some(&mut Cursor::new(src)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), trgt);
+    }
+}
@@ -0,0 +1,188 @@
+//! Cheap similarity estimates between files, used to pick a dictionary without encoding against every candidate.
+//!
+//! A [`Sketch`] keeps a content-defined sample of the rolling hashes of a file (about 1 in 32 positions).
+//! Since the samples are chosen by the hash value and not the position, two files sharing a region sample the same hashes within it.
+//! The fraction of the target's samples found in a candidate estimates how much of the target can be copied from it.
+use std::io::{Read, Write};
+
+use smdiff_common::{read_u_varint, write_u_varint};
+
+use crate::hasher::{calculate_large_checksum, update_large_checksum_fwd};
+
+/// Size of the hash window. Same as the source matcher.
+const SKETCH_WINDOW: usize = 9;
+/// A hash is sampled when these bits are all zero (1 in 32).
+const SAMPLE_MASK: u64 = 0x1F;
+
+const SKETCH_MAGIC: [u8; 4] = *b"SMSK";
+
+/// A sample of the rolling hashes of a file.
+/// * len: The length of the file the sketch was made from.
+/// * hashes: Sorted and deduplicated sampled hashes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sketch {
+    len: u64,
+    hashes: Vec<u64>,
+}
+
+impl Sketch {
+    pub fn new(data: &[u8]) -> Self {
+        let mut hashes = Vec::new();
+        if data.len() >= SKETCH_WINDOW {
+            let mut hash = calculate_large_checksum(&data[..SKETCH_WINDOW]);
+            for pos in 0..=data.len() - SKETCH_WINDOW {
+                if pos > 0 {
+                    hash = update_large_checksum_fwd(hash, data[pos - 1], data[pos + SKETCH_WINDOW - 1]);
+                }
+                let h = mix(hash);
+                if h & SAMPLE_MASK == 0 {
+                    hashes.push(h);
+                }
+            }
+        }
+        hashes.sort_unstable();
+        hashes.dedup();
+        Self { len: data.len() as u64, hashes }
+    }
+    /// Reads the whole reader and sketches it.
+    pub fn from_reader<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(Self::new(&data))
+    }
+    /// The length of the file this sketch was made from.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Whether any hash was sampled. Files shorter than a few hundred bytes often have none,
+    /// their similarity then only says whether the lengths are equal.
+    pub fn has_samples(&self) -> bool {
+        !self.hashes.is_empty()
+    }
+    /// The fraction (0.0..=1.0) of this sketch's samples that are also in `other`.
+    /// For a target and a candidate dictionary, this estimates how much of the target can be copied from the dictionary.
+    pub fn containment(&self, other: &Sketch) -> f32 {
+        if self.hashes.is_empty() {
+            //too small to sample, only an identical file is known to be a match.
+            return if self.len == other.len && self.hashes == other.hashes && self.len > 0 { 1.0 } else { 0.0 };
+        }
+        self.shared(other) as f32 / self.hashes.len() as f32
+    }
+    /// The Jaccard similarity (0.0..=1.0) of the two sketches.
+    pub fn similarity(&self, other: &Sketch) -> f32 {
+        if self.hashes.is_empty() && other.hashes.is_empty() {
+            return if self.len == other.len { 1.0 } else { 0.0 };
+        }
+        let shared = self.shared(other);
+        shared as f32 / (self.hashes.len() + other.hashes.len() - shared) as f32
+    }
+    fn shared(&self, other: &Sketch) -> usize {
+        let (a, b) = (&self.hashes, &other.hashes);
+        let (mut i, mut j, mut shared) = (0, 0, 0);
+        while i < a.len() && j < b.len() {
+            match a[i].cmp(&b[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    shared += 1;
+                    i += 1;
+                    j += 1;
+                },
+            }
+        }
+        shared
+    }
+    /// Writes the sketch, so sketches of stored artifacts can be computed once and kept.
+    ///
+    /// Layout: Magic(4 bytes) | Len (u-varint) | Num Hashes (u-varint) | Hashes (u64 le)...
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&SKETCH_MAGIC)?;
+        write_u_varint(writer, self.len)?;
+        write_u_varint(writer, self.hashes.len() as u64)?;
+        for h in self.hashes.iter() {
+            writer.write_all(&h.to_le_bytes())?;
+        }
+        Ok(())
+    }
+    pub fn read<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != SKETCH_MAGIC {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a smdiff sketch"));
+        }
+        let len = read_u_varint(reader)?;
+        let num_hashes = read_u_varint(reader)? as usize;
+        let mut hashes = Vec::with_capacity(num_hashes.min(1 << 20));
+        for _ in 0..num_hashes {
+            let mut h = [0u8; 8];
+            reader.read_exact(&mut h)?;
+            hashes.push(u64::from_le_bytes(h));
+        }
+        if hashes.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Sketch hashes are not sorted"));
+        }
+        Ok(Self { len, hashes })
+    }
+}
+
+/// The rolling hash has weak low bits for short windows, so we mix before sampling.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h
+}
+
+/// A ranked candidate dictionary.
+/// * index: The position of the candidate in the list given to [`rank_candidates`].
+/// * containment: The estimated fraction of the target that can be copied from this candidate.
+/// * est_new_bytes: The estimated number of target bytes that would need to be added as new data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub index: usize,
+    pub containment: f32,
+    pub est_new_bytes: u64,
+}
+
+/// Ranks the candidates by how small a patch against each one is expected to be, best first.
+/// Ties are broken by the smaller candidate, since it is cheaper to match against.
+pub fn rank_candidates(target: &Sketch, candidates: &[Sketch]) -> Vec<Candidate> {
+    let mut ranked: Vec<Candidate> = candidates.iter().enumerate().map(|(index, c)| {
+        let containment = target.containment(c);
+        let est_new_bytes = ((1.0 - containment) * target.len as f32).round() as u64;
+        Candidate { index, containment, est_new_bytes }
+    }).collect();
+    ranked.sort_by(|a, b| {
+        b.containment.total_cmp(&a.containment)
+            .then(candidates[a.index].len.cmp(&candidates[b.index].len))
+            .then(a.index.cmp(&b.index))
+    });
+    ranked
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::test_util::random_bytes;
+
+    #[test]
+    fn test_rank_candidates() {
+        let base = random_bytes(1, 20_000);
+        let mut trgt = base.clone();
+        trgt[10_000..12_000].copy_from_slice(&random_bytes(2, 2000));
+        let close = base.clone();
+        let half = base[..10_000].to_vec();
+        let unrelated = random_bytes(3, 20_000);
+        let sketches = [Sketch::new(&unrelated), Sketch::new(&half), Sketch::new(&close)];
+        let ranked = rank_candidates(&Sketch::new(&trgt), &sketches);
+        assert_eq!(ranked.iter().map(|c| c.index).collect::<Vec<_>>(), vec![2, 1, 0]);
+        assert!(ranked[0].containment > 0.8, "{:?}", ranked);
+        assert!(ranked[2].containment < 0.05, "{:?}", ranked);
+        let mut bytes = Vec::new();
+        sketches[1].write(&mut bytes).unwrap();
+        assert_eq!(Sketch::read(&mut std::io::Cursor::new(bytes)).unwrap(), sketches[1]);
+    }
+}
@@ -1,5 +1,9 @@
 
-use crate::{hasher::*, hashmap::BasicHashTable,Ranger};
+use std::sync::Arc;
+
+use smdiff_common::copy_addr_size;
+
+use crate::{hasher::*, hashmap::BasicHashTable, index::DictionaryIndex, suffix_array::SuffixArray, Ranger};
 
 struct InnerConfig{
     l_step:usize,
@@ -11,10 +15,11 @@ struct InnerConfig{
 
 pub(crate) struct SrcMatcher{
     pub(crate) l_step:usize,
-    pub(crate) fwd_hash: usize,
+    pub(crate) fwd_hash: u64,
     pub(crate) fwd_pos: usize,
     pub(crate) max_fwd_hash_pos:usize,
-    table: BasicHashTable,
+    //Shared with a DictionaryIndex, or owned (and only then written to).
+    table: Arc<BasicHashTable>,
     //Window calc state
     src_len:usize,
     trgt_len:usize,
@@ -23,42 +28,65 @@ pub(crate) struct SrcMatcher{
     cur_window_end:usize,
     pub(crate) next_hash_pos:usize,
     max_match_pos:usize,
+    suffix_array: Option<SuffixArray>,
 }
 
 impl SrcMatcher{
 
     ///Returns (src_pos, pre_match, post_match) post_match *includes* the hash_win_len.
-    pub fn find_best_src_match(&mut self,src:&[u8],trgt:&[u8])->Option<(usize,usize,usize)>{
-        let table_pos = self.table.get(self.fwd_hash)?;
-        let src_pos = self.table_to_abs_pos(table_pos);
-        if let Some((pre_match,post_match)) = extend_src_match(src, src_pos, trgt, self.fwd_pos) {
-            let total_post_match = post_match + 9;
-            return Some((src_pos,pre_match,total_post_match));
+    /// * `last_d` - (src start, o_pos) of the previous src match. Copy addresses are encoded relative to the previous one,
+    ///   so a candidate close to it (or continuing its alignment) can beat a slightly longer one far away.
+    pub fn find_best_src_match(&mut self,src:&[u8],trgt:&[u8],last_d:(usize,usize),min_match:usize)->Option<(usize,usize,usize)>{
+        let (last_d_addr,last_d_o_pos) = last_d;
+        //the src position that keeps the alignment of the previous match
+        let aligned = (last_d_addr + self.fwd_pos).checked_sub(last_d_o_pos).filter(|p| p + 9 <= src.len());
+        let found = match self.suffix_array.as_ref(){
+            Some(sa) => sa.longest_match_near(src, &trgt[self.fwd_pos..], aligned.unwrap_or(last_d_addr)).filter(|(_,len)| *len >= 9).map(|(pos,_)|pos),
+            None => self.table.get(self.fwd_hash).map(|table_pos| self.table_to_abs_pos(table_pos)),
+        }?;
+        let mut best: Option<(usize,usize,usize)> = None;
+        let mut best_key = (false, isize::MIN, std::cmp::Reverse(u64::MAX));
+        //the aligned position only competes with a match that was found, finding matches between sparse changes is left to `approximate_matches`.
+        for src_pos in std::iter::once(found).chain(aligned.filter(|a| *a != found)) {
+            if let Some((pre_match,post_match)) = extend_src_match(src, src_pos, trgt, self.fwd_pos) {
+                let total_post_match = post_match + 9;
+                let start = (src_pos - pre_match) as u64;
+                let score = (pre_match + total_post_match) as isize - copy_addr_size(last_d_addr as u64, start) as isize;
+                let key = (total_post_match >= min_match, score, std::cmp::Reverse(start.abs_diff(last_d_addr as u64)));
+                if key > best_key {
+                    best_key = key;
+                    best = Some((src_pos,pre_match,total_post_match));
+                }
+            }
+        }
+        best
+    }
+    /// Builds a matcher that looks up matches in a prebuilt index instead of hashing the source.
+    /// The source must be the one the index was built from (checked by the caller).
+    pub(crate) fn from_index(index:&DictionaryIndex,trgt_start_pos:usize,trgt:&[u8])->SrcMatcher{
+        let (fwd_hash,fwd_pos,max_fwd_hash_pos) = start_fwd_hash(trgt, trgt_start_pos);
+        let src_len = index.src_len() as usize;
+        let l_step = index.l_step();
+        SrcMatcher{
+            table: index.table(), src_len, trgt_len: trgt.len(),
+            max_end_pos: align(src_len.saturating_sub(9),l_step),
+            max_fwd_hash_pos,
+            fwd_hash,
+            fwd_pos,
+            l_step,
+            half_win_size: src_len,
+            cur_window_end: 0,
+            //the whole source is already hashed.
+            next_hash_pos: usize::MAX,
+            max_match_pos: trgt_start_pos,
+            suffix_array: None,
         }
-        None
     }
     /// Positions returned from the table are in table space, this converts them to absolute start positions.
     /// In other words, the table_pos is multiplied by l_step to get the absolute position.
     pub(crate) fn table_to_abs_pos(&self, table_pos:usize)->usize{
         table_pos * self.l_step
     }
-    fn abs_to_table_pos(&self, abs_pos:usize)->usize{
-        debug_assert!(abs_pos % self.l_step == 0, "abs_pos({}) is !divisible by l_step({})",abs_pos,self.l_step);
-        abs_pos / self.l_step
-    }
-    fn store(&mut self, hash:usize, abs_pos:usize){
-        debug_assert!(abs_pos % self.l_step == 0);
-        let table_pos = self.abs_to_table_pos(abs_pos);
-        match self.table.insert(hash, table_pos){
-            Ok(None) => {},
-            Ok(Some(_prev)) => {
-                //self.chain.insert(hash, table_pos, prev);
-            },
-            Err((_old_hash,_prev_pos)) => {
-                //self.chain.insert(old_hash, table_pos, prev_pos);
-            }
-        }
-    }
 }
 
 // I used to add them in forward order, and on the test files actually got better matches
@@ -89,29 +117,48 @@ pub(crate) fn add_start_positions_to_matcher(matcher: &mut SrcMatcher, cur_o_pos
             matcher.next_hash_pos = cur_o_pos + (matcher.half_win_size);
         }
 
-        if matcher.l_step >= 9 {
-            for pos in range.step_by(matcher.l_step).rev() {
-                let hash = calculate_large_checksum(&src[pos..pos + 9]);
-                matcher.store(hash, pos)
+        let l_step = matcher.l_step;
+        let table = Arc::get_mut(&mut matcher.table).expect("A shared table is never hashed in to");
+        hash_src_range(table, l_step, src, range);
+    }
+}
+
+/// Stores the hash of every `l_step` position of `range` in the table, in reverse order.
+pub(crate) fn hash_src_range(table: &mut BasicHashTable, l_step: usize, src: &[u8], range: std::ops::Range<usize>) {
+    let mut store = |hash: u64, abs_pos: usize| {
+        debug_assert!(abs_pos.is_multiple_of(l_step), "abs_pos({}) is !divisible by l_step({})",abs_pos,l_step);
+        match table.insert(hash, abs_pos / l_step){
+            Ok(None) => {},
+            Ok(Some(_prev)) => {
+                //self.chain.insert(hash, table_pos, prev);
+            },
+            Err((_old_hash,_prev_pos)) => {
+                //self.chain.insert(old_hash, table_pos, prev_pos);
             }
-        }else{
-            let aligned_last_hash = align(range.end.saturating_sub(9),matcher.l_step);
-            let mut hash = calculate_large_checksum(&src[aligned_last_hash..range.end]);
-            for pos in (range.start..aligned_last_hash).rev() {
-                hash = update_large_checksum_bwd(hash, src[pos+9], src[pos]);
-                if pos % matcher.l_step == 0 {
-                    matcher.store(hash, pos);
-                }
+        }
+    };
+    if l_step >= 9 {
+        for pos in range.step_by(l_step).rev() {
+            let hash = calculate_large_checksum(&src[pos..pos + 9]);
+            store(hash, pos)
+        }
+    }else{
+        let aligned_last_hash = align(range.end.saturating_sub(9),l_step);
+        let mut hash = calculate_large_checksum(&src[aligned_last_hash..range.end]);
+        for pos in (range.start..aligned_last_hash).rev() {
+            hash = update_large_checksum_bwd(hash, src[pos+9], src[pos]);
+            if pos % l_step == 0 {
+                store(hash, pos);
             }
-            // for pos in (0..aligned_last_hash).rev().step_by(matcher.l_step).skip(1) {
-            //     for inner_pos in (0..matcher.l_step).rev() {
-            //         let current_pos = pos + inner_pos;
-            //         hash = update_large_checksum_bwd(hash, src[current_pos + 9], src[current_pos]);
-            //     }
-            //     matcher.store(hash, pos);
-            // }
-
         }
+        // for pos in (0..aligned_last_hash).rev().step_by(l_step).skip(1) {
+        //     for inner_pos in (0..l_step).rev() {
+        //         let current_pos = pos + inner_pos;
+        //         hash = update_large_checksum_bwd(hash, src[current_pos + 9], src[current_pos]);
+        //     }
+        //     store(hash, pos);
+        // }
+
     }
 }
 const DEFAULT_SRC_WIN_SIZE: usize = 1 << 26;
@@ -119,6 +166,20 @@ const MIN_SRC_WIN_SIZE: usize = 1 << 20;
 const _HASH_CHUNK_SIZE: usize = 1 << 23;
 //const DEFAULT_PREV_SIZE: usize = 1 << 18;
 
+/// How the SrcMatcher finds matches in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SrcMatcherBackend{
+    /// Hashes every `l_step` source position within a window that follows the target position.
+    /// Fast and light on memory, but can miss matches.
+    #[default]
+    Hash,
+    /// Builds a suffix array over the whole source and looks up the longest match at every target position.
+    /// `l_step` and `max_src_win_size` are ignored.
+    /// Much slower to build and uses about 20 bytes of memory per source byte while building (on 64 bit targets), meant for offline encodes.
+    /// Sources of 4 GiB or more do not fit the array's u32 positions and fall back to `Hash`.
+    SuffixArray,
+}
+
 ///Configuration for the SrcMatcher.
 #[derive(Debug, Clone)]
 pub struct SrcMatcherConfig{
@@ -130,6 +191,8 @@ pub struct SrcMatcherConfig{
     /// Larger values consider more matches, but might hash excessively slowing down encoder.
     /// Leave blank for dynamic calculation.
     pub max_src_win_size: Option<usize>,
+    /// How matches are found, see `set_backend`.
+    backend: SrcMatcherBackend,
 }
 
 impl Default for SrcMatcherConfig {
@@ -142,7 +205,7 @@ impl SrcMatcherConfig {
     /// l_step: How much to advance the Large Hash between storing a src hash.
     /// max_src_win_size: The maximum size of the source window.
     pub fn new(l_step: usize,max_src_win_size:Option<usize>) -> Self {
-        Self { l_step, max_src_win_size}
+        Self { l_step, max_src_win_size, backend: SrcMatcherBackend::Hash}
     }
     ///Creates a new SrcMatcherConfig with the given compression level.
     /// level: The compression level to use. Must be between 0 and 9.
@@ -150,7 +213,13 @@ impl SrcMatcherConfig {
     pub fn comp_level(level:usize)->Self{
         assert!(level <= 9);
         let l_step = Ranger::new(0..10, 26..=2).map(level);
-        Self { l_step, max_src_win_size: None}
+        Self { l_step, max_src_win_size: None, backend: SrcMatcherBackend::Hash}
+    }
+    ///Sets how matches are found in the source.
+    /// Default Value: Hash
+    pub fn set_backend(mut self, backend: SrcMatcherBackend) -> Self {
+        self.backend = backend;
+        self
     }
     fn make_inner_config(&mut self, src_len: usize,trgt_len:usize)->InnerConfig{
         self.l_step = self.l_step.max(1);
@@ -173,15 +242,12 @@ impl SrcMatcherConfig {
     pub(crate) fn build(&mut self,src:&[u8],trgt_start_pos:usize,trgt:&[u8])->SrcMatcher{
         let trgt_len = trgt.len();
         let InnerConfig { l_step, src_len, trgt_len, src_win_size, max_end_pos } = self.make_inner_config(src.len(),trgt_len);
-        let max_fwd_hash_pos = trgt.len().saturating_sub(9);
-        let (fwd_hash,fwd_pos) = if trgt_start_pos < max_fwd_hash_pos {
-            (calculate_large_checksum(&trgt[trgt_start_pos..trgt_start_pos+9]),trgt_start_pos)
-        }else{
-            (0,max_fwd_hash_pos)
-        };
+        let (fwd_hash,fwd_pos,max_fwd_hash_pos) = start_fwd_hash(trgt, trgt_start_pos);
         //regardless of win_size given, we do not need to store more than the entire src file.
         let table_win_effective = (src_len.next_power_of_two() >> 1).min(src_win_size);
-        let table = BasicHashTable::new(table_win_effective/l_step, false);
+        let use_suffix_array = self.backend == SrcMatcherBackend::SuffixArray && SuffixArray::fits(src_len);
+        let table_size = if use_suffix_array { 1 } else { table_win_effective/l_step };
+        let table = Arc::new(BasicHashTable::new(table_size, false));
         let mut matcher = SrcMatcher{
             table, src_len, trgt_len, max_end_pos,max_fwd_hash_pos,
             fwd_hash,
@@ -191,15 +257,32 @@ impl SrcMatcherConfig {
             cur_window_end: 0,
             next_hash_pos: 0,
             max_match_pos: trgt_start_pos,
+            suffix_array: None,
         };
+        if use_suffix_array {
+            //nothing is ever hashed, the array covers the whole source.
+            matcher.suffix_array = Some(SuffixArray::new(src));
+            matcher.next_hash_pos = usize::MAX;
+            return matcher;
+        }
         //prefill with hash start positions.
         add_start_positions_to_matcher(&mut matcher, trgt_start_pos, src);
         matcher
     }
 }
 
+/// Returns (fwd_hash, fwd_pos, max_fwd_hash_pos) for a matcher starting at `trgt_start_pos`.
+fn start_fwd_hash(trgt:&[u8],trgt_start_pos:usize)->(u64,usize,usize){
+    let max_fwd_hash_pos = trgt.len().saturating_sub(9);
+    if trgt_start_pos < max_fwd_hash_pos {
+        (calculate_large_checksum(&trgt[trgt_start_pos..trgt_start_pos+9]),trgt_start_pos,max_fwd_hash_pos)
+    }else{
+        (0,max_fwd_hash_pos,max_fwd_hash_pos)
+    }
+}
+
 #[inline]
-fn align(pos:usize,l_step:usize)->usize{
+pub(crate) fn align(pos:usize,l_step:usize)->usize{
     pos - (pos % l_step)
 }
 
@@ -222,16 +305,19 @@ pub(crate) fn extend_src_match(src:&[u8],src_start:usize,trgt:&[u8],trgt_start:u
     }else{0};
 
     // Extend forward
-    let src_end = src_start + 9;
-    let trgt_end = trgt_start + 9;
-    let src_remain = src.len() - src_end;
-    let trgt_remain = trgt.len() - trgt_end;
-    let post_match = (0..src_remain.min(trgt_remain)).take_while(|&i| {
-        src[src_end + i] == trgt[trgt_end + i]
-    }).count();
+    let post_match = forward_match_len(src, src_start + 9, trgt, trgt_start + 9);
     Some((pre_match,post_match))
 }
 
+///Returns how many bytes match going forward from the given positions.
+pub(crate) fn forward_match_len(src:&[u8],src_start:usize,trgt:&[u8],trgt_start:usize)->usize{
+    let src_remain = src.len().saturating_sub(src_start);
+    let trgt_remain = trgt.len().saturating_sub(trgt_start);
+    (0..src_remain.min(trgt_remain)).take_while(|&i| {
+        src[src_start + i] == trgt[trgt_start + i]
+    }).count()
+}
+
 //Thought I could cut down encoding time (which it does), but this misses lots of good matches on dissimilar files of similar length
 #[allow(dead_code)]
 fn calculate_default_win_size(src_len: usize, trgt_len: usize,max_win_size:Option<usize>) -> usize {
@@ -0,0 +1,153 @@
+//! A suffix array over the dictionary, for finding the longest match at any target position.
+//!
+//! Built with prefix doubling and counting sorts, so it is O(n log n) time and about 20 bytes per dictionary byte while building on 64 bit targets
+//! (three u32 arrays plus the usize counts, which grow to one per rank class).
+//! Positions are u32, so the dictionary must be shorter than `u32::MAX` bytes, see `SuffixArray::fits`.
+
+/// Bytes compared while searching. Suffixes that agree this far are all good matches, the one found is then extended.
+const MAX_CMP_LEN: usize = 1 << 16;
+/// Suffixes checked on each side when looking for the match closest to a position.
+const NEAR_SCAN: usize = 16;
+
+pub(crate) struct SuffixArray {
+    sa: Vec<u32>,
+}
+
+impl SuffixArray {
+    /// Whether a dictionary of `len` bytes can be indexed.
+    pub(crate) fn fits(len: usize) -> bool {
+        len < u32::MAX as usize
+    }
+    /// Panics if the dictionary does not `fit`.
+    pub(crate) fn new(data: &[u8]) -> Self {
+        assert!(Self::fits(data.len()), "Dictionary is too large for the suffix array");
+        let n = data.len();
+        if n == 0 {
+            return Self { sa: Vec::new() };
+        }
+        //sort by the first byte
+        let mut counts = vec![0usize; 257];
+        for &b in data {
+            counts[b as usize + 1] += 1;
+        }
+        for i in 1..counts.len() {
+            counts[i] += counts[i - 1];
+        }
+        let mut sa = vec![0u32; n];
+        for (i, &b) in data.iter().enumerate() {
+            sa[counts[b as usize]] = i as u32;
+            counts[b as usize] += 1;
+        }
+        let mut rank: Vec<u32> = data.iter().map(|&b| b as u32).collect();
+        let mut classes = 256;
+        let mut tmp = vec![0u32; n];
+        let mut k = 1;
+        loop {
+            //order by the second half (rank[i + k]); suffixes without one sort first.
+            let mut t = 0;
+            for i in n - k.min(n)..n {
+                tmp[t] = i as u32;
+                t += 1;
+            }
+            for &s in sa.iter() {
+                if s as usize >= k {
+                    tmp[t] = s - k as u32;
+                    t += 1;
+                }
+            }
+            //stable counting sort by the first half
+            counts.clear();
+            counts.resize(classes + 1, 0);
+            for &r in rank.iter() {
+                counts[r as usize + 1] += 1;
+            }
+            for i in 1..counts.len() {
+                counts[i] += counts[i - 1];
+            }
+            for &s in tmp.iter() {
+                let r = rank[s as usize] as usize;
+                sa[counts[r]] = s;
+                counts[r] += 1;
+            }
+            //new ranks, reusing tmp
+            let key = |i: usize| (rank[i], rank.get(i + k).map(|r| *r as i64).unwrap_or(-1));
+            tmp[sa[0] as usize] = 0;
+            let mut class = 0;
+            for w in 1..n {
+                if key(sa[w] as usize) != key(sa[w - 1] as usize) {
+                    class += 1;
+                }
+                tmp[sa[w] as usize] = class;
+            }
+            std::mem::swap(&mut rank, &mut tmp);
+            classes = class as usize + 1;
+            if classes == n {
+                break;
+            }
+            k *= 2;
+        }
+        Self { sa }
+    }
+
+    /// Returns (dict_pos, len) of the longest prefix of `needle` found in `data` (the same data the array was built from).
+    #[cfg(test)]
+    pub(crate) fn longest_match(&self, data: &[u8], needle: &[u8]) -> Option<(usize, usize)> {
+        self.longest_match_near(data, needle, 0)
+    }
+
+    /// Same as [`SuffixArray::longest_match`], but among the matches of the longest length the one closest to `near` is returned.
+    /// Only the first few suffixes on each side are considered.
+    pub(crate) fn longest_match_near(&self, data: &[u8], needle: &[u8], near: usize) -> Option<(usize, usize)> {
+        if self.sa.is_empty() || needle.is_empty() {
+            return None;
+        }
+        let needle_cmp = &needle[..needle.len().min(MAX_CMP_LEN)];
+        let idx = self.sa.partition_point(|&s| {
+            let suffix = &data[s as usize..];
+            suffix[..suffix.len().min(MAX_CMP_LEN)] < *needle_cmp
+        });
+        let prefix_len = |i: usize| common_prefix_len(&data[self.sa[i] as usize..], needle_cmp);
+        //the longest match is next to where the needle would be inserted, all the others of that length are next to it.
+        let (first, len) = [idx.checked_sub(1), Some(idx).filter(|i| *i < self.sa.len())].into_iter().flatten()
+            .map(|i| (i, prefix_len(i)))
+            .max_by_key(|&(i, len)| (len, std::cmp::Reverse(i)))?;
+        if len == 0 {
+            return None;
+        }
+        let lo = (first.saturating_sub(NEAR_SCAN)..first).rev().take_while(|&i| prefix_len(i) == len).last().unwrap_or(first);
+        let hi = (first + 1..(first + 1 + NEAR_SCAN).min(self.sa.len())).take_while(|&i| prefix_len(i) == len).last().unwrap_or(first);
+        let best = (lo..=hi).map(|i| self.sa[i] as usize).min_by_key(|&s| s.abs_diff(near)).unwrap();
+        Some((best, common_prefix_len(&data[best..], needle)))
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_suffix_array() {
+        let data = b"mississippi banana mississippi";
+        let sa = SuffixArray::new(data);
+        let mut expected: Vec<u32> = (0..data.len() as u32).collect();
+        expected.sort_by_key(|&i| &data[i as usize..]);
+        assert_eq!(sa.sa, expected);
+        assert_eq!(sa.longest_match(data, b"anana split"), Some((13, 6)));
+        assert_eq!(sa.longest_match(data, b"ssippi banjo"), Some((5, 10)));
+        assert_eq!(sa.longest_match(data, b"xyz"), None);
+        assert_eq!(sa.longest_match(data, b"mississippi"), Some((0, 11)));
+        assert_eq!(sa.longest_match_near(data, b"mississippi", 20), Some((19, 11)));
+    }
+
+    #[test]
+    fn test_fits() {
+        //the src matcher falls back to hashing for dictionaries that do not fit
+        assert!(SuffixArray::fits(0));
+        assert!(SuffixArray::fits(u32::MAX as usize - 1));
+        assert!(!SuffixArray::fits(u32::MAX as usize));
+    }
+}
@@ -1,3 +1,5 @@
+use smdiff_common::copy_addr_size;
+
 use crate::{hasher::*, hashmap::{BasicHashTable, ChainList}, Ranger};
 
 
@@ -37,13 +39,15 @@ impl TrgtMatcher {
     //     }
     // }
     ///Returns (trgt_pos, post_match) post_match *includes* the hash_win_len.
-    pub fn find_best_trgt_match(&self,trgt:&[u8],min_match:usize)->Option<(usize,usize)>{
-        let cur_hash = self.fwd_hash as usize;
+    /// Candidates are weighed by their length minus the cost of their address relative to `last_o_addr`.
+    /// Among equally good candidates, the one closest to `last_o_addr` wins.
+    pub fn find_best_trgt_match(&self,trgt:&[u8],min_match:usize,last_o_addr:usize)->Option<(usize,usize)>{
+        let cur_hash = self.fwd_hash as u64;
         let table_pos = self.table.get(cur_hash)?;
         let mut iter = std::iter::once(table_pos).chain(self.chain.iter_prev_starts(table_pos, self.fwd_pos,cur_hash)).filter(|start|start + 4 < self.fwd_pos);
         let mut chain = if min_match > 4 {(self.chain_check/4).max(1)} else {self.chain_check};
         let mut best = None;
-        let mut best_len = 0;
+        let mut best_key = (false, isize::MIN, std::cmp::Reverse(usize::MAX));
         let mut _chain_len = 0;
         let mut _collisions = 0;
         loop {
@@ -72,10 +76,12 @@ impl TrgtMatcher {
                     trgt[match_end + i] == trgt[trgt_end + i]
                 }).count();
                 let total_post_match = post_match + 4;
-                if total_post_match > best_len{
-                    best_len = total_post_match;
+                let score = total_post_match as isize - copy_addr_size(last_o_addr as u64, start_pos as u64) as isize;
+                let key = (total_post_match >= min_match, score, std::cmp::Reverse(start_pos.abs_diff(last_o_addr)));
+                if key > best_key{
+                    best_key = key;
                     best = Some((start_pos,total_post_match));
-                    if best_len >= self.compress_early_exit{
+                    if total_post_match >= self.compress_early_exit{
                         break;
                     }
                 }
@@ -93,7 +99,7 @@ impl TrgtMatcher {
         // std::thread::sleep(std::time::Duration::from_millis(100));
         best
     }
-    pub(crate) fn store(&mut self, hash:usize, pos:usize){
+    pub(crate) fn store(&mut self, hash:u64, pos:usize){
         match self.table.insert(hash, pos){
             Ok(None) => {},
             Ok(Some(prev)) => {
@@ -160,7 +166,8 @@ impl TrgtMatcherConfig {
         let mut matcher = TrgtMatcher{
             compress_early_exit: *compress_early_exit,
             chain_check: *chain_check,
-            fwd_hash: 0,
+            //the encoder only rolls this forward, so it has to start as the hash at trgt_start_pos
+            fwd_hash: trgt.get(trgt_start_pos..trgt_start_pos + 4).map(calculate_small_checksum).unwrap_or(0),
             fwd_pos: trgt_start_pos,
             table,
             chain: ChainList::new(self.prev_table_capacity.unwrap()),
@@ -170,10 +177,10 @@ impl TrgtMatcherConfig {
             let start = trgt_start_pos.saturating_sub(self.prev_table_capacity.unwrap());
             let end = trgt_start_pos;
             let mut hash = calculate_small_checksum(&trgt[start..]);
-            matcher.store(hash as usize, start);
+            matcher.store(hash as u64, start);
             for old_pos in start..end{
                 hash = update_small_checksum_fwd(hash, trgt[old_pos], trgt[old_pos + 4]);
-                matcher.store(hash as usize, old_pos + 1);
+                matcher.store(hash as u64, old_pos + 1);
             }
         }
         matcher
@@ -1,51 +1,119 @@
 //! A writer for an smdiff section.
 //! This handles writing the header and the operations, and optionally secondary compression.
 use std::io::Write;
+use std::time::{Duration, Instant};
 
 use crate::{encode, EncoderConfig, SecondaryCompression};
 
-use smdiff_common::AddOp;
+use smdiff_common::{AddOp, Format};
 use smdiff_writer::{write_ops, write_section_header};
 
 
 /// Writes a section to a writer, with secondary compression if requested.
 pub fn section_writer<W:Write,A:AddOp>(
     sec_comp: &Option<SecondaryCompression>,
+    header: smdiff_common::SectionHeader,
+    writer: &mut W,
+    seg_ops: &[smdiff_common::Op<A>],
+    sec_data_buffer: &mut Vec<u8>)
+-> std::io::Result<()> {
+    match sec_comp {
+        None => {
+            write_section_header(&header, writer)?;
+            write_ops(seg_ops,&header,writer)?;
+        },
+        Some(SecondaryCompression::Auto { time_budget }) => {
+            auto_section_writer(*time_budget, header, writer, seg_ops, sec_data_buffer)?;
+        },
+        Some(comp @ SecondaryCompression::Smdiff (config)) => {
+            buffer_ops(comp, header, writer, seg_ops, sec_data_buffer)?;
+            let mut crsr = std::io::Cursor::new(sec_data_buffer.as_slice());
+            let inner_config = EncoderConfig::default().no_match_src().set_match_target(config.clone());
+            encode(None, &mut crsr, writer, &inner_config)?;
+        },
+        Some(comp @ SecondaryCompression::Zstd { level }) => {
+            buffer_ops(comp, header, writer, seg_ops, sec_data_buffer)?;
+            let mut a = ::zstd::Encoder::new(writer, *level)?;
+            a.set_pledged_src_size(Some(sec_data_buffer.len() as u64))?;
+            a.include_contentsize(true)?;
+            a.write_all(&*sec_data_buffer)?;
+            a.finish()?;
+        },
+        Some(comp @ SecondaryCompression::Brotli { options }) => {
+            buffer_ops(comp, header, writer, seg_ops, sec_data_buffer)?;
+            let mut options = options.clone();
+            options.size_hint(sec_data_buffer.len() as u32);
+            let mut a = ::brotlic::CompressorWriter::with_encoder(options.build().unwrap(), writer);
+            a.write_all(&*sec_data_buffer)?;
+            a.into_inner()?;
+        },
+    }
+    sec_data_buffer.clear();
+    Ok(())
+}
+
+/// Writes the header (marked with the algorithm) to the writer and the ops to the buffer, for the algorithm to compress.
+fn buffer_ops<W:Write,A:AddOp>(
+    comp: &SecondaryCompression,
     mut header: smdiff_common::SectionHeader,
     writer: &mut W,
     seg_ops: &[smdiff_common::Op<A>],
-    mut sec_data_buffer: &mut Vec<u8>)
+    sec_data_buffer: &mut Vec<u8>)
 -> std::io::Result<()> {
-    Ok(if sec_comp.is_some() {
-        let comp = sec_comp.clone().unwrap();
-        header.compression_algo = comp.algo_value();
-        //dbg!(&header);
-        write_section_header(&header, writer)?;
-        write_ops(seg_ops,&header,sec_data_buffer)?;
-        match comp{
-            SecondaryCompression::Smdiff (config) => {
-                let mut crsr = std::io::Cursor::new(sec_data_buffer);
-                let inner_config = EncoderConfig::default().no_match_src().set_match_target(config);
-                encode(None, &mut crsr, writer, &inner_config)?;
-                sec_data_buffer = crsr.into_inner();
-            },
-            SecondaryCompression::Zstd { level } => {
-                let mut a = ::zstd::Encoder::new(writer, level)?;
-                a.set_pledged_src_size(Some(sec_data_buffer.len() as u64))?;
-                a.include_contentsize(true)?;
-                a.write_all(&*sec_data_buffer)?;
-                a.finish()?;
-            },
-            SecondaryCompression::Brotli { mut options }=> {
-                options.size_hint(sec_data_buffer.len() as u32);
-                let mut a = ::brotlic::CompressorWriter::with_encoder(options.build().unwrap(), writer);
-                a.write_all(&*sec_data_buffer)?;
-                a.into_inner()?;
-            },
+    header.compression_algo = comp.algo_value();
+    write_section_header(&header, writer)?;
+    write_ops(seg_ops,&header,sec_data_buffer)
+}
+
+/// Writes the section with each algorithm (starting with none) until the time budget is used, and keeps the smallest.
+fn auto_section_writer<W:Write,A:AddOp>(
+    time_budget: Option<Duration>,
+    header: smdiff_common::SectionHeader,
+    writer: &mut W,
+    seg_ops: &[smdiff_common::Op<A>],
+    sec_data_buffer: &mut Vec<u8>)
+-> std::io::Result<()> {
+    let start = Instant::now();
+    let mut best = Vec::new();
+    section_writer(&None, header, &mut best, seg_ops, sec_data_buffer)?;
+    let candidates = [
+        SecondaryCompression::new_zstd_default(),
+        SecondaryCompression::new_smdiff_default(),
+        SecondaryCompression::new_brotli_default(),
+    ];
+    for comp in candidates {
+        if time_budget.is_some_and(|budget| start.elapsed() >= budget) {
+            break;
+        }
+        let mut candidate = Vec::new();
+        section_writer(&Some(comp), header, &mut candidate, seg_ops, sec_data_buffer)?;
+        if candidate.len() < best.len() {
+            best = candidate;
         }
-        sec_data_buffer.clear();
+    }
+    writer.write_all(&best)
+}
+
+/// Writes a section in both formats and keeps whichever is smaller (Interleaved on a tie).
+/// The format in the given header is ignored.
+/// # Returns
+/// The format that was written.
+pub fn adaptive_section_writer<W:Write,A:AddOp>(
+    sec_comp: &Option<SecondaryCompression>,
+    header: smdiff_common::SectionHeader,
+    writer: &mut W,
+    seg_ops: &[smdiff_common::Op<A>],
+    sec_data_buffer: &mut Vec<u8>)
+-> std::io::Result<Format> {
+    let mut interleaved = Vec::new();
+    section_writer(sec_comp, header.set_format(Format::Interleaved), &mut interleaved, seg_ops, sec_data_buffer)?;
+    let mut segregated = Vec::new();
+    section_writer(sec_comp, header.set_format(Format::Segregated), &mut segregated, seg_ops, sec_data_buffer)?;
+    if segregated.len() < interleaved.len() {
+        writer.write_all(&segregated)?;
+        Ok(Format::Segregated)
     }else{
-        write_section_header(&header, writer)?;
-        write_ops(seg_ops,&header,writer)?;
-    })
-}
\ No newline at end of file
+        writer.write_all(&interleaved)?;
+        Ok(Format::Interleaved)
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::{Cursor, Read, Seek};
+
+use smdiff_decoder::apply_patch;
+use smdiff_reader::Op;
+
+use crate::{deref_copy_o, extract_patch_instructions, reject_filtered, mapping::{MappingIndex, Splicer}, SummaryPatch};
+
+/// Produces the inverse of a patch, that is a patch that turns the target back into the dictionary (source).
+///
+/// Every Copy-D in the forward patch says a region of the source also exists in the target, so it can be copied back for free.
+/// Only the regions of the source that no Copy-D covers are encoded, using the target as the dictionary.
+/// # Arguments
+/// * `patch` - The source->target patch.
+/// * `dict` - The source (dictionary) the patch was made against.
+/// # Returns
+/// The target->source patch. Use [`SummaryPatch::write`] to write it out.
+/// # Errors
+/// Returns an error if there was an issue reading the patch or the dictionary, or the patch uses a filter.
+pub fn invert_patch<P: Read + Seek, D: Read + Seek>(patch: &mut P, dict: &mut D) -> std::io::Result<SummaryPatch> {
+    reject_filtered(patch)?;
+    let mut src = Vec::new();
+    dict.read_to_end(&mut src)?;
+    let mut trgt = Cursor::new(Vec::new());
+    apply_patch(patch, Some(&mut Cursor::new(&src)), &mut trgt)?;
+    let trgt = trgt.into_inner();
+    patch.seek(std::io::SeekFrom::Start(0))?;
+    let (ops, _stats) = extract_patch_instructions(&mut *patch)?;
+    //Copy-O only points at target bytes we already have, resolving them exposes the Copy-D they came from.
+    let index = MappingIndex::from_copy_d(&deref_copy_o(ops));
+    let mut pieces = Vec::new();
+    index.cover(0, src.len() as u64, &mut pieces);
+    let mut gap_bytes = Vec::new();
+    let mut pos = 0;
+    for piece in pieces.iter() {
+        if piece.trgt_addr.is_none() {
+            gap_bytes.extend_from_slice(&src[pos..pos + piece.len as usize]);
+        }
+        pos += piece.len as usize;
+    }
+    let mut output: Vec<Op> = Vec::new();
+    Splicer::new(&gap_bytes, &trgt)?.push(&pieces, &mut output)?;
+    Ok(SummaryPatch(output))
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::test_util::random_bytes;
+    use smdiff_encoder::{encode, EncoderConfig};
+
+    #[test]
+    fn test_invert_patch() {
+        let mut src = random_bytes(1, 40_000);
+        src[35_000..36_000].fill(7);
+        let mut trgt = Vec::new();
+        trgt.extend_from_slice(&src[..10_000]);
+        trgt.extend_from_slice(&random_bytes(2, 500));
+        //src[10_000..12_000] is deleted, so the inverse has to add it back.
+        trgt.extend_from_slice(&src[12_000..30_000]);
+        trgt.extend_from_slice(&src[..1000]);
+        trgt.extend_from_slice(&src[31_000..35_000]);
+        //a long run in the source that is gone from the target
+        trgt.extend_from_slice(&src[36_000..]);
+        let mut patch = Vec::new();
+        encode(Some(&mut Cursor::new(src.as_slice())), &mut Cursor::new(trgt.as_slice()), &mut patch, &EncoderConfig::default()).unwrap();
+        let inverse = invert_patch(&mut Cursor::new(patch), &mut Cursor::new(&src)).unwrap();
+        let mut inverse_patch = Vec::new();
+        inverse.write(&mut inverse_patch, None, None, None).unwrap();
+        //roughly the 2000 deleted bytes plus the gap that was overwritten
+        assert!(inverse_patch.len() < 3500, "inverse too large: {}", inverse_patch.len());
+        let mut sink = Cursor::new(Vec::new());
+        apply_patch(&mut Cursor::new(inverse_patch), Some(&mut Cursor::new(&trgt)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), src);
+    }
+}
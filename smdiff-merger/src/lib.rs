@@ -1,14 +1,22 @@
 use std::io::{Read, Seek, Write};
 
-use smdiff_common::{Format, Run, MAX_INST_SIZE, MAX_WIN_SIZE};
+use smdiff_common::{filter::read_filter_header, progress::{check_cancelled, Observer, Phase}, Format, Run, MAX_INST_SIZE, MAX_WIN_SIZE};
 use smdiff_decoder::reader::SectionIterator;
 use smdiff_encoder::{writer::section_writer, SecondaryCompression};
 use smdiff_reader::Op;
 use smdiff_writer::make_sections;
 
 pub mod transcoder;
+pub mod inverter;
+pub mod streaming;
+pub mod rebase;
+pub mod render;
+mod optimizer;
+mod mapping;
 ///Extracted Instruction with the starting position in the output buffer.
 pub type SparseOp = (u64, Op);
+/// Copies resolved between progress reports (and cancellation checks) while merging.
+const MERGE_REPORT_INTERVAL: usize = 1 << 12;
 
 impl MergeOp for Run {
     fn skip(&mut self,amt:u32) {
@@ -170,9 +178,21 @@ impl Stats {
     }
 }
 
+/// Returns an InvalidInput error if the patch starts with a filter header (see `smdiff_common::filter`).
+/// The ops of a filtered patch describe the filtered bytes, so they can not be combined with other patches or rewritten.
+/// If there is no header the reader is put back where it was.
+pub(crate) fn reject_filtered<R:Read + Seek>(patch:&mut R)->std::io::Result<()>{
+    if read_filter_header(patch)?.is_some(){
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "A filtered patch can not be merged, transcoded, inverted or rebased"));
+    }
+    Ok(())
+}
+
 ///Extracts all instructions from all windows.
 ///Memory consumption may be 2-4x the size of the encoded (uncompressed) patch.
-pub fn extract_patch_instructions<R:Read + Seek>(patch:R)->std::io::Result<(Vec<SparseOp>, Stats)>{
+///Filtered patches are rejected with an InvalidInput error.
+pub fn extract_patch_instructions<R:Read + Seek>(mut patch:R)->std::io::Result<(Vec<SparseOp>, Stats)>{
+    reject_filtered(&mut patch)?;
     let mut output = Vec::new();
     let mut reader = SectionIterator::new(patch);
     let mut o_pos_start = 0;
@@ -296,7 +316,16 @@ impl Merger {
     /// # Returns
     /// If the resulting summary patch has no Copy instructions, a SummaryPatch is returned.
     /// If the resulting summary patch has even a single Copy instructions, a Merger is returned.
-    pub fn merge<R:Read + Seek>(mut self, predecessor_patch:R) -> std::io::Result<Result<Merger,SummaryPatch>> {
+    pub fn merge<R:Read + Seek>(self, predecessor_patch:R) -> std::io::Result<Result<Merger,SummaryPatch>> {
+        self.merge_with_observer(predecessor_patch, &mut ())
+    }
+    ///Same as [`Merger::merge`], but reports progress to the observer, which can also cancel the merge.
+    /// # Arguments
+    /// * `predecessor_patch` - The patch to merge into the current summary patch.
+    /// * `observer` - Told when the patch is read and when merging starts, then the Copy bytes resolved so far.
+    /// # Errors
+    /// Returns a [`smdiff_common::progress::Cancelled`] error if the observer cancelled. The Merger is consumed either way.
+    pub fn merge_with_observer<R:Read + Seek>(mut self, predecessor_patch:R, observer:&mut dyn Observer) -> std::io::Result<Result<Merger,SummaryPatch>> {
         debug_assert!({
             let mut x = 0;
             for inst in self.terminal_patch.iter(){
@@ -305,26 +334,35 @@ impl Merger {
             }
             true
         });
+        observer.phase(Phase::Reading);
         let (mut predecessor_patch,stats) = extract_patch_instructions(predecessor_patch)?;
         if stats.has_copy(){
             predecessor_patch = deref_copy_o(predecessor_patch);
         }
+        observer.phase(Phase::Merging);
         let mut terminal_copy_indices = Vec::with_capacity(self.terminal_copy_indices.len());
         let mut inserts = Vec::with_capacity(self.terminal_copy_indices.len());
         let mut shift = 0;
-        for i in self.terminal_copy_indices{
+        let mut resolved_bytes = 0u64;
+        for (n, i) in self.terminal_copy_indices.into_iter().enumerate(){
+            if n % MERGE_REPORT_INTERVAL == 0 {
+                observer.bytes_processed(resolved_bytes);
+                check_cancelled(observer)?;
+            }
             let (_,op) = self.terminal_patch[i].clone();
             let copy = op.take_copy().expect("Expected Copy");
             //this a src window copy that we need to resolve from the predecessor patch.
             debug_assert!(matches!(copy.src, smdiff_common::CopySrc::Dict));
             let o_start = copy.addr; //ssp is o_pos, u is offset from that.
             let resolved = get_exact_slice(&predecessor_patch, o_start, copy.len as u32).unwrap();
+            resolved_bytes += copy.len as u64;
             //debug_assert_eq!(sum_len_in_o(&resolved), copy.len_in_o() as u64, "resolved: {:?} copy: {:?}",resolved,copy);
             find_mergeable_copies(&resolved, i+shift, &mut terminal_copy_indices);
             shift += resolved.len() - 1;
             inserts.push((i, resolved));
 
         }
+        observer.bytes_processed(resolved_bytes);
         //now we expand the old copy values with the derefd instructions.
         //debug_assert_eq!(sum_len_in_o(&self.terminal_patch), self.final_size, "final size: {} sum_len: {}",self.final_size,sum_len_in_o(&self.terminal_patch));
         if terminal_copy_indices.is_empty(){
@@ -343,6 +381,95 @@ impl Merger {
 
 }
 
+///Merges a whole chain of patches in one call.
+///
+///Adjacent patches are merged pairwise, then the results are merged pairwise, and so on (a balanced merge tree).
+///Each level reads every op of the patches at that level once, and there are log(n) levels.
+///When the merged patches stay about as large as their inputs, a chain of n patches costs about n·log(n) instead of the n² of repeated [`Merger::merge`] calls.
+///Copy-D ops that resolve to many ops of the older patch make the merged patches (and so the later levels) larger.
+/// # Arguments
+/// * `patches` - The patches in the order they are applied (oldest first). The first patch is applied to the original source file.
+/// # Returns
+/// The summary patch that turns the original source file into the output of the last patch.
+/// # Errors
+/// Returns an error if there are no patches, if a patch could not be read or uses a filter, or if a patch copies past the end of its predecessor's output.
+pub fn merge_chain<R:Read + Seek, I:IntoIterator<Item=R>>(patches:I)->std::io::Result<SummaryPatch>{
+    merge_chain_inner(patches, false)
+}
+
+///Same as [`merge_chain`], but splits the pairs of each level of the tree over one thread per available core
+///(see [`std::thread::available_parallelism`]).
+pub fn merge_chain_parallel<R:Read + Seek, I:IntoIterator<Item=R>>(patches:I)->std::io::Result<SummaryPatch>{
+    merge_chain_inner(patches, true)
+}
+
+fn merge_chain_inner<R:Read + Seek, I:IntoIterator<Item=R>>(patches:I,parallel:bool)->std::io::Result<SummaryPatch>{
+    let mut level = Vec::new();
+    for patch in patches {
+        let (extracted,stats) = extract_patch_instructions(patch)?;
+        level.push(if stats.has_copy(){deref_copy_o(extracted)}else{extracted});
+    }
+    if level.is_empty(){
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No patches to merge"));
+    }
+    while level.len() > 1 {
+        let mut pairs = Vec::with_capacity(level.len().div_ceil(2));
+        let mut iter = level.into_iter();
+        while let Some(older) = iter.next() {
+            pairs.push((older, iter.next()));
+        }
+        level = if parallel {
+            let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let chunk_len = pairs.len().div_ceil(workers);
+            let mut pairs = pairs.into_iter();
+            let chunks:Vec<Vec<_>> = std::iter::from_fn(|| Some(pairs.by_ref().take(chunk_len).collect::<Vec<_>>()).filter(|c| !c.is_empty())).collect();
+            std::thread::scope(|scope| {
+                let handles:Vec<_> = chunks.into_iter().map(|chunk| scope.spawn(move || {
+                    chunk.into_iter().map(|(older,newer)| merge_pair(older,newer)).collect::<std::io::Result<Vec<_>>>()
+                })).collect();
+                let mut merged = Vec::new();
+                for handle in handles {
+                    merged.extend(handle.join().expect("Merge thread panicked")?);
+                }
+                Ok::<_, std::io::Error>(merged)
+            })?
+        }else{
+            pairs.into_iter().map(|(older,newer)| merge_pair(older,newer)).collect::<std::io::Result<Vec<_>>>()?
+        };
+    }
+    Ok(SummaryPatch(level.pop().unwrap().into_iter().map(|s|s.1).collect()))
+}
+
+///Replaces every Copy-D of `newer` with the ops of `older` that produced those bytes.
+///Both must already have their Copy-O ops dereferenced.
+fn merge_pair(older:Vec<SparseOp>,newer:Option<Vec<SparseOp>>)->std::io::Result<Vec<SparseOp>>{
+    let newer = match newer {
+        Some(n) => n,
+        None => return Ok(older),
+    };
+    let mut output = Vec::with_capacity(newer.len());
+    let mut cur_o_pos = 0;
+    for (_,op) in newer {
+        match op {
+            Op::Copy(copy) if matches!(copy.src, smdiff_common::CopySrc::Dict) => {
+                let resolved = get_exact_slice(&older, copy.addr, copy.len as u32)
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Copy {}..{} is past the end of the predecessor's output", copy.addr, copy.addr + copy.len as u64)))?;
+                for (_,resolved_op) in resolved {
+                    let o_pos_start = cur_o_pos;
+                    cur_o_pos += resolved_op.oal() as u64;
+                    output.push((o_pos_start,resolved_op));
+                }
+            },
+            _ => {
+                let o_pos_start = cur_o_pos;
+                cur_o_pos += op.oal() as u64;
+                output.push((o_pos_start,op));
+            },
+        }
+    }
+    Ok(output)
+}
+
 /// This is returned when the current summary patch contains no Copy instructions, OR when you are finished with the Merger.
 #[derive(Debug)]
 pub struct SummaryPatch(Vec<Op>);
@@ -364,6 +491,16 @@ impl SummaryPatch{
         }
         Ok(())
     }
+    /// Runs a cheap optimization pass over the merged ops. This is much faster than a fresh encode, but recovers part of the lost compression.
+    /// * Adjacent copies with contiguous addresses are joined.
+    /// * Add/Run data repeating earlier Add/Run data is replaced with Copy-O ops.
+    /// * Ops crossing a window boundary are split, so every section is full.
+    /// # Arguments
+    /// * `max_win_size` - The window size that will be given to [`SummaryPatch::write`].
+    pub fn optimize(self, max_win_size: Option<usize>) -> SummaryPatch {
+        let max_win_size = max_win_size.unwrap_or(MAX_WIN_SIZE).clamp(MAX_INST_SIZE, MAX_WIN_SIZE);
+        SummaryPatch(optimizer::optimize_ops(self.0, max_win_size))
+    }
     /// Returns the ops that represents the summary patch.
     /// This allows applying them directly to a source file without translating them to a patch file.
     pub fn take_ops(self)->Vec<Op>{
@@ -434,6 +571,9 @@ where
 
 
 
+#[cfg(test)]
+pub(crate) use smdiff_common::test_util;
+
 #[cfg(test)]
 mod test_super {
 
@@ -634,6 +774,66 @@ mod test_super {
         assert_eq!(output,answer);
     }
     #[test]
+    fn test_merge_chain(){
+        //same as test_all_seq, but all at once and oldest first
+        let answer = b"YZZXZYZZXZ";
+        for parallel in [false,true]{
+            let patches = vec![add_run_patch(), complex_patch(), copy_patch()];
+            let merged = if parallel {merge_chain_parallel(patches)} else {merge_chain(patches)}.unwrap();
+            let mut merged_patch = Vec::new();
+            merged.write(&mut merged_patch, None,None,None).unwrap();
+            let mut output = Cursor::new(Vec::new());
+            apply_patch::<_, Cursor<Vec<u8>>,_>(&mut Cursor::new(merged_patch), None, &mut output).unwrap();
+            assert_eq!(output.into_inner(),answer);
+        }
+        assert!(merge_chain(Vec::<Cursor<Vec<u8>>>::new()).is_err());
+    }
+    #[test]
+    fn test_merge_chain_many(){
+        //more pairs than there are cores, so each thread merges several
+        let mut versions = vec![test_util::random_bytes(1, 5_000)];
+        for i in 0..40u64 {
+            let mut next = versions.last().unwrap().clone();
+            let at = (i as usize * 97) % 4_000;
+            next[at..at + 50].copy_from_slice(&test_util::random_bytes(100 + i, 50));
+            versions.push(next);
+        }
+        let patches: Vec<Vec<u8>> = versions.windows(2).map(|w| {
+            let mut patch = Vec::new();
+            smdiff_encoder::encode(Some(&mut Cursor::new(&w[0])), &mut Cursor::new(&w[1]), &mut patch, &smdiff_encoder::EncoderConfig::default()).unwrap();
+            patch
+        }).collect();
+        for parallel in [false,true]{
+            let patches = patches.iter().map(Cursor::new);
+            let merged = if parallel {merge_chain_parallel(patches)} else {merge_chain(patches)}.unwrap();
+            let mut merged_patch = Vec::new();
+            merged.write(&mut merged_patch, None,None,None).unwrap();
+            let mut output = Cursor::new(Vec::new());
+            apply_patch(&mut Cursor::new(merged_patch), Some(&mut Cursor::new(&versions[0])), &mut output).unwrap();
+            assert_eq!(&output.into_inner(), versions.last().unwrap());
+        }
+    }
+    #[test]
+    fn test_reject_filtered(){
+        let src = test_util::random_bytes(3, 4000);
+        let mut trgt = src.clone();
+        trgt[1000..1005].copy_from_slice(&[0xE8, 0x10, 0x00, 0x00, 0x00]);
+        let mut patch = Vec::new();
+        let config = smdiff_encoder::EncoderConfig::default().set_filter(smdiff_common::filter::Filter::X86);
+        smdiff_encoder::encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut patch, &config).unwrap();
+        let is_invalid_input = |err: std::io::Error| err.kind() == std::io::ErrorKind::InvalidInput;
+        assert!(is_invalid_input(Merger::new(Cursor::new(&patch)).err().unwrap()));
+        assert!(is_invalid_input(merge_chain([Cursor::new(&patch)]).err().unwrap()));
+        assert!(is_invalid_input(crate::streaming::StreamingMerger::new(Cursor::new(&patch)).err().unwrap()));
+        assert!(is_invalid_input(crate::transcoder::transcode(&mut Cursor::new(&patch), &mut Vec::new(), Format::Segregated, None, MAX_WIN_SIZE).unwrap_err()));
+        assert!(is_invalid_input(crate::inverter::invert_patch(&mut Cursor::new(&patch), &mut Cursor::new(&src)).err().unwrap()));
+        let mut plain = Vec::new();
+        smdiff_encoder::encode(Some(&mut Cursor::new(&src)), &mut Cursor::new(&trgt), &mut plain, &smdiff_encoder::EncoderConfig::default()).unwrap();
+        assert!(is_invalid_input(crate::rebase::rebase_patch(&mut Cursor::new(&plain), &mut Cursor::new(&patch), &mut Cursor::new(&src)).err().unwrap()));
+        //the same patch without the filter still works
+        assert!(Merger::new(Cursor::new(&plain)).is_ok());
+    }
+    #[test]
     fn test_kitchen_sink(){
         //"hello" -> "hello world!" -> "Hello! Hello! Hello. hello. hello..."
         //we need to use a series of VCD_TARGET windows and Sequences across multiple patches
@@ -0,0 +1,112 @@
+//! Helpers for patches that reuse the Copy-D mapping of another patch (inversion and rebasing).
+use std::io::Cursor;
+
+use smdiff_common::{Copy, CopySrc};
+use smdiff_encoder::{encode, EncoderConfig};
+use smdiff_reader::Op;
+
+use crate::{deref_copy_o, extract_patch_instructions, get_exact_slice, SparseOp};
+
+/// `src[src_start..src_start+len] == trgt[trgt_start..trgt_start+len]`
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Mapping {
+    pub(crate) src_start: u64,
+    pub(crate) trgt_start: u64,
+    pub(crate) len: u64,
+}
+
+impl Mapping {
+    fn src_end(&self) -> u64 {
+        self.src_start + self.len
+    }
+}
+
+/// A range of `src` and where it can be found in `trgt` (None if it can't be found).
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Piece {
+    pub(crate) len: u64,
+    pub(crate) trgt_addr: Option<u64>,
+}
+
+/// Mappings sorted by `src_start`, with the furthest reaching mapping of every prefix so ranges can be covered with a binary search.
+pub(crate) struct MappingIndex {
+    mappings: Vec<Mapping>,
+    furthest: Vec<usize>,
+}
+
+impl MappingIndex {
+    pub(crate) fn new(mut mappings: Vec<Mapping>) -> Self {
+        mappings.sort_by_key(|m| m.src_start);
+        let mut furthest: Vec<usize> = Vec::with_capacity(mappings.len());
+        for (i, m) in mappings.iter().enumerate() {
+            let best = match furthest.last() {
+                Some(&b) if mappings[b].src_end() >= m.src_end() => b,
+                _ => i,
+            };
+            furthest.push(best);
+        }
+        Self { mappings, furthest }
+    }
+    /// Mappings from the dictionary (src) to the output (trgt) of a patch.
+    /// The ops must have their Copy-O ops dereferenced, else the Copy-D they point to are missed.
+    pub(crate) fn from_copy_d(ops: &[SparseOp]) -> Self {
+        Self::new(ops.iter().filter_map(|(o_pos, op)| match op {
+            Op::Copy(Copy { src: CopySrc::Dict, addr, len }) => Some(Mapping { src_start: *addr, trgt_start: *o_pos, len: *len as u64 }),
+            _ => None,
+        }).collect())
+    }
+    /// Splits `start..end` of src into pieces, greedily using the mapping reaching furthest at each point.
+    pub(crate) fn cover(&self, start: u64, end: u64, pieces: &mut Vec<Piece>) {
+        let mut pos = start;
+        while pos < end {
+            let k = self.mappings.partition_point(|m| m.src_start <= pos);
+            if k > 0 {
+                let m = self.mappings[self.furthest[k - 1]];
+                if m.src_end() > pos {
+                    let piece_end = m.src_end().min(end);
+                    pieces.push(Piece { len: piece_end - pos, trgt_addr: Some(m.trgt_start + pos - m.src_start) });
+                    pos = piece_end;
+                    continue;
+                }
+            }
+            let gap_end = self.mappings.get(k).map(|m| m.src_start).unwrap_or(end).min(end);
+            pieces.push(Piece { len: gap_end - pos, trgt_addr: None });
+            pos = gap_end;
+        }
+    }
+}
+
+/// Turns pieces into ops. Mapped pieces become Copy-D ops, gaps are taken (in order) from the encoded gaps.
+pub(crate) struct Splicer {
+    gap_ops: Vec<SparseOp>,
+    gap_o_pos: u64,
+}
+
+impl Splicer {
+    /// Encodes all the gap bytes at once against `dict`, so they can share matches.
+    pub(crate) fn new(gap_bytes: &[u8], dict: &[u8]) -> std::io::Result<Self> {
+        let gap_ops = if gap_bytes.is_empty() {
+            Vec::new()
+        } else {
+            let mut gap_patch = Vec::new();
+            encode(Some(&mut Cursor::new(dict)), &mut Cursor::new(gap_bytes), &mut gap_patch, &EncoderConfig::default())?;
+            //Copy-O addresses point into the concatenated gaps, they would be wrong once the gaps are split apart.
+            deref_copy_o(extract_patch_instructions(Cursor::new(gap_patch))?.0)
+        };
+        Ok(Self { gap_ops, gap_o_pos: 0 })
+    }
+    pub(crate) fn push(&mut self, pieces: &[Piece], output: &mut Vec<Op>) -> std::io::Result<()> {
+        for piece in pieces {
+            match piece.trgt_addr {
+                Some(addr) => output.push(Op::Copy(Copy { src: CopySrc::Dict, addr, len: piece.len as u16 })),
+                None => {
+                    let slice = get_exact_slice(&self.gap_ops, self.gap_o_pos, piece.len as u32)
+                        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Encoded gaps do not cover the source"))?;
+                    output.extend(slice.into_iter().map(|(_, op)| op));
+                    self.gap_o_pos += piece.len;
+                },
+            }
+        }
+        Ok(())
+    }
+}
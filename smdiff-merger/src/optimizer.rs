@@ -0,0 +1,271 @@
+//! A cheap clean up pass for merged patches.
+//!
+//! Merging splices ops from several patches together without any matching, so the summary patch ends up with:
+//! * Runs of Copy-D ops that continue each other.
+//! * Add/Run data that repeats data added earlier in the output.
+//! * Sections that are cut short because the next op did not fit.
+//!
+//! Only the literal (Add/Run) bytes are known without the dictionary, so those are the only bytes searched for Copy-O matches.
+use std::collections::HashMap;
+
+use smdiff_common::{Copy, CopySrc, Run, MAX_INST_SIZE, MAX_RUN_LEN};
+use smdiff_reader::{Add, Op};
+
+use crate::MergeOp;
+
+/// Shortest Copy-O we make from literal bytes. Anything shorter rarely beats the Add it replaces.
+const MIN_COPY_O_LEN: usize = 16;
+/// Bytes used as the key of the match table.
+const KEY_LEN: usize = 8;
+/// Only every n-th literal position is put in the match table, to bound its size.
+const KEY_STRIDE: usize = 4;
+/// Shortest stretch of a single byte that is emitted as a Run instead of being part of an Add.
+const MIN_RUN_LEN: usize = 6;
+
+/// Runs the whole pass. See the module docs.
+pub(crate) fn optimize_ops(ops: Vec<Op>, max_win_size: usize) -> Vec<Op> {
+    let ops = coalesce_copies(ops);
+    let ops = match_literals(ops);
+    fill_sections(ops, max_win_size)
+}
+
+/// Joins copies of the same kind whose addresses continue each other.
+/// A joined Copy-O must still end before the position it is written to, as the decoder can not apply overlapping copies.
+fn coalesce_copies(ops: Vec<Op>) -> Vec<Op> {
+    let mut output: Vec<Op> = Vec::with_capacity(ops.len());
+    //(src, addr, len, output position) of the copy being joined.
+    let mut pending: Option<(CopySrc, u64, u64, u64)> = None;
+    let mut o_pos = 0u64;
+    for op in ops {
+        let op_len = op.oal() as u64;
+        if let Op::Copy(copy) = &op {
+            match pending.as_mut() {
+                Some((src, addr, len, start)) if *src == copy.src && *addr + *len == copy.addr
+                    && (copy.src == CopySrc::Dict || *addr + *len + op_len <= *start) => {
+                    *len += op_len;
+                },
+                _ => {
+                    flush_copy(pending.take(), &mut output);
+                    pending = Some((copy.src, copy.addr, op_len, o_pos));
+                },
+            }
+        } else {
+            flush_copy(pending.take(), &mut output);
+            output.push(op);
+        }
+        o_pos += op_len;
+    }
+    flush_copy(pending, &mut output);
+    output
+}
+
+fn flush_copy(pending: Option<(CopySrc, u64, u64, u64)>, output: &mut Vec<Op>) {
+    if let Some((src, addr, len, _)) = pending {
+        push_copies(src, addr, len, output);
+    }
+}
+
+fn push_copies(src: CopySrc, mut addr: u64, mut len: u64, output: &mut Vec<Op>) {
+    while len > 0 {
+        let chunk = len.min(MAX_INST_SIZE as u64);
+        output.push(Op::Copy(Copy { src, addr, len: chunk as u16 }));
+        addr += chunk;
+        len -= chunk;
+    }
+}
+
+/// Replaces literal bytes that repeat earlier literal bytes with Copy-O ops.
+fn match_literals(ops: Vec<Op>) -> Vec<Op> {
+    let mut output = Vec::with_capacity(ops.len());
+    //all literal bytes seen so far, and (index in `known`, output position) for the start of each literal segment.
+    let mut known: Vec<u8> = Vec::new();
+    let mut segments: Vec<(usize, u64)> = Vec::new();
+    let mut table: HashMap<[u8; KEY_LEN], usize> = HashMap::new();
+    let mut o_pos = 0u64;
+    let mut ops = ops.into_iter().peekable();
+    while let Some(op) = ops.next() {
+        if op.is_copy() {
+            o_pos += op.oal() as u64;
+            output.push(op);
+            continue;
+        }
+        //gather the whole literal segment
+        let seg_start = known.len();
+        let seg_o_start = o_pos;
+        push_literal(&op, &mut known);
+        while let Some(next) = ops.next_if(|op| !op.is_copy()) {
+            push_literal(&next, &mut known);
+        }
+        segments.push((seg_start, seg_o_start));
+        let seg_end = known.len();
+        o_pos += (seg_end - seg_start) as u64;
+        let o_of = |segments: &[(usize, u64)], idx: usize| -> (u64, usize) {
+            let seg = segments.partition_point(|(start, _)| *start <= idx) - 1;
+            let seg_end = segments.get(seg + 1).map(|s| s.0).unwrap_or(seg_end);
+            (segments[seg].1 + (idx - segments[seg].0) as u64, seg_end)
+        };
+
+        let mut lit_start = seg_start;
+        let mut p = seg_start;
+        while p + KEY_LEN <= seg_end {
+            let key: [u8; KEY_LEN] = known[p..p + KEY_LEN].try_into().unwrap();
+            if let Some(&c) = table.get(&key) {
+                let (c_o_pos, c_seg_end) = o_of(&segments, c);
+                let p_o_pos = seg_o_start + (p - seg_start) as u64;
+                //Copy-O may not read bytes it is writing.
+                let max_len = (c_seg_end - c).min(seg_end - p).min((p_o_pos - c_o_pos) as usize);
+                let len = known[c..c + max_len].iter().zip(&known[p..p + max_len]).take_while(|(a, b)| a == b).count();
+                if len >= MIN_COPY_O_LEN {
+                    emit_literal(&known[lit_start..p], &mut output);
+                    push_copies(CopySrc::Output, c_o_pos, len as u64, &mut output);
+                    for q in (p..p + len).filter(|q| q.is_multiple_of(KEY_STRIDE) && q + KEY_LEN <= seg_end) {
+                        table.insert(known[q..q + KEY_LEN].try_into().unwrap(), q);
+                    }
+                    p += len;
+                    lit_start = p;
+                    continue;
+                }
+            }
+            if p.is_multiple_of(KEY_STRIDE) {
+                table.insert(key, p);
+            }
+            p += 1;
+        }
+        emit_literal(&known[lit_start..seg_end], &mut output);
+    }
+    output
+}
+
+fn push_literal(op: &Op, known: &mut Vec<u8>) {
+    match op {
+        Op::Add(add) => known.extend_from_slice(&add.bytes),
+        Op::Run(run) => known.extend(std::iter::repeat_n(run.byte, run.len as usize)),
+        Op::Copy(_) => unreachable!(),
+    }
+}
+
+/// Emits literal bytes as Run ops for longer stretches of a single byte, and Add ops for everything else.
+fn emit_literal(bytes: &[u8], output: &mut Vec<Op>) {
+    let mut add_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let run_len = bytes[i..].iter().take_while(|b| **b == bytes[i]).count();
+        if run_len >= MIN_RUN_LEN {
+            emit_adds(&bytes[add_start..i], output);
+            let mut remaining = run_len;
+            while remaining > 0 {
+                let len = remaining.min(MAX_RUN_LEN as usize);
+                output.push(Op::Run(Run { byte: bytes[i], len: len as u8 }));
+                remaining -= len;
+            }
+            i += run_len;
+            add_start = i;
+        } else {
+            i += run_len;
+        }
+    }
+    emit_adds(&bytes[add_start..], output);
+}
+
+fn emit_adds(bytes: &[u8], output: &mut Vec<Op>) {
+    if bytes.len() == 1 {
+        output.push(Op::Run(Run { byte: bytes[0], len: 1 }));
+        return;
+    }
+    for chunk in bytes.chunks(MAX_INST_SIZE) {
+        output.push(Op::Add(Add { bytes: chunk.to_vec() }));
+    }
+}
+
+/// Splits the op that crosses each window boundary, so every section but the last is exactly `max_win_size` long.
+fn fill_sections(ops: Vec<Op>, max_win_size: usize) -> Vec<Op> {
+    let mut output = Vec::with_capacity(ops.len());
+    let mut space = max_win_size;
+    for op in ops {
+        let len = op.oal() as usize;
+        if len <= space {
+            space -= len;
+            output.push(op);
+            continue;
+        }
+        let mut head = op.clone();
+        head.trunc((len - space) as u32);
+        let mut tail = op;
+        tail.skip(space as u32);
+        if space > 0 {
+            output.push(head);
+        }
+        space = max_win_size - (len - space);
+        output.push(tail);
+    }
+    output
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::test_util::random_bytes;
+    use crate::SummaryPatch;
+    use smdiff_decoder::apply_patch;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_optimize_summary() {
+        let dict = random_bytes(1, 70_000);
+        let added = random_bytes(2, 900);
+        let ops = vec![
+            Op::Add(Add { bytes: added[..400].to_vec() }),
+            Op::Add(Add { bytes: added[400..].to_vec() }),
+            Op::Copy(Copy { src: CopySrc::Dict, addr: 100, len: 30_000 }),
+            Op::Copy(Copy { src: CopySrc::Dict, addr: 30_100, len: 30_000 }),
+            Op::Copy(Copy { src: CopySrc::Dict, addr: 60_100, len: 9_000 }),
+            Op::Run(Run { byte: 0, len: 20 }),
+            //this repeats the first add
+            Op::Add(Add { bytes: added[100..700].to_vec() }),
+            Op::Run(Run { byte: 9, len: 1 }),
+        ];
+        let mut expected_patch = Vec::new();
+        SummaryPatch(ops.clone()).write(&mut expected_patch, None, None, None).unwrap();
+        let optimized = SummaryPatch(ops).optimize(Some(MAX_INST_SIZE));
+        let optimized_ops = optimized.0.clone();
+        //the three copies are joined, then split at the window boundaries
+        assert!(optimized_ops.iter().any(|op| matches!(op, Op::Copy(Copy { src: CopySrc::Output, addr: 100, len: 600 }))), "{:?}", optimized_ops);
+        let ends: Vec<usize> = optimized_ops.iter().scan(0, |sum, op| {
+            *sum += op.oal() as usize;
+            Some(*sum)
+        }).collect();
+        let total = *ends.last().unwrap();
+        for boundary in (MAX_INST_SIZE..total).step_by(MAX_INST_SIZE) {
+            assert!(ends.contains(&boundary), "no op ends at {}", boundary);
+        }
+        let mut patch = Vec::new();
+        optimized.write(&mut patch, Some(MAX_INST_SIZE), None, None).unwrap();
+        assert!(patch.len() + 500 < expected_patch.len(), "{} vs {}", patch.len(), expected_patch.len());
+        let mut expected = Cursor::new(Vec::new());
+        apply_patch(&mut Cursor::new(expected_patch), Some(&mut Cursor::new(&dict)), &mut expected).unwrap();
+        let mut sink = Cursor::new(Vec::new());
+        apply_patch(&mut Cursor::new(patch), Some(&mut Cursor::new(&dict)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), expected.into_inner());
+    }
+
+    #[test]
+    fn test_no_overlapping_copy_o() {
+        //periodic data: the literal matcher makes back to back Copy-O ops of the first period, which must not be joined.
+        let period = random_bytes(3, 32);
+        let data = period.repeat(6);
+        let ops = vec![Op::Add(Add { bytes: data.clone() })];
+        let optimized = SummaryPatch(ops).optimize(None).optimize(None);
+        let mut o_pos = 0;
+        for op in optimized.0.iter() {
+            if let Op::Copy(Copy { src: CopySrc::Output, addr, len }) = op {
+                assert!(addr + *len as u64 <= o_pos, "overlapping {:?} at {}", op, o_pos);
+            }
+            o_pos += op.oal() as u64;
+        }
+        let mut patch = Vec::new();
+        optimized.write(&mut patch, None, None, None).unwrap();
+        let mut sink = Cursor::new(Vec::new());
+        apply_patch::<_, Cursor<Vec<u8>>, _>(&mut Cursor::new(patch), None, &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), data);
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::{Cursor, Read, Seek};
+
+use smdiff_common::{Copy, CopySrc};
+use smdiff_decoder::apply_patch;
+use smdiff_encoder::{encode, EncoderConfig};
+use smdiff_reader::Op;
+
+use crate::{deref_copy_o, extract_patch_instructions, reject_filtered, mapping::{MappingIndex, Piece, Splicer}, SummaryPatch};
+
+/// Moves a patch onto a new dictionary, given a patch between the old and the new dictionary.
+///
+/// Every Copy-D of the A->B patch is looked up in the A->A' patch. The parts of A that survived in A' are copied from there,
+/// only the parts of A that no longer exist in A' are encoded (against A').
+/// Add, Run and Copy-O ops are kept as they are, so B itself is never needed.
+/// # Arguments
+/// * `patch_ab` - The patch to rebase (A->B).
+/// * `patch_aa2` - The patch from the old dictionary to the new dictionary (A->A').
+/// * `dict_a` - The old dictionary (A).
+/// # Returns
+/// The A'->B patch. Use [`SummaryPatch::write`] to write it out.
+/// # Errors
+/// Returns an error if there was an issue reading the patches or the dictionary, or either patch uses a filter.
+pub fn rebase_patch<P: Read + Seek, Q: Read + Seek, D: Read + Seek>(patch_ab: &mut P, patch_aa2: &mut Q, dict_a: &mut D) -> std::io::Result<SummaryPatch> {
+    reject_filtered(patch_ab)?;
+    reject_filtered(patch_aa2)?;
+    let mut dict_a_bytes = Vec::new();
+    dict_a.read_to_end(&mut dict_a_bytes)?;
+    let mut dict_a2 = Cursor::new(Vec::new());
+    apply_patch(patch_aa2, Some(&mut Cursor::new(&dict_a_bytes)), &mut dict_a2)?;
+    patch_aa2.seek(std::io::SeekFrom::Start(0))?;
+    let (aa2_ops, _stats) = extract_patch_instructions(&mut *patch_aa2)?;
+    //A' copying from A, seen from A's side, says where each part of A ended up in A'.
+    let index = MappingIndex::from_copy_d(&deref_copy_o(aa2_ops));
+    rebase_inner(patch_ab, &index, &dict_a_bytes, &dict_a2.into_inner())
+}
+
+/// Same as [`rebase_patch`], but with the new dictionary instead of a patch to it.
+/// The A->A' patch is encoded first with the given config.
+/// # Arguments
+/// * `patch_ab` - The patch to rebase (A->B).
+/// * `dict_a` - The old dictionary (A).
+/// * `dict_a2` - The new dictionary (A').
+/// * `config` - The config used to encode A->A'. Better matching here means more of A is found in A'.
+/// # Errors
+/// Returns an error if there was an issue reading the patch or the dictionaries, or the patch uses a filter.
+pub fn rebase_with_dict<P: Read + Seek, D: Read + Seek>(patch_ab: &mut P, dict_a: &mut D, dict_a2: &mut D, config: &EncoderConfig) -> std::io::Result<SummaryPatch> {
+    reject_filtered(patch_ab)?;
+    let mut dict_a_bytes = Vec::new();
+    dict_a.read_to_end(&mut dict_a_bytes)?;
+    let mut dict_a2_bytes = Vec::new();
+    dict_a2.read_to_end(&mut dict_a2_bytes)?;
+    let mut patch_aa2 = Vec::new();
+    encode(Some(&mut Cursor::new(dict_a_bytes.as_slice())), &mut Cursor::new(dict_a2_bytes.as_slice()), &mut patch_aa2, config)?;
+    let (aa2_ops, _stats) = extract_patch_instructions(Cursor::new(patch_aa2))?;
+    let index = MappingIndex::from_copy_d(&deref_copy_o(aa2_ops));
+    rebase_inner(patch_ab, &index, &dict_a_bytes, &dict_a2_bytes)
+}
+
+fn rebase_inner<P: Read + Seek>(patch_ab: &mut P, index: &MappingIndex, dict_a: &[u8], dict_a2: &[u8]) -> std::io::Result<SummaryPatch> {
+    let (ab_ops, _stats) = extract_patch_instructions(&mut *patch_ab)?;
+    //first find everything that is gone, so it can be encoded in one go.
+    let mut gap_bytes = Vec::new();
+    let mut pieces: Vec<Piece> = Vec::new();
+    for (_, op) in ab_ops.iter() {
+        if let Op::Copy(Copy { src: CopySrc::Dict, addr, len }) = op {
+            let end = addr + *len as u64;
+            if end > dict_a.len() as u64 {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Copy {}..{} is past the end of the dictionary", addr, end)));
+            }
+            pieces.clear();
+            index.cover(*addr, end, &mut pieces);
+            let mut pos = *addr as usize;
+            for piece in pieces.iter() {
+                if piece.trgt_addr.is_none() {
+                    gap_bytes.extend_from_slice(&dict_a[pos..pos + piece.len as usize]);
+                }
+                pos += piece.len as usize;
+            }
+        }
+    }
+    let mut splicer = Splicer::new(&gap_bytes, dict_a2)?;
+    let mut output = Vec::with_capacity(ab_ops.len());
+    for (_, op) in ab_ops {
+        match op {
+            Op::Copy(Copy { src: CopySrc::Dict, addr, len }) => {
+                pieces.clear();
+                index.cover(addr, addr + len as u64, &mut pieces);
+                splicer.push(&pieces, &mut output)?;
+            },
+            //output positions do not change, so Copy-O stays valid.
+            op => output.push(op),
+        }
+    }
+    Ok(SummaryPatch(output))
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::test_util::random_bytes;
+
+    fn encode_bytes(src: &[u8], trgt: &[u8]) -> Vec<u8> {
+        let mut patch = Vec::new();
+        encode(Some(&mut Cursor::new(src)), &mut Cursor::new(trgt), &mut patch, &EncoderConfig::default()).unwrap();
+        patch
+    }
+
+    #[test]
+    fn test_rebase_patch() {
+        let a = random_bytes(1, 30_000);
+        //the hotfix moves a block and drops another
+        let mut a2 = a[..5000].to_vec();
+        a2.extend_from_slice(&a[20_000..25_000]);
+        a2.extend_from_slice(&a[5000..20_000]);
+        a2.extend_from_slice(&a[26_000..]);
+        let mut b = a[2000..28_000].to_vec();
+        b[10_000..10_100].copy_from_slice(&random_bytes(2, 100));
+        let patch_ab = encode_bytes(&a, &b);
+        let patch_aa2 = encode_bytes(&a, &a2);
+        let rebased = rebase_patch(&mut Cursor::new(patch_ab.clone()), &mut Cursor::new(patch_aa2), &mut Cursor::new(&a)).unwrap();
+        let mut rebased_patch = Vec::new();
+        rebased.write(&mut rebased_patch, None, None, None).unwrap();
+        //the 1000 bytes of A that A' dropped have to be added
+        assert!(rebased_patch.len() < 1500, "rebased patch too large: {}", rebased_patch.len());
+        let mut sink = Cursor::new(Vec::new());
+        apply_patch(&mut Cursor::new(&rebased_patch), Some(&mut Cursor::new(&a2)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), b);
+
+        let rebased = rebase_with_dict(&mut Cursor::new(patch_ab), &mut Cursor::new(a.clone()), &mut Cursor::new(a2.clone()), &EncoderConfig::default()).unwrap();
+        let mut rebased_patch = Vec::new();
+        rebased.write(&mut rebased_patch, None, None, None).unwrap();
+        let mut sink = Cursor::new(Vec::new());
+        apply_patch(&mut Cursor::new(&rebased_patch), Some(&mut Cursor::new(&a2)), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), b);
+    }
+}
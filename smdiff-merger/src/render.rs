@@ -0,0 +1,187 @@
+//! Shows a patch as a unified diff, for text inputs.
+//!
+//! The patch ops are read as an alignment between the dictionary and the output: in order Copy-D ops keep dictionary bytes,
+//! skipped dictionary bytes are removed, and everything else (Adds, Runs, Copy-O and out of order Copy-D) is new.
+//! A line is shown as unchanged when one kept stretch covers it whole on both sides, every other line as removed or added.
+//! Patches made with `EncoderConfig::match_lines` line up exactly with the line edits. Byte level patches show every line they touch.
+use std::io::{Cursor, Read, Seek};
+use std::ops::Range;
+
+use smdiff_common::{filter::read_filter_header, CopySrc};
+use smdiff_decoder::apply_patch;
+use smdiff_encoder::split_lines;
+
+use crate::extract_patch_instructions;
+
+/// Default number of unchanged lines shown around each change.
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Line {
+    Same(usize, usize),
+    Removed(usize),
+    Added(usize),
+}
+
+/// Renders the patch as a unified diff of the dictionary (`--- dictionary`) and the output it produces (`+++ output`).
+/// # Arguments
+/// * `patch` - The patch to render.
+/// * `dict` - The dictionary the patch applies to.
+/// * `context_lines` - The number of unchanged lines to show around each change.
+/// # Returns
+/// The diff text. It is empty if the output equals the dictionary.
+/// # Errors
+/// Returns an error if the patch can not be applied, it uses a filter, or the dictionary or the output are not UTF-8.
+pub fn render_patch<P: Read + Seek, D: Read + Seek>(patch: &mut P, dict: &mut D, context_lines: usize) -> std::io::Result<String> {
+    if read_filter_header(patch)?.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "A filtered patch can not be rendered as text"));
+    }
+    patch.seek(std::io::SeekFrom::Start(0))?;
+    let mut dict_bytes = Vec::new();
+    dict.read_to_end(&mut dict_bytes)?;
+    let mut output = Cursor::new(Vec::new());
+    apply_patch(patch, Some(&mut Cursor::new(&dict_bytes)), &mut output)?;
+    let output = output.into_inner();
+    patch.seek(std::io::SeekFrom::Start(0))?;
+    let (ops, _stats) = extract_patch_instructions(&mut *patch)?;
+
+    let not_utf8 = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("The {} is not UTF-8", what));
+    let old = std::str::from_utf8(&dict_bytes).map_err(|_| not_utf8("dictionary"))?;
+    let new = std::str::from_utf8(&output).map_err(|_| not_utf8("output"))?;
+
+    //The kept stretches, as (dict start, output start, len).
+    let mut kept: Vec<(usize, usize, usize)> = Vec::new();
+    let mut d_pos = 0;
+    for (o_pos, op) in ops.iter() {
+        if let smdiff_common::Op::Copy(copy) = op {
+            let addr = copy.addr as usize;
+            if copy.src == CopySrc::Dict && addr >= d_pos {
+                match kept.last_mut() {
+                    Some((d, o, len)) if *d + *len == addr && *o + *len == *o_pos as usize => *len += copy.len as usize,
+                    _ => kept.push((addr, *o_pos as usize, copy.len as usize)),
+                }
+                d_pos = addr + copy.len as usize;
+            }
+        }
+    }
+    let old_lines = split_lines(old.as_bytes());
+    let new_lines = split_lines(new.as_bytes());
+    let lines = align_lines(&kept, &old_lines, &new_lines);
+    Ok(format_hunks(&lines, old, &old_lines, new, &new_lines, context_lines))
+}
+
+/// Pairs up the lines that a kept stretch covers whole on both sides. All other lines are removed or added.
+fn align_lines(kept: &[(usize, usize, usize)], old_lines: &[Range<usize>], new_lines: &[Range<usize>]) -> Vec<Line> {
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for &(d, o, len) in kept {
+        while i < old_lines.len() && old_lines[i].start < d {
+            i += 1;
+        }
+        while i < old_lines.len() && old_lines[i].end <= d + len {
+            let o_start = o + (old_lines[i].start - d);
+            let o_end = o + (old_lines[i].end - d);
+            while j < new_lines.len() && new_lines[j].start < o_start {
+                j += 1;
+            }
+            if j < new_lines.len() && new_lines[j] == (o_start..o_end) {
+                pairs.push((i, j));
+                j += 1;
+            }
+            i += 1;
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (pi, pj) in pairs.into_iter().chain(std::iter::once((old_lines.len(), new_lines.len()))) {
+        lines.extend((i..pi).map(Line::Removed));
+        lines.extend((j..pj).map(Line::Added));
+        if pi < old_lines.len() {
+            lines.push(Line::Same(pi, pj));
+        }
+        (i, j) = (pi + 1, pj + 1);
+    }
+    lines
+}
+
+fn format_hunks(lines: &[Line], old: &str, old_lines: &[Range<usize>], new: &str, new_lines: &[Range<usize>], context_lines: usize) -> String {
+    let changes: Vec<usize> = lines.iter().enumerate().filter(|(_, l)| !matches!(l, Line::Same(..))).map(|(i, _)| i).collect();
+    let mut out = String::new();
+    if changes.is_empty() {
+        return out;
+    }
+    out.push_str("--- dictionary\n+++ output\n");
+    //Line numbers before each entry.
+    let mut positions = Vec::with_capacity(lines.len());
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for line in lines {
+        positions.push((old_pos, new_pos));
+        match line {
+            Line::Same(..) => (old_pos, new_pos) = (old_pos + 1, new_pos + 1),
+            Line::Removed(_) => old_pos += 1,
+            Line::Added(_) => new_pos += 1,
+        }
+    }
+    let mut c = 0;
+    while c < changes.len() {
+        let start = changes[c].saturating_sub(context_lines);
+        let mut end = changes[c] + 1;
+        while c + 1 < changes.len() && changes[c + 1] - end <= 2 * context_lines {
+            c += 1;
+            end = changes[c] + 1;
+        }
+        let end = (end + context_lines).min(lines.len());
+        let hunk = &lines[start..end];
+        let old_count = hunk.iter().filter(|l| !matches!(l, Line::Added(_))).count();
+        let new_count = hunk.iter().filter(|l| !matches!(l, Line::Removed(_))).count();
+        //Per the unified format, an empty side gives the line before it.
+        let (old_start, new_start) = positions[start];
+        let old_start = if old_count == 0 { old_start } else { old_start + 1 };
+        let new_start = if new_count == 0 { new_start } else { new_start + 1 };
+        out.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start, old_count, new_start, new_count));
+        for line in hunk {
+            let (prefix, text) = match *line {
+                Line::Same(_, j) => (' ', &new[new_lines[j].clone()]),
+                Line::Removed(i) => ('-', &old[old_lines[i].clone()]),
+                Line::Added(j) => ('+', &new[new_lines[j].clone()]),
+            };
+            out.push(prefix);
+            out.push_str(text);
+            if !text.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+        c += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use smdiff_encoder::{encode, EncoderConfig, LineMatcherConfig};
+
+    #[test]
+    fn test_render_patch() {
+        let old: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+        let new = old.replace("line 5\n", "line five\n").replace("line 17\n", "").replace("line 20\n", "line 20\nline 21");
+        let mut patch = Vec::new();
+        let config = EncoderConfig::default().set_match_lines(LineMatcherConfig::default());
+        encode(Some(&mut Cursor::new(old.as_bytes())), &mut Cursor::new(new.as_bytes()), &mut patch, &config).unwrap();
+        let diff = render_patch(&mut Cursor::new(&patch), &mut Cursor::new(old.as_bytes()), DEFAULT_CONTEXT_LINES).unwrap();
+        let expected = "--- dictionary\n+++ output\n\
+            @@ -2,7 +2,7 @@\n line 2\n line 3\n line 4\n-line 5\n+line five\n line 6\n line 7\n line 8\n\
+            @@ -14,7 +14,7 @@\n line 14\n line 15\n line 16\n-line 17\n line 18\n line 19\n line 20\n+line 21\n\\ No newline at end of file\n";
+        assert_eq!(diff, expected);
+
+        //A byte level patch shows the lines it touches.
+        let mut patch = Vec::new();
+        encode(Some(&mut Cursor::new(old.as_bytes())), &mut Cursor::new(new.as_bytes()), &mut patch, &EncoderConfig::default()).unwrap();
+        let diff = render_patch(&mut Cursor::new(&patch), &mut Cursor::new(old.as_bytes()), 0).unwrap();
+        assert!(diff.contains("-line 5\n+line five\n"), "{}", diff);
+        assert!(diff.contains("-line 17\n"), "{}", diff);
+
+        let err = render_patch(&mut Cursor::new(&patch), &mut Cursor::new(vec![0xff; old.len()]), 3).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,402 @@
+//! A merger that keeps the summary patch on disk instead of in memory.
+//!
+//! [`crate::Merger`] holds every op of the summary patch (and of the patch being merged) in memory.
+//! [`StreamingMerger`] instead spills the ops to temporary files, using a compact fixed layout with absolute addresses.
+//! Every [`INDEX_INTERVAL`] ops the output position and file offset are recorded, so the op covering any output position
+//! can be found with a binary search and a short sequential scan.
+//!
+//! Merging streams the summary patch in output order. Each Copy-D is resolved against the indexed predecessor and written to a new spill file.
+//! Memory use is bounded by the checkpoint indices, one section worth of ops when reading a patch, and one window when writing the result.
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use smdiff_common::{read_u8, read_u_varint, write_u8, write_u_varint, Copy, CopySrc, Format, Run, SectionHeader, MAX_INST_SIZE, MAX_WIN_SIZE};
+use smdiff_decoder::reader::SectionIterator;
+use smdiff_encoder::{writer::section_writer, SecondaryCompression};
+use smdiff_reader::{Add, Op};
+
+use crate::{reject_filtered, MergeOp};
+
+/// Number of ops between two checkpoints of the spill index.
+pub const INDEX_INTERVAL: usize = 256;
+
+const TAG_ADD: u8 = 0;
+const TAG_RUN: u8 = 1;
+const TAG_COPY_D: u8 = 2;
+const TAG_COPY_O: u8 = 3;
+
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A list of ops stored in a file.
+/// * index: (output position, file offset) of every INDEX_INTERVAL'th op.
+/// * len: The total output size of the ops.
+/// * num_copy_d: The number of Copy-D ops in the file.
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    index: Vec<(u64, u64)>,
+    len: u64,
+    num_ops: u64,
+    num_copy_d: u64,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+struct SpillWriter {
+    file: BufWriter<File>,
+    spill: SpillFile,
+    file_pos: u64,
+    buf: Vec<u8>,
+}
+
+impl SpillWriter {
+    fn create(path: PathBuf) -> std::io::Result<Self> {
+        let file = BufWriter::new(File::options().read(true).write(true).create_new(true).open(&path)?);
+        let spill = SpillFile { path, index: Vec::new(), len: 0, num_ops: 0, num_copy_d: 0 };
+        Ok(Self { file, spill, file_pos: 0, buf: Vec::new() })
+    }
+    fn push(&mut self, op: &Op) -> std::io::Result<()> {
+        if self.spill.num_ops.is_multiple_of(INDEX_INTERVAL as u64) {
+            self.spill.index.push((self.spill.len, self.file_pos));
+        }
+        self.buf.clear();
+        write_spill_op(&mut self.buf, op)?;
+        self.file.write_all(&self.buf)?;
+        self.file_pos += self.buf.len() as u64;
+        self.spill.len += op.oal() as u64;
+        self.spill.num_ops += 1;
+        if matches!(op, Op::Copy(Copy { src: CopySrc::Dict, .. })) {
+            self.spill.num_copy_d += 1;
+        }
+        Ok(())
+    }
+    fn finish(mut self) -> std::io::Result<SpillFile> {
+        self.file.flush()?;
+        Ok(self.spill)
+    }
+}
+
+fn write_spill_op<W: Write>(writer: &mut W, op: &Op) -> std::io::Result<()> {
+    match op {
+        Op::Add(add) => {
+            write_u8(writer, TAG_ADD)?;
+            write_u_varint(writer, add.bytes.len() as u64)?;
+            writer.write_all(&add.bytes)
+        },
+        Op::Run(run) => {
+            write_u8(writer, TAG_RUN)?;
+            write_u8(writer, run.byte)?;
+            write_u8(writer, run.len)
+        },
+        Op::Copy(copy) => {
+            write_u8(writer, if copy.src == CopySrc::Dict { TAG_COPY_D } else { TAG_COPY_O })?;
+            write_u_varint(writer, copy.addr)?;
+            write_u_varint(writer, copy.len as u64)
+        },
+    }
+}
+
+/// Returns None at the end of the file.
+fn read_spill_op<R: Read>(reader: &mut R) -> std::io::Result<Option<Op>> {
+    let mut tag = [0u8; 1];
+    if reader.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+    let op = match tag[0] {
+        TAG_ADD => {
+            let len = read_u_varint(reader)? as usize;
+            let mut bytes = vec![0u8; len];
+            reader.read_exact(&mut bytes)?;
+            Op::Add(Add { bytes })
+        },
+        TAG_RUN => {
+            let byte = read_u8(reader)?;
+            Op::Run(Run { byte, len: read_u8(reader)? })
+        },
+        TAG_COPY_D | TAG_COPY_O => {
+            let src = if tag[0] == TAG_COPY_D { CopySrc::Dict } else { CopySrc::Output };
+            let addr = read_u_varint(reader)?;
+            Op::Copy(Copy { src, addr, len: read_u_varint(reader)? as u16 })
+        },
+        t => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid spill op tag {}", t))),
+    };
+    Ok(Some(op))
+}
+
+/// Random access reader over a spill file, by output position.
+struct SpillCursor {
+    file: BufReader<File>,
+    /// Output position of the next op to be read.
+    o_pos: u64,
+    /// Set when `o_pos` is not known (right after opening).
+    lost: bool,
+}
+
+impl SpillCursor {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        Ok(Self { file: BufReader::new(File::open(path)?), o_pos: 0, lost: true })
+    }
+    /// Calls `f` with the ops of `spill` that exactly cover `start..start+len`, clipped to that range.
+    fn resolve<F: FnMut(Op) -> std::io::Result<()>>(&mut self, spill: &SpillFile, start: u64, len: u64, mut f: F) -> std::io::Result<()> {
+        let end = start + len;
+        if end > spill.len {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Copy {}..{} is past the end of the output ({})", start, end, spill.len)));
+        }
+        //an empty spill file has no checkpoints, its ops (none) start at the start of the file.
+        let checkpoint = spill.index.partition_point(|(o_pos, _)| *o_pos <= start).checked_sub(1).map_or((0, 0), |i| spill.index[i]);
+        //sequential copies are common, so keep reading from where we are if it is in the same block.
+        if self.lost || self.o_pos < checkpoint.0 || self.o_pos > start {
+            self.file.seek(SeekFrom::Start(checkpoint.1))?;
+            self.o_pos = checkpoint.0;
+            self.lost = false;
+        }
+        while self.o_pos < end {
+            let mut op = read_spill_op(&mut self.file)?
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Spill file ended early"))?;
+            let op_start = self.o_pos;
+            let op_end = op_start + op.oal() as u64;
+            self.o_pos = op_end;
+            if op_end <= start {
+                continue;
+            }
+            if op_start < start {
+                op.skip((start - op_start) as u32);
+            }
+            if op_end > end {
+                op.trunc((op_end - end) as u32);
+            }
+            f(op)?;
+        }
+        Ok(())
+    }
+}
+
+/// Like [`crate::Merger`], but keeps the patches on disk so memory use does not grow with the size or number of the patches.
+///
+/// Patches are given from the newest (terminal) to the oldest, same as [`crate::Merger`].
+pub struct StreamingMerger {
+    spill_dir: PathBuf,
+    summary: SpillFile,
+}
+
+impl StreamingMerger {
+    /// Creates a new merger from the terminal patch, spilling to the system temp directory.
+    pub fn new<R: Read + Seek>(terminal_patch: R) -> std::io::Result<Self> {
+        Self::with_spill_dir(terminal_patch, &std::env::temp_dir())
+    }
+    /// Creates a new merger from the terminal patch, spilling to the given directory.
+    /// The temporary files are removed when the merger is dropped.
+    pub fn with_spill_dir<R: Read + Seek>(terminal_patch: R, spill_dir: &Path) -> std::io::Result<Self> {
+        let spill_dir = spill_dir.to_path_buf();
+        let summary = ingest_patch(terminal_patch, spill_path(&spill_dir))?;
+        Ok(Self { spill_dir, summary })
+    }
+    /// Returns true if the summary patch still has Copy-D ops.
+    /// If it has none, merging more patches has no effect.
+    pub fn has_dict_copies(&self) -> bool {
+        self.summary.num_copy_d > 0
+    }
+    /// The output size of the summary patch.
+    pub fn output_len(&self) -> u64 {
+        self.summary.len
+    }
+    /// Merges a predecessor patch into the summary patch.
+    /// # Arguments
+    /// * `predecessor_patch` - The patch that produced the dictionary of the current summary patch.
+    /// # Errors
+    /// Returns an error if a patch is invalid or uses a filter, if the summary copies past the end of the predecessor's output, or if the spill files could not be written.
+    pub fn merge<R: Read + Seek>(mut self, predecessor_patch: R) -> std::io::Result<Self> {
+        if !self.has_dict_copies() {
+            return Ok(self);
+        }
+        let predecessor = ingest_patch(predecessor_patch, spill_path(&self.spill_dir))?;
+        let mut pred_cursor = SpillCursor::open(&predecessor.path)?;
+        let mut writer = SpillWriter::create(spill_path(&self.spill_dir))?;
+        let mut summary = BufReader::new(File::open(&self.summary.path)?);
+        while let Some(op) = read_spill_op(&mut summary)? {
+            match op {
+                Op::Copy(Copy { src: CopySrc::Dict, addr, len }) => {
+                    pred_cursor.resolve(&predecessor, addr, len as u64, |op| writer.push(&op))?;
+                },
+                op => writer.push(&op)?,
+            }
+        }
+        //dropping the old summary removes its file
+        self.summary = writer.finish()?;
+        Ok(self)
+    }
+    /// Writes the summary patch to a sink.
+    /// Only one window of ops is held in memory at a time.
+    /// # Arguments
+    /// * `sink` - The sink to write the summary patch to.
+    /// * `max_win_size` - The maximum output size for any window of instructions.
+    /// * `format` - The format of the sections, Interleaved if None.
+    /// * `sec_comp` - The secondary compression to use, if any.
+    pub fn finish<W: Write>(self, sink: &mut W, max_win_size: Option<usize>, format: Option<Format>, sec_comp: Option<SecondaryCompression>) -> std::io::Result<()> {
+        let max_win_size = max_win_size.unwrap_or(MAX_WIN_SIZE).clamp(MAX_INST_SIZE, MAX_WIN_SIZE) as u32;
+        let format = format.unwrap_or(Format::Interleaved);
+        let mut summary = BufReader::new(File::open(&self.summary.path)?);
+        let mut section: Vec<Op> = Vec::new();
+        let mut header = SectionHeader::new(0, 0, 0).set_format(format);
+        let mut sec_data_buffer = Vec::new();
+        while let Some(op) = read_spill_op(&mut summary)? {
+            let op_size = op.oal() as u32;
+            if header.output_size + op_size > max_win_size {
+                header.num_operations = section.len() as u32;
+                section_writer(&sec_comp, header.set_more_sections(true), sink, &section, &mut sec_data_buffer)?;
+                section.clear();
+                header = SectionHeader::new(0, 0, 0).set_format(format);
+            }
+            if op.is_add() {
+                header.num_add_bytes += op_size;
+            }
+            header.output_size += op_size;
+            section.push(op);
+        }
+        header.num_operations = section.len() as u32;
+        section_writer(&sec_comp, header, sink, &section, &mut sec_data_buffer)
+    }
+}
+
+fn spill_path(spill_dir: &Path) -> PathBuf {
+    let n = SPILL_COUNTER.fetch_add(1, Ordering::Relaxed);
+    spill_dir.join(format!("smdiff-merge-{}-{}.spill", std::process::id(), n))
+}
+
+/// Reads a patch section by section into a spill file, resolving Copy-O ops against what was already spilled.
+fn ingest_patch<R: Read + Seek>(mut patch: R, path: PathBuf) -> std::io::Result<SpillFile> {
+    reject_filtered(&mut patch)?;
+    let mut writer = SpillWriter::create(path)?;
+    let mut cursor: Option<SpillCursor> = None;
+    let mut resolved = Vec::new();
+    //output size that is already in the file, copies below it can be resolved without flushing.
+    let mut flushed_len = 0;
+    let mut sections = SectionIterator::new(patch);
+    while let Some(res) = sections.next_borrowed() {
+        let (ops, _header) = res?;
+        for op in ops {
+            match op {
+                Op::Copy(Copy { src: CopySrc::Output, addr, len }) => {
+                    let must_flush = *addr + *len as u64 > flushed_len;
+                    if must_flush {
+                        writer.file.flush()?;
+                        flushed_len = writer.spill.len;
+                    }
+                    let cursor = match cursor.as_mut() {
+                        Some(c) => c,
+                        None => cursor.insert(SpillCursor::open(&writer.spill.path)?),
+                    };
+                    if must_flush {
+                        //the file grew since the cursor last read it, so its buffer may be stale.
+                        cursor.lost = true;
+                    }
+                    resolved.clear();
+                    cursor.resolve(&writer.spill, *addr, *len as u64, |op| {
+                        resolved.push(op);
+                        Ok(())
+                    })?;
+                    for op in resolved.iter() {
+                        writer.push(op)?;
+                    }
+                },
+                op => writer.push(op)?,
+            }
+        }
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod test_super {
+    use std::io::Cursor;
+
+    use smdiff_decoder::apply_patch;
+    use smdiff_encoder::{encode, EncoderConfig, TrgtMatcherConfig};
+
+    use super::*;
+    use crate::test_util::random_bytes;
+
+    #[test]
+    fn test_resolve_empty_spill() {
+        let spill = SpillWriter::create(spill_path(&std::env::temp_dir())).unwrap().finish().unwrap();
+        let mut cursor = SpillCursor::open(&spill.path).unwrap();
+        let mut ops = Vec::new();
+        cursor.resolve(&spill, 0, 0, |op| { ops.push(op); Ok(()) }).unwrap();
+        assert!(ops.is_empty());
+        let err = cursor.resolve(&spill, 0, 1, |_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_many_copy_o() {
+        //copies of old output (already flushed) mixed with copies of the output just written.
+        let mut ops = vec![Op::Add(Add { bytes: random_bytes(7, 200) })];
+        let mut output_len = 200u64;
+        let mut addrs = crate::test_util::random_stream(8);
+        for i in 0..3000u64 {
+            let len = 4 + (i % 13) as u16;
+            let addr = if i % 5 == 0 { output_len - len as u64 } else { addrs.next().unwrap() % (output_len - len as u64) };
+            ops.push(Op::Copy(Copy { src: CopySrc::Output, addr, len }));
+            output_len += len as u64;
+            if i % 100 == 0 {
+                ops.push(Op::Add(Add { bytes: random_bytes(100 + i, 3) }));
+                output_len += 3;
+            }
+        }
+        let mut patch = Vec::new();
+        let header = SectionHeader { num_operations: ops.len() as u32, num_add_bytes: 0, output_size: output_len as u32, compression_algo: 0, format: Format::Interleaved, more_sections: false };
+        smdiff_writer::write_section_header(&header, &mut patch).unwrap();
+        smdiff_writer::write_ops(&ops, &header, &mut patch).unwrap();
+        let mut expected = Cursor::new(Vec::new());
+        apply_patch::<_, Cursor<Vec<u8>>, _>(&mut Cursor::new(&patch), None, &mut expected).unwrap();
+
+        let merger = StreamingMerger::new(Cursor::new(&patch)).unwrap();
+        assert!(!merger.has_dict_copies());
+        assert_eq!(merger.output_len(), output_len);
+        let mut summary = Vec::new();
+        merger.finish(&mut summary, None, None, None).unwrap();
+        let mut sink = Cursor::new(Vec::new());
+        apply_patch::<_, Cursor<Vec<u8>>, _>(&mut Cursor::new(summary), None, &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), expected.into_inner());
+    }
+
+    #[test]
+    fn test_streaming_merge_chain() {
+        //v0 -> v1 -> v2 -> v3, each version edits the previous one.
+        let mut versions = vec![random_bytes(1, 50_000)];
+        for i in 0..3u64 {
+            let prev = versions.last().unwrap();
+            let mut next = prev[..10_000 * (i as usize + 1)].to_vec();
+            next.extend_from_slice(&random_bytes(10 + i, 700));
+            next.extend_from_slice(&prev[10_000 * (i as usize + 1) + 300..]);
+            //some repetition, so the patches have Copy-O ops
+            next.extend_from_slice(&prev[..2000]);
+            versions.push(next);
+        }
+        let config = EncoderConfig::default().set_match_target(TrgtMatcherConfig::comp_level(3));
+        let patches: Vec<Vec<u8>> = versions.windows(2).map(|w| {
+            let mut patch = Vec::new();
+            encode(Some(&mut Cursor::new(w[0].as_slice())), &mut Cursor::new(w[1].as_slice()), &mut patch, &config).unwrap();
+            patch
+        }).collect();
+        let mut merger = StreamingMerger::new(Cursor::new(&patches[2])).unwrap();
+        for patch in patches[..2].iter().rev() {
+            merger = merger.merge(Cursor::new(patch)).unwrap();
+        }
+        assert_eq!(merger.output_len(), versions[3].len() as u64);
+        let spill = merger.summary.path.clone();
+        let mut summary = Vec::new();
+        //small windows, so several sections are written.
+        merger.finish(&mut summary, Some(MAX_INST_SIZE), None, None).unwrap();
+        assert!(!spill.exists());
+        let mut sink = Cursor::new(Vec::new());
+        apply_patch(&mut Cursor::new(summary), Some(&mut Cursor::new(&versions[0])), &mut sink).unwrap();
+        assert_eq!(sink.into_inner(), versions[3]);
+    }
+}
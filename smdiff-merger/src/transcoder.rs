@@ -1,6 +1,6 @@
 use std::io::{Read, Seek, Write};
 
-use smdiff_common::{CopySrc, MAX_INST_SIZE, MAX_RUN_LEN};
+use smdiff_common::{progress::{check_cancelled, Observer, Phase}, run::RunCache, MAX_INST_SIZE};
 use smdiff_reader::{Add, Op};
 
 use crate::extract_patch_instructions;
@@ -16,7 +16,7 @@ enum InnerOp{
 /// * It groups adjacent Add operations together, and joins adjacent Run operations.
 /// * It then makes sure that Add operations are no larger than the maximum instruction size.
 /// * The Run operations are also optimized to be no larger than the maximum run length.
-/// * If a Run is long enough, it this fn will encode them using progressively larger Copy operations, until the whole run length is covered.
+/// * Long Runs are encoded with progressively larger Copy operations, or copied from an earlier run of the same byte (see `smdiff_common::run`).
 ///
 ///
 /// # Arguments
@@ -28,7 +28,7 @@ enum InnerOp{
 ///
 /// # Errors
 /// Returns an error if there was an issue reading from the input or writing to the output.
-/// Can also error if there are any invalid operations in the input patch.
+/// Can also error if there are any invalid operations in the input patch, or it uses a filter.
 pub fn transcode<R,W>(
     input: &mut R,
     output: &mut W,
@@ -40,15 +40,83 @@ where
     R: Read+Seek,
     W: Write,
 {
+    transcode_with_observer(input, output, format, sec_comp, output_segment_size, &mut ())
+}
+
+/// Same as [`transcode`], but reports progress to the observer, which can also cancel the transcode.
+///
+/// # Arguments
+/// * `observer` - Told when the input is read, then about every section written.
+///
+/// The other arguments are the same as for [`transcode`].
+///
+/// # Errors
+/// Same as [`transcode`], and a [`smdiff_common::progress::Cancelled`] error if the observer cancelled (the output then holds an incomplete patch).
+pub fn transcode_with_observer<R,W>(
+    input: &mut R,
+    output: &mut W,
+    format: smdiff_common::Format,
+    sec_comp: Option<smdiff_encoder::SecondaryCompression>,
+    output_segment_size: usize,
+    observer: &mut dyn Observer,
+) -> std::io::Result<()>
+where
+    R: Read+Seek,
+    W: Write,
+{
+    observer.phase(Phase::Reading);
     let ops = optimize_and_convert_ops(read_ops_from_patch(input)?);
     let mut win_data = Vec::new();
-    for (seg_ops, mut header) in crate::make_sections(&ops, output_segment_size) {
+    observer.phase(if sec_comp.is_some() { Phase::Compressing } else { Phase::Writing });
+    let mut o_pos = 0u64;
+    for (i, (seg_ops, mut header)) in crate::make_sections(&ops, output_segment_size).into_iter().enumerate() {
+        check_cancelled(observer)?;
         header.format = format;
+        o_pos += header.output_size as u64;
         smdiff_encoder::writer::section_writer(
             &sec_comp,
             header,
             output,
-            &seg_ops,
+            seg_ops,
+            &mut win_data,
+        )?;
+        observer.sections_processed(i + 1);
+        observer.bytes_processed(o_pos);
+    }
+
+    Ok(())
+}
+
+/// Same as [`transcode`], but writes each section in whichever format (Interleaved or Segregated) comes out smaller.
+/// Each section is compressed twice, so this is about twice as slow.
+///
+/// # Arguments
+/// * `input` - The input patch to transcode.
+/// * `output` - The writer to write the transcoded patch to.
+/// * `sec_comp` - The secondary compression to use, if any.
+/// * `output_segment_size` - The size of the output segments.
+///
+/// # Errors
+/// Returns an error if there was an issue reading from the input or writing to the output.
+/// Can also error if there are any invalid operations in the input patch, or it uses a filter.
+pub fn transcode_adaptive<R,W>(
+    input: &mut R,
+    output: &mut W,
+    sec_comp: Option<smdiff_encoder::SecondaryCompression>,
+    output_segment_size: usize,
+) -> std::io::Result<()>
+where
+    R: Read+Seek,
+    W: Write,
+{
+    let ops = optimize_and_convert_ops(read_ops_from_patch(input)?);
+    let mut win_data = Vec::new();
+    for (seg_ops, header) in crate::make_sections(&ops, output_segment_size) {
+        smdiff_encoder::writer::adaptive_section_writer(
+            &sec_comp,
+            header,
+            output,
+            seg_ops,
             &mut win_data,
         )?;
     }
@@ -71,11 +139,12 @@ fn optimize_and_convert_ops(mut ops: Vec<InnerOp>)->Vec<Op> {
     join_adjacent_adds(&mut ops);
     join_adjacent_runs(&mut ops);
     let mut out_ops = Vec::with_capacity(ops.len());
+    let mut runs = RunCache::new();
     for iop in ops {
         match iop {
             InnerOp::Add(bytes) if !bytes.is_empty() => make_add_ops(bytes, &mut out_ops),
             InnerOp::Copy(copy) => out_ops.push(Op::Copy(copy)),
-            InnerOp::Run{byte, len,output_start_pos} if len>0 => make_run_ops(byte, len, output_start_pos,&mut out_ops),
+            InnerOp::Run{byte, len,output_start_pos} if len>0 => runs.make_run_ops(byte, len, output_start_pos as u64, &mut out_ops),
             _ => ()
         }
     }
@@ -135,37 +204,6 @@ pub fn make_add_ops<'a>(bytes: Vec<u8>, output: &mut Vec<Op>){
     }
 }
 
-// This is directly lifted from the smdiff-encoder crate, so check there for more details/tests
-const RUN_LIMIT: usize = (MAX_RUN_LEN as usize) * 6;
-const COPY_LIMIT: usize = RUN_LIMIT/2;
-fn make_run_ops(byte:u8, len:usize, run_start_pos:usize, output: &mut Vec<Op>){
-    if len < RUN_LIMIT {
-        let mut processed = 0;
-        while processed < len {
-            let remaining = len - processed;
-            let chunk_size = (MAX_RUN_LEN as usize).min(remaining);
-            let op = Op::Run(smdiff_common::Run{byte, len: chunk_size as u8});
-            output.push(op);
-            processed += chunk_size;
-        };
-    }else{
-        //we can use one or more copies on 3 runs.
-        //we need to emit the three runs, then make the copies from the stack
-        output.extend(std::iter::repeat_with(|| Op::Run(smdiff_common::Run{byte, len: MAX_RUN_LEN})).take(3));
-
-        let copy_bytes = len - COPY_LIMIT;
-        let mut processed = 0;
-        let mut max_copy_size = COPY_LIMIT;
-        while processed < copy_bytes{
-            let copy_size = max_copy_size.min(copy_bytes - processed).min(MAX_INST_SIZE);
-            let op = Op::Copy(smdiff_common::Copy{src: CopySrc::Output, addr: run_start_pos as u64, len: copy_size as u16});
-            output.push(op);
-            processed += copy_size;
-            max_copy_size += copy_size;
-        }
-    }
-}
-
 
 #[cfg(test)]
 mod tests {
@@ -264,10 +264,8 @@ pub fn new_encode_test_gcc_2951_2952()-> Result<(), Box<dyn std::error::Error>>
 
 
     .set_match_src(
-        SrcMatcherConfig {
-            l_step: 2,
-            max_src_win_size: None,//None, //Some(1 << 24),
-        }).set_lazy_escape_len(90)
+        SrcMatcherConfig::new(2, None)//None, //Some(1 << 24),
+        ).set_lazy_escape_len(90)
     ;
     smdiff_encoder::encode(
         Some(&mut src),
@@ -196,6 +196,33 @@ mod test_super {
 
     }
     #[test]
+    fn test_encoded_size() {
+        let ops= vec![
+            Op::Add(Add::new(vec![7; 63])),
+            Op::Copy(Copy { src: CopySrc::Dict, addr: 100_000, len: 4 }),
+            Op::Run(Run { byte: 1, len: 62 }),
+            Op::Copy(Copy { src: CopySrc::Dict, addr: 5, len: 300 }),
+            Op::Copy(Copy { src: CopySrc::Output, addr: 2, len: 64 }),
+            Op::Add(Add::new(vec![8; 2])),
+        ];
+        let output_size = ops.iter().map(|op| op.oal() as u32).sum();
+        let header = SectionHeader { compression_algo: 0, format: Format::Interleaved, num_operations: 6, num_add_bytes: 65, output_size, more_sections:false };
+        let mut writer = Vec::new();
+        write_ops(&ops, &header,&mut writer).unwrap();
+        let mut last_d = 0;
+        let mut last_o = 0;
+        let mut total = 0;
+        for op in ops.iter() {
+            total += op.encoded_size(last_d, last_o);
+            match op {
+                Op::Copy(Copy { src: CopySrc::Dict, addr, .. }) => last_d = *addr,
+                Op::Copy(Copy { src: CopySrc::Output, addr, .. }) => last_o = *addr,
+                _ => (),
+            }
+        }
+        assert_eq!(total, writer.len());
+    }
+    #[test]
     fn test_hello_micro() {
         // Instructions
         // "hello" -> "Hello! Hello!"